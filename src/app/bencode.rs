@@ -9,7 +9,7 @@ pub enum Value {
     Int(i64),
     Str(Vec<u8>),
     List(Vec<Value>),
-    Dict(HashMap<String, Value>),
+    Dict(HashMap<Vec<u8>, Value>),
 }
 
 impl Value {
@@ -21,10 +21,58 @@ impl Value {
             .collect::<String>())
     }
 
+    /// Builds a dictionary from string keys.
+    pub(crate) fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+        Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Str(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_int(&self) -> Option<i64> {
+        match self {
+            Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_dict(&self) -> Option<&HashMap<Vec<u8>, Value>> {
+        match self {
+            Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
     pub(crate) fn info_hash_u8(&self) -> Result<Vec<u8>> {
         let mut hasher = Sha1::new();
         if let bencode::Dict(dict) = self {
-            let info = bencode::to_vec_u8(&dict["info"])?;
+            let info = dict
+                .get("info".as_bytes())
+                .ok_or(anyhow!("Missing 'info' dictionary."))?;
+            let info = bencode::to_vec_u8(info)?;
             Digest::update(&mut hasher, info);
             let hashed_data = hasher.finalize().to_vec();
             return Ok(hashed_data);
@@ -66,20 +114,24 @@ fn parse_str(buffer: &[u8], start: &mut usize) -> Result<Value> {
         .map_err(|_| anyhow!("Failed to interpred string length as utf-8."))?
         .parse::<usize>()
         .map_err(|_| anyhow!("Failed to parse size length into usize"))?;
-    let s = &input[delimiter + 1..delimiter + 1 + len];
+    let end = (delimiter + 1)
+        .checked_add(len)
+        .filter(|&end| end <= input.len())
+        .ok_or(anyhow!("String of length {} runs past end of input", len))?;
+    let s = &input[delimiter + 1..end];
     // println!("{:#?}", &buffer[*start..]);
-    *start += delimiter + 1 + len;
+    *start += end;
 
     //println!("{:#?}", &buffer[*start..]);
     Ok(Value::Str(s.to_owned()))
 }
 
-fn parse_list(buffer: &[u8], start: &mut usize) -> Result<Value> {
+fn parse_list(buffer: &[u8], start: &mut usize, depth: usize) -> Result<Value> {
     if buffer.get(*start) == Some(&b'l') {
         *start += 1;
         let mut list: Vec<Value> = Vec::new();
         while buffer.get(*start) != Some(&b'e') {
-            list.push(parse_bencode(buffer, start)?)
+            list.push(parse_bencode(buffer, start, depth + 1)?)
         }
 
         *start += 1;
@@ -90,16 +142,17 @@ fn parse_list(buffer: &[u8], start: &mut usize) -> Result<Value> {
     }
 }
 
-fn parse_dict(buffer: &[u8], start: &mut usize) -> Result<Value> {
+fn parse_dict(buffer: &[u8], start: &mut usize, depth: usize) -> Result<Value> {
     if buffer.get(*start) == Some(&b'd') {
         *start += 1;
-        let mut map: HashMap<String, Value> = HashMap::new();
+        let mut map: HashMap<Vec<u8>, Value> = HashMap::new();
         while buffer.get(*start) != Some(&b'e') {
-            if let Str(key) = parse_bencode(buffer, start)? {
-                let utf8_key = std::str::from_utf8(&key)
-                    .map_err(|_| anyhow!("Failed to parse map key as valid utf-8."))?;
-                let value = parse_bencode(buffer, start)?;
-                map.insert(utf8_key.to_owned(), value);
+            // Keys are byte strings; scrape replies key `files` by raw info hash.
+            if let Str(key) = parse_bencode(buffer, start, depth + 1)? {
+                let value = parse_bencode(buffer, start, depth + 1)?;
+                map.insert(key, value);
+            } else {
+                return Err(anyhow!("Expected dictionary key to be a string."));
             }
         }
         *start += 1;
@@ -110,12 +163,19 @@ fn parse_dict(buffer: &[u8], start: &mut usize) -> Result<Value> {
     }
 }
 
-fn parse_bencode(buffer: &[u8], start: &mut usize) -> Result<Value> {
+/// Deepest list/dict nesting accepted; keeps hostile network input from
+/// exhausting the stack.
+const MAX_DEPTH: usize = 64;
+
+fn parse_bencode(buffer: &[u8], start: &mut usize, depth: usize) -> Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("Bencode nested deeper than {} levels", MAX_DEPTH));
+    }
     match &buffer.get(*start) {
         Some(b'i') => parse_int(buffer, start),
         Some(&c) if c.is_ascii_digit() => parse_str(buffer, start),
-        Some(b'l') => parse_list(buffer, start),
-        Some(b'd') => parse_dict(buffer, start),
+        Some(b'l') => parse_list(buffer, start, depth),
+        Some(b'd') => parse_dict(buffer, start, depth),
         _ => Err(anyhow!(format!(
             "Invalid bencode format or unsupported bencode value while parsing: {:?}",
             std::str::from_utf8(&buffer[*start..])?
@@ -125,11 +185,11 @@ fn parse_bencode(buffer: &[u8], start: &mut usize) -> Result<Value> {
 
 pub fn decode(buffer: &[u8]) -> Result<Value> {
     let mut n: usize = 0;
-    parse_bencode(buffer, &mut n)
+    parse_bencode(buffer, &mut n, 0)
 }
 
 #[allow(dead_code)]
-fn print_blist(values: &Vec<Value>) {
+fn print_blist(values: &[Value]) {
     print!("[");
     for (i, value) in values.iter().enumerate() {
         print_bvalue(value);
@@ -140,7 +200,7 @@ fn print_blist(values: &Vec<Value>) {
     print!("]");
 }
 
-fn blist_to_string(values: &Vec<Value>) -> Result<String> {
+fn blist_to_string(values: &[Value]) -> Result<String> {
     let mut output = "".to_owned();
     output += "[";
     for (i, value) in values.iter().enumerate() {
@@ -154,16 +214,16 @@ fn blist_to_string(values: &Vec<Value>) -> Result<String> {
 }
 
 #[allow(dead_code)]
-fn bdict_to_string_old(values: &HashMap<String, Value>) -> Result<String> {
+fn bdict_to_string_old(values: &HashMap<Vec<u8>, Value>) -> Result<String> {
     let mut output = "".to_owned();
     output += "{";
-    let mut sorted_keys = Vec::<String>::new();
+    let mut sorted_keys = Vec::<Vec<u8>>::new();
     for (key, _) in values.iter() {
         sorted_keys.push(key.to_owned());
     }
     sorted_keys.sort();
     for (i, key) in sorted_keys.iter().enumerate() {
-        output += &format!("\"{key}\":");
+        output += &format!("\"{}\":", String::from_utf8_lossy(key));
         output += &to_string(&values[key])?;
         if i != values.len() - 1 {
             output += ",";
@@ -174,23 +234,26 @@ fn bdict_to_string_old(values: &HashMap<String, Value>) -> Result<String> {
 }
 
 #[allow(dead_code)]
-fn bdict_to_string(values: &HashMap<String, Value>) -> Result<String> {
-    let mut sorted_keys: Vec<&String> = values.keys().collect();
+fn bdict_to_string(values: &HashMap<Vec<u8>, Value>) -> Result<String> {
+    let mut sorted_keys: Vec<&Vec<u8>> = values.keys().collect();
     sorted_keys.sort();
 
     let entries: Result<Vec<String>> = sorted_keys
         .into_iter()
-        .map(|key| to_string(&values[key]).map(|value| format!("\"{key}\":{value}")))
+        .map(|key| {
+            to_string(&values[key])
+                .map(|value| format!("\"{}\":{value}", String::from_utf8_lossy(key)))
+        })
         .collect();
 
     Ok(format!("{{{}}}", entries?.join(",")))
 }
 
 #[allow(dead_code)]
-fn print_bdict(map: &HashMap<String, Value>) {
+fn print_bdict(map: &HashMap<Vec<u8>, Value>) {
     print!("{{");
     for (i, (key, value)) in map.iter().enumerate() {
-        print!("\"{}\" : ", key);
+        print!("\"{}\" : ", String::from_utf8_lossy(key));
         print_bvalue(value);
         if i != map.len() - 1 {
             print!(", ");
@@ -222,7 +285,7 @@ pub fn to_string(value: &Value) -> Result<String> {
     .to_owned())
 }
 
-fn blist_to_vec_u8(values: &Vec<Value>) -> Result<Vec<u8>> {
+fn blist_to_vec_u8(values: &[Value]) -> Result<Vec<u8>> {
     let mut output: Vec<u8> = "".as_bytes().to_owned();
     output.push(b'l');
     for value in values.iter() {
@@ -232,17 +295,17 @@ fn blist_to_vec_u8(values: &Vec<Value>) -> Result<Vec<u8>> {
     Ok(output)
 }
 #[allow(dead_code)]
-fn bdict_to_vecu8(values: &HashMap<String, Value>) -> Result<Vec<u8>> {
+fn bdict_to_vecu8(values: &HashMap<Vec<u8>, Value>) -> Result<Vec<u8>> {
     let mut output = "".as_bytes().to_owned();
     output.push(b'd');
-    let mut sorted_keys = Vec::<String>::new();
+    let mut sorted_keys = Vec::<Vec<u8>>::new();
     for (key, _) in values.iter() {
         sorted_keys.push(key.to_owned());
     }
     sorted_keys.sort();
-    for (_i, key) in sorted_keys.iter().enumerate() {
+    for key in sorted_keys.iter() {
         output.extend_from_slice(format!("{:?}:", key.len()).as_bytes());
-        output.extend_from_slice(key.as_bytes());
+        output.extend_from_slice(key);
         output.extend_from_slice(&to_vec_u8(&values[key])?);
     }
     output.push(b'e');
@@ -276,6 +339,16 @@ mod tests {
             Value::Str("spam".to_owned().into())
         );
     }
+    #[test]
+    fn decode_rejects_truncated_and_deeply_nested_input() {
+        assert!(decode(b"10:short").is_err());
+        assert!(decode(b"18446744073709551615:x").is_err());
+        let nested = [vec![b'l'; 10_000], vec![b'e'; 10_000]].concat();
+        assert!(decode(&nested).is_err());
+        let shallow = [vec![b'l'; 8], vec![b'e'; 8]].concat();
+        assert!(decode(&shallow).is_ok());
+    }
+
     #[test]
     fn decode_malformed_int() {
         let buffer = "i42"; // Missing 'e' at the end
@@ -333,10 +406,10 @@ mod tests {
     fn decode_nested_dict() {
         let buffer = "d4:dictd3:keyi42eee";
         let mut inner_dict = HashMap::new();
-        inner_dict.insert("key".to_owned(), Value::Int(42));
+        inner_dict.insert("key".to_owned().into(), Value::Int(42));
 
         let mut expected_dict = HashMap::new();
-        expected_dict.insert("dict".to_owned(), Value::Dict(inner_dict));
+        expected_dict.insert("dict".to_owned().into(), Value::Dict(inner_dict));
 
         assert_eq!(
             decode(buffer.as_bytes()).unwrap(),
@@ -348,19 +421,21 @@ mod tests {
     fn decode_nested_dict_in_list() {
         let buffer = "li24ed3:keyli3123e3:heli23e3:assi1337eeei23ed3:assi23eee";
         let decoded = decode(buffer.as_bytes()).unwrap();
-        let mut vec1: Vec<Value> = Vec::new();
-        vec1.push(Int(3123));
-        vec1.push(Str("hel".to_owned().into()));
-        vec1.push(Int(23));
-        vec1.push(Str("ass".to_owned().into()));
-        vec1.push(Int(1337)); // Corrected value to match input
+        let vec1: Vec<Value> = vec![
+            Int(3123),
+            Str("hel".to_owned().into()),
+            Int(23),
+            Str("ass".to_owned().into()),
+            Int(1337), // Corrected value to match input
+        ];
         let mut d1 = HashMap::new();
-        d1.insert("key".to_owned(), List(vec1));
-        let mut outer_vec: Vec<Value> = Vec::new();
-        outer_vec.push(Int(24));
-        outer_vec.push(Dict(d1));
-        outer_vec.push(Int(23));
-        outer_vec.push(Dict(HashMap::from([("ass".to_owned(), Int(23))]))); // Correct usage of d2 according to input
+        d1.insert("key".to_owned().into(), List(vec1));
+        let outer_vec: Vec<Value> = vec![
+            Int(24),
+            Dict(d1),
+            Int(23),
+            Dict(HashMap::from([("ass".to_owned().into(), Int(23))])), // Correct usage of d2 according to input
+        ];
         let expected = List(outer_vec);
         assert_eq!(decoded, expected);
     }
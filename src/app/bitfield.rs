@@ -0,0 +1,200 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum BitfieldError {
+    #[error("Bitfield has {got} bytes, expected {expected} for {pieces} pieces")]
+    WrongLength {
+        got: usize,
+        expected: usize,
+        pieces: usize,
+    },
+    #[error("Bitfield sets spare bits past the last piece")]
+    SpareBitsSet,
+}
+
+/// One bit per piece, high bit of the first byte for piece 0, exactly as
+/// sent in the `bitfield` message.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// A bitfield with no pieces set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// A bitfield with every piece set, as implied by `HaveAll`.
+    pub fn full(len: usize) -> Self {
+        Self::new(len).not()
+    }
+
+    /// Wraps bytes received from a peer. Until [`Bitfield::validate`] is
+    /// called every bit, spare ones included, counts as a piece.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        let len = bytes.len() * 8;
+        Self { bytes, len }
+    }
+
+    /// Checks the size against the torrent's piece count and that the spare
+    /// bits at the end are clear, as BEP 3 requires.
+    pub fn validate(mut self, pieces: usize) -> Result<Self, BitfieldError> {
+        let expected = pieces.div_ceil(8);
+        if self.bytes.len() != expected {
+            return Err(BitfieldError::WrongLength {
+                got: self.bytes.len(),
+                expected,
+                pieces,
+            });
+        }
+        if self
+            .bytes
+            .last()
+            .is_some_and(|&last| last & spare_mask(pieces) != 0)
+        {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        self.len = pieces;
+        Ok(self)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Sets or clears `index`; indices past the end are ignored.
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        let bit = 0x80 >> (index % 8);
+        if value {
+            self.bytes[index / 8] |= bit;
+        } else {
+            self.bytes[index / 8] &= !bit;
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Indices of the pieces set, in order.
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.get(index))
+    }
+
+    /// Pieces set in both, e.g. what a peer has that we want.
+    pub fn and(&self, other: &Bitfield) -> Bitfield {
+        let len = self.len.min(other.len);
+        let mut result = Bitfield::new(len);
+        for (byte, (a, b)) in result
+            .bytes
+            .iter_mut()
+            .zip(self.bytes.iter().zip(&other.bytes))
+        {
+            *byte = a & b;
+        }
+        result.clear_spare_bits();
+        result
+    }
+
+    /// Every piece not set, e.g. what we still need.
+    pub fn not(&self) -> Bitfield {
+        let mut result = Bitfield {
+            bytes: self.bytes.iter().map(|byte| !byte).collect(),
+            len: self.len,
+        };
+        result.clear_spare_bits();
+        result
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let mask = spare_mask(self.len);
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= !mask;
+        }
+    }
+}
+
+/// Bits of the last byte that do not belong to any of `len` pieces.
+fn spare_mask(len: usize) -> u8 {
+    match len % 8 {
+        0 => 0,
+        used => 0xff >> used,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_set_count_and_iterate() {
+        let mut bits = Bitfield::new(10);
+        assert_eq!(bits.as_bytes(), &[0, 0]);
+        bits.set(0, true);
+        bits.set(9, true);
+        bits.set(10, true);
+        assert_eq!(bits.as_bytes(), &[0x80, 0x40]);
+        assert!(bits.get(0) && bits.get(9) && !bits.get(1) && !bits.get(10));
+        assert_eq!(bits.count(), 2);
+        assert_eq!(bits.iter_set().collect::<Vec<_>>(), vec![0, 9]);
+        bits.set(0, false);
+        assert_eq!(bits.iter_set().collect::<Vec<_>>(), vec![9]);
+    }
+
+    #[test]
+    fn and_not_keep_spare_bits_clear() {
+        let full = Bitfield::full(10);
+        assert_eq!(full.as_bytes(), &[0xff, 0xc0]);
+        assert_eq!(full.count(), full.len());
+        let mut ours = Bitfield::new(10);
+        ours.set(3, true);
+        let missing = ours.not();
+        assert_eq!(missing.count(), 9);
+        assert!(!missing.get(3));
+        assert_eq!(missing.as_bytes(), &[0xef, 0xc0]);
+        let mut theirs = Bitfield::new(10);
+        theirs.set(3, true);
+        theirs.set(8, true);
+        assert_eq!(theirs.and(&missing).iter_set().collect::<Vec<_>>(), vec![8]);
+    }
+
+    #[test]
+    fn validate_checks_length_and_spare_bits() {
+        let bits = Bitfield::from_bytes(vec![0xff, 0x80]).validate(9).unwrap();
+        assert_eq!(bits.len(), 9);
+        assert_eq!(bits.count(), 9);
+        assert_eq!(
+            Bitfield::from_bytes(vec![0xff, 0xc0]).validate(9),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(vec![0xff]).validate(9),
+            Err(BitfieldError::WrongLength {
+                got: 1,
+                expected: 2,
+                pieces: 9
+            })
+        );
+        assert!(Bitfield::from_bytes(vec![0xff]).validate(8).is_ok());
+    }
+}
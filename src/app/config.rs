@@ -0,0 +1,142 @@
+use crate::app::messages::{Capabilities, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::app::mse::EncryptionPolicy;
+use crate::app::picker::PickMode;
+use crate::app::random;
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, SocketAddr};
+
+/// Azureus-style client prefix for generated peer ids.
+const PEER_ID_PREFIX: &[u8; 8] = b"-XX0001-";
+const PEER_ID_CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Per-session settings shared by tracker announces and peer handshakes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    pub peer_id: [u8; 20],
+    /// Port we accept peer connections on.
+    pub port: u16,
+    /// Number of peers to ask the tracker for; tracker default if unset.
+    pub numwant: Option<u32>,
+    /// Random value that lets the tracker recognise us across IP changes.
+    pub key: String,
+    /// Address to advertise instead of the one the tracker sees.
+    pub ip: Option<IpAddr>,
+    /// Ask trackers to omit peer ids from non-compact replies.
+    pub no_peer_id: bool,
+    /// Peers to try in addition to discovered ones (`--peer ip:port`).
+    pub peers: Vec<SocketAddr>,
+    /// Announce on and listen to the local network (BEP 14).
+    pub lsd: bool,
+    /// Reserved handshake bits we advertise to peers.
+    pub capabilities: Capabilities,
+    /// Peers sending longer messages are disconnected.
+    pub max_message_size: usize,
+    /// Whether peer connections use Message Stream Encryption.
+    pub encryption: EncryptionPolicy,
+    /// Try uTP (BEP 29) before TCP when connecting to peers.
+    pub utp: bool,
+    /// Peers a download keeps connected at once.
+    pub max_peers: usize,
+    /// Which piece a download starts next.
+    pub piece_order: PickMode,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: generate_peer_id(),
+            port: 6881,
+            numwant: None,
+            key: format!("{:08x}", random::next_u32()),
+            ip: None,
+            no_peer_id: false,
+            peers: Vec::new(),
+            lsd: true,
+            capabilities: Capabilities::EXTENSION_PROTOCOL.with(Capabilities::FAST),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            encryption: EncryptionPolicy::default(),
+            utp: false,
+            max_peers: 30,
+            piece_order: PickMode::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Pulls `--port`, `--numwant`, `--key`, `--ip`, `--no-peer-id`,
+    /// `--peer`, `--no-lsd`, `--capabilities`, `--max-message-size`,
+    /// `--encryption`, `--utp`, `--max-peers` and `--piece-order` out of the
+    /// command line, returning the config and the remaining arguments.
+    pub(crate) fn from_args(args: Vec<String>) -> Result<(Self, Vec<String>)> {
+        let mut config = Self::default();
+        let mut rest = Vec::with_capacity(args.len());
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or(anyhow!("Missing value for option {}", name))
+            };
+            match arg.as_str() {
+                "--port" => config.port = value(&arg)?.parse()?,
+                "--numwant" => config.numwant = Some(value(&arg)?.parse()?),
+                "--key" => config.key = value(&arg)?,
+                "--ip" => config.ip = Some(value(&arg)?.parse()?),
+                "--no-peer-id" => config.no_peer_id = true,
+                "--peer" => config.peers.push(value(&arg)?.parse()?),
+                "--no-lsd" => config.lsd = false,
+                "--capabilities" => config.capabilities = Capabilities::parse(&value(&arg)?)?,
+                "--max-message-size" => config.max_message_size = value(&arg)?.parse()?,
+                "--encryption" => config.encryption = value(&arg)?.parse()?,
+                "--utp" => config.utp = true,
+                "--max-peers" => config.max_peers = value(&arg)?.parse()?,
+                "--piece-order" => config.piece_order = value(&arg)?.parse()?,
+                _ => rest.push(arg),
+            }
+        }
+        Ok((config, rest))
+    }
+}
+
+/// Builds a fresh `-XX0001-` peer id with a random printable suffix.
+pub(crate) fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for byte in peer_id[8..].iter_mut() {
+        *byte = PEER_ID_CHARSET[(random::next_u32() as usize) % PEER_ID_CHARSET.len()];
+    }
+    peer_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_ids_are_azureus_style_and_unique() {
+        let a = generate_peer_id();
+        let b = generate_peer_id();
+        assert!(a.starts_with(b"-XX0001-"));
+        assert!(a[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn from_args_extracts_options() {
+        let args = [
+            "prog",
+            "peers",
+            "--port",
+            "7000",
+            "x.torrent",
+            "--numwant",
+            "5",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let (config, rest) = ClientConfig::from_args(args).unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.numwant, Some(5));
+        assert_eq!(rest, vec!["prog", "peers", "x.torrent"]);
+    }
+}
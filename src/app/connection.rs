@@ -0,0 +1,223 @@
+use crate::app::config::ClientConfig;
+use crate::app::messages::{
+    BTMessage, BTMessageFramer, Capabilities, Handshake, HandshakeCodec, HandshakeError,
+};
+use crate::app::utp::UtpStream;
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, FramedParts};
+
+/// The transports peer connections run over.
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    #[allow(dead_code)]
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Transport::Tcp(stream) => Ok(stream.peer_addr()?),
+            Transport::Utp(stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A peer connection past the handshake, on any transport.
+pub struct PeerConnection<T> {
+    pub framed: Framed<T, BTMessageFramer>,
+    /// The handshake the peer sent.
+    pub handshake: Handshake,
+    /// Capabilities both sides advertised.
+    pub capabilities: Capabilities,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> PeerConnection<T> {
+    /// Handshakes on a connection we opened: ours goes first, then the
+    /// peer's must name the same torrent.
+    pub async fn outgoing(io: T, config: &ClientConfig, info_hash: [u8; 20]) -> Result<Self> {
+        let mut framed = Framed::new(io, HandshakeCodec);
+        let ours = Handshake::new(&config.peer_id, &info_hash, config.capabilities);
+        framed.send(ours).await?;
+        let theirs = receive(&mut framed).await?;
+        theirs.validate(&info_hash, &config.peer_id)?;
+        Ok(Self::upgrade(framed, theirs, config))
+    }
+
+    /// Handshakes on a connection the peer opened. Its handshake tells us
+    /// the torrent, which `serves` must accept before we answer.
+    #[allow(dead_code)]
+    pub async fn incoming(
+        io: T,
+        config: &ClientConfig,
+        serves: impl Fn(&[u8; 20]) -> bool,
+    ) -> Result<Self> {
+        let mut framed = Framed::new(io, HandshakeCodec);
+        let theirs = receive(&mut framed).await?;
+        let info_hash = theirs.info_hash();
+        if !serves(&info_hash) {
+            return Err(HandshakeError::InfoHashMismatch(info_hash).into());
+        }
+        theirs.validate(&info_hash, &config.peer_id)?;
+        let ours = Handshake::new(&config.peer_id, &info_hash, config.capabilities);
+        framed.send(ours).await?;
+        Ok(Self::upgrade(framed, theirs, config))
+    }
+
+    /// Switches the transport to peer messages, keeping anything the peer
+    /// sent behind its handshake.
+    fn upgrade(
+        framed: Framed<T, HandshakeCodec>,
+        theirs: Handshake,
+        config: &ClientConfig,
+    ) -> Self {
+        let parts = framed.into_parts();
+        let codec = BTMessageFramer::with_max_message_size(config.max_message_size);
+        let mut next = FramedParts::new::<BTMessage>(parts.io, codec);
+        next.read_buf = parts.read_buf;
+        next.write_buf = parts.write_buf;
+        Self {
+            framed: Framed::from_parts(next),
+            capabilities: config.capabilities.intersection(theirs.capabilities()),
+            handshake: theirs,
+        }
+    }
+}
+
+async fn receive<T: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<T, HandshakeCodec>,
+) -> Result<Handshake> {
+    framed.next().await.ok_or(HandshakeError::Truncated(0))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const INFO_HASH: [u8; 20] = [0x33; 20];
+
+    fn config(capabilities: Capabilities) -> ClientConfig {
+        ClientConfig {
+            capabilities,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn outgoing_and_incoming_agree() {
+        let (a, b) = duplex(1024);
+        let dialer = config(Capabilities::EXTENSION_PROTOCOL.with(Capabilities::FAST));
+        let listener = config(Capabilities::EXTENSION_PROTOCOL.with(Capabilities::DHT));
+        let (outgoing, incoming) = tokio::join!(
+            PeerConnection::outgoing(a, &dialer, INFO_HASH),
+            PeerConnection::incoming(b, &listener, |info_hash| *info_hash == INFO_HASH),
+        );
+        let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+        assert_eq!(outgoing.capabilities, Capabilities::EXTENSION_PROTOCOL);
+        assert_eq!(incoming.capabilities, Capabilities::EXTENSION_PROTOCOL);
+        assert_eq!(incoming.handshake.info_hash(), INFO_HASH);
+
+        outgoing.framed.send(BTMessage::HaveNone).await.unwrap();
+        assert_eq!(
+            incoming.framed.next().await.unwrap().unwrap(),
+            BTMessage::HaveNone
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_sent_with_the_handshake() {
+        let (mut raw, io) = duplex(1024);
+        let peer = Handshake::new(b"-YY0001-cccccccccccc", &INFO_HASH, Capabilities::empty());
+        let mut bytes = peer.serialize();
+        bytes.extend(BTMessage::HaveAll.serialize().unwrap());
+        bytes.extend(BTMessage::Unchoke.serialize().unwrap());
+        raw.write_all(&bytes).await.unwrap();
+
+        let ours = config(Capabilities::FAST);
+        let mut connection = PeerConnection::incoming(io, &ours, |_| true).await.unwrap();
+        assert_eq!(connection.capabilities, Capabilities::empty());
+        for expected in [BTMessage::HaveAll, BTMessage::Unchoke] {
+            assert_eq!(connection.framed.next().await.unwrap().unwrap(), expected);
+        }
+        let mut reply = [0u8; 68];
+        raw.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            Handshake::deserialize(&reply).unwrap().info_hash(),
+            INFO_HASH
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_other_torrents_and_ourselves() {
+        let ours = config(Capabilities::empty());
+        let other = config(Capabilities::empty());
+        let (a, b) = duplex(1024);
+        let (_, incoming) = tokio::join!(
+            PeerConnection::outgoing(a, &other, INFO_HASH),
+            PeerConnection::incoming(b, &ours, |_| false),
+        );
+        let error = incoming.err().unwrap();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&HandshakeError::InfoHashMismatch(INFO_HASH))
+        );
+
+        // The dialer sees the connection close without a reply.
+        let (a, b) = duplex(1024);
+        let (outgoing, incoming) = tokio::join!(
+            PeerConnection::outgoing(a, &ours, INFO_HASH),
+            PeerConnection::incoming(b, &ours, |_| true),
+        );
+        let error = incoming.err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&HandshakeError::SelfConnection));
+        let error = outgoing.err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&HandshakeError::Truncated(0)));
+    }
+}
@@ -0,0 +1,1047 @@
+use crate::app::bencode::{self, Value};
+use crate::app::dht_items::{
+    immutable_target, mutable_target, ItemStore, MutableItem, PutError, StoredItem, MAX_VALUE_SIZE,
+};
+use crate::app::ed25519::SigningKey;
+use crate::app::krpc::{
+    decode_nodes, decode_peer, encode_nodes, encode_peer, get_id, KrpcBody, KrpcMessage, NodeId,
+    NodeInfo, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL,
+};
+use crate::app::random;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+
+/// Well-known routers used when no other bootstrap nodes are configured.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Bucket size.
+const K: usize = 8;
+/// Queries kept in flight during an iterative lookup.
+const ALPHA: usize = 3;
+/// Timeouts after which a contact is dropped from the routing table.
+const MAX_FAILURES: u32 = 3;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Peers returned per `get_peers` reply, which has to fit in one datagram.
+const MAX_VALUES: usize = 50;
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// Bucket `i` holds nodes whose distance from us has its highest set bit at
+/// position `i`, so bucket 159 covers the half of the id space furthest away.
+fn bucket_index(own: &NodeId, other: &NodeId) -> Option<usize> {
+    let distance = distance(own, other);
+    let (byte, value) = distance.iter().enumerate().find(|(_, &byte)| byte != 0)?;
+    Some(159 - (byte * 8 + value.leading_zeros() as usize))
+}
+
+fn random_id() -> NodeId {
+    let mut id = [0u8; 20];
+    random::fill(&mut id);
+    id
+}
+
+struct Contact {
+    node: NodeInfo,
+    failures: u32,
+}
+
+/// Kademlia routing table of `K`-sized buckets. Live contacts are never
+/// displaced by new ones; only contacts that stopped answering are replaced.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Records a node that just talked to us. Returns whether it is in the
+    /// table afterwards.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = bucket_index(&self.own_id, &node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(contact) = bucket.iter_mut().find(|c| c.node.id == node.id) {
+            contact.node = node;
+            contact.failures = 0;
+            return true;
+        }
+        let contact = Contact { node, failures: 0 };
+        if bucket.len() < K {
+            bucket.push(contact);
+            return true;
+        }
+        match bucket
+            .iter_mut()
+            .filter(|c| c.failures > 0)
+            .max_by_key(|c| c.failures)
+        {
+            Some(stale) => {
+                *stale = contact;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a timeout against the contact at `addr`.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(contact) = bucket.iter_mut().find(|c| c.node.addr == addr) {
+                contact.failures += 1;
+            }
+            bucket.retain(|c| c.failures < MAX_FAILURES);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self.nodes();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|contact| contact.node)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// `announce_peer` tokens are a hash of the requester's IP and a secret that
+/// rotates every five minutes; tokens from the previous secret stay valid.
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl TokenSecrets {
+    fn new() -> Self {
+        let mut secrets = Self {
+            current: [0; 16],
+            previous: [0; 16],
+            rotated: Instant::now(),
+        };
+        random::fill(&mut secrets.current);
+        secrets.previous = secrets.current;
+        secrets
+    }
+
+    fn rotate(&mut self) {
+        self.previous = self.current;
+        random::fill(&mut self.current);
+        self.rotated = Instant::now();
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.rotate();
+        }
+    }
+
+    fn token(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        token_for(&self.current, ip)
+    }
+
+    fn verify(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+        token == token_for(&self.current, ip) || token == token_for(&self.previous, ip)
+    }
+}
+
+fn token_for(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..8].to_vec()
+}
+
+struct DhtState {
+    table: RoutingTable,
+    secrets: TokenSecrets,
+    /// Peers announced to us, by info hash, with the time of their announce.
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    /// BEP 44 items put to us.
+    items: ItemStore,
+}
+
+impl DhtState {
+    fn peers_for(&mut self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        let Some(peers) = self.peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        peers.keys().take(MAX_VALUES).copied().collect()
+    }
+
+    fn store_peer(&mut self, info_hash: [u8; 20], addr: SocketAddr) {
+        let peers = self.peers.entry(info_hash).or_default();
+        if peers.len() < MAX_PEERS_PER_TORRENT || peers.contains_key(&addr) {
+            peers.insert(addr, Instant::now());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Local UDP address for the node.
+    pub bind: SocketAddr,
+    /// `host:port` of nodes to contact when the routing table is empty.
+    pub bootstrap: Vec<String>,
+    /// Where the node id and routing table are kept between runs.
+    pub state_file: Option<PathBuf>,
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 6881),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            state_file: None,
+            query_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+struct Lookup {
+    /// Replies from every node that answered, closest to the target first.
+    replies: Vec<(NodeInfo, Value)>,
+}
+
+impl Lookup {
+    /// The `K` closest responders that handed out a write token.
+    fn tokens(&self) -> impl Iterator<Item = (NodeInfo, Vec<u8>)> + '_ {
+        self.replies
+            .iter()
+            .filter_map(|(node, reply)| Some((*node, reply.get("token")?.as_bytes()?.to_vec())))
+            .take(K)
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        for (_, reply) in &self.replies {
+            let values = reply.get("values").and_then(Value::as_list).unwrap_or(&[]);
+            for peer in values
+                .iter()
+                .filter_map(|value| decode_peer(value.as_bytes()?))
+            {
+                if !peers.contains(&SocketAddr::V4(peer)) {
+                    peers.push(SocketAddr::V4(peer));
+                }
+            }
+        }
+        peers
+    }
+}
+
+struct PendingQuery {
+    addr: SocketAddr,
+    reply: oneshot::Sender<KrpcBody>,
+}
+
+/// A BEP 5 mainline DHT node: answers queries from other nodes on its
+/// socket and runs iterative lookups on our behalf.
+pub struct DhtNode {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    config: DhtConfig,
+    state: Mutex<DhtState>,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction: AtomicU16,
+    receiver: AbortHandle,
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl DhtNode {
+    /// Binds the socket and starts answering queries. The node id and known
+    /// contacts are restored from `config.state_file` when it exists.
+    pub async fn bind(config: DhtConfig) -> Result<Arc<Self>> {
+        let socket = Arc::new(UdpSocket::bind(config.bind).await?);
+        let saved = match &config.state_file {
+            Some(path) if path.exists() => match load_state(path) {
+                Ok(saved) => Some(saved),
+                Err(e) => {
+                    log::warn!("Ignoring DHT state in {}: {}", path.display(), e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let (id, contacts) = saved.unwrap_or_else(|| (random_id(), Vec::new()));
+        let mut table = RoutingTable::new(id);
+        for contact in contacts {
+            table.insert(contact);
+        }
+
+        Ok(Arc::new_cyclic(|node: &Weak<Self>| {
+            let receiver = tokio::spawn(Self::receive(node.clone(), socket.clone()));
+            Self {
+                id,
+                socket,
+                config,
+                state: Mutex::new(DhtState {
+                    table,
+                    secrets: TokenSecrets::new(),
+                    peers: HashMap::new(),
+                    items: ItemStore::default(),
+                }),
+                pending: Mutex::new(HashMap::new()),
+                next_transaction: AtomicU16::new(random::next_u32() as u16),
+                receiver: receiver.abort_handle(),
+            }
+        }))
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn routing_table_len(&self) -> usize {
+        self.state.lock().unwrap().table.len()
+    }
+
+    /// Writes the node id and routing table to the configured state file.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.config.state_file else {
+            return Ok(());
+        };
+        let nodes = self.state.lock().unwrap().table.nodes();
+        let state = Value::dict([
+            ("id", Value::Str(self.id.to_vec())),
+            ("nodes", Value::Str(encode_nodes(&nodes))),
+        ]);
+        std::fs::write(path, bencode::to_vec_u8(&state)?)?;
+        Ok(())
+    }
+
+    async fn receive(node: Weak<Self>, socket: Arc<UdpSocket>) {
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    log::debug!("DHT receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Some(node) = node.upgrade() else {
+                return;
+            };
+            node.handle_packet(&buf[..len], from).await;
+        }
+    }
+
+    async fn handle_packet(&self, packet: &[u8], from: SocketAddr) {
+        let message = match KrpcMessage::decode(packet) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Ignoring malformed KRPC packet from {}: {}", from, e);
+                return;
+            }
+        };
+        let body = match message.body {
+            KrpcBody::Query { method, args } => {
+                let reply = KrpcMessage {
+                    transaction_id: message.transaction_id,
+                    body: self.handle_query(&method, &args, from),
+                };
+                match reply.encode() {
+                    Ok(packet) => {
+                        if let Err(e) = self.socket.send_to(&packet, from).await {
+                            log::debug!("Failed to answer {} from {}: {}", method, from, e);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to encode KRPC reply: {}", e),
+                }
+                return;
+            }
+            body => body,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        // Replies must come from the node we asked.
+        if pending
+            .get(&message.transaction_id)
+            .is_some_and(|query| query.addr == from)
+        {
+            let query = pending.remove(&message.transaction_id).unwrap();
+            let _ = query.reply.send(body);
+        }
+    }
+
+    fn reply(&self, mut values: Vec<(&str, Value)>) -> KrpcBody {
+        values.push(("id", Value::Str(self.id.to_vec())));
+        KrpcBody::Response(Value::dict(values))
+    }
+
+    fn handle_query(&self, method: &str, args: &Value, from: SocketAddr) -> KrpcBody {
+        let protocol_error = |message: &str| KrpcBody::Error {
+            code: ERROR_PROTOCOL,
+            message: message.to_owned(),
+        };
+        let Some(id) = get_id(args, "id") else {
+            return protocol_error("Missing id");
+        };
+        let mut state = self.state.lock().unwrap();
+        state.table.insert(NodeInfo { id, addr: from });
+
+        match method {
+            "ping" => self.reply(vec![]),
+            "find_node" => {
+                let Some(target) = get_id(args, "target") else {
+                    return protocol_error("Missing target");
+                };
+                let nodes = state.table.closest(&target, K);
+                self.reply(vec![("nodes", Value::Str(encode_nodes(&nodes)))])
+            }
+            "get_peers" => {
+                let Some(info_hash) = get_id(args, "info_hash") else {
+                    return protocol_error("Missing info_hash");
+                };
+                let token = state.secrets.token(from.ip());
+                let peers = state.peers_for(&info_hash);
+                let mut values = vec![("token", Value::Str(token))];
+                if peers.is_empty() {
+                    let nodes = state.table.closest(&info_hash, K);
+                    values.push(("nodes", Value::Str(encode_nodes(&nodes))));
+                } else {
+                    let peers = peers
+                        .into_iter()
+                        .filter_map(|peer| match peer {
+                            SocketAddr::V4(peer) => Some(Value::Str(encode_peer(peer).to_vec())),
+                            SocketAddr::V6(_) => None,
+                        })
+                        .collect();
+                    values.push(("values", Value::List(peers)));
+                }
+                self.reply(values)
+            }
+            "announce_peer" => {
+                let Some(info_hash) = get_id(args, "info_hash") else {
+                    return protocol_error("Missing info_hash");
+                };
+                let token = args.get("token").and_then(Value::as_bytes).unwrap_or(&[]);
+                if !state.secrets.verify(from.ip(), token) {
+                    return protocol_error("Bad token");
+                }
+                let implied_port = args.get("implied_port").and_then(Value::as_int) == Some(1);
+                let port = match args.get("port").and_then(Value::as_int) {
+                    _ if implied_port => from.port(),
+                    Some(port) => match u16::try_from(port) {
+                        Ok(port) if port != 0 => port,
+                        _ => return protocol_error("Bad port"),
+                    },
+                    None => return protocol_error("Missing port"),
+                };
+                state.store_peer(info_hash, SocketAddr::new(from.ip(), port));
+                self.reply(vec![])
+            }
+            "get" => {
+                let Some(target) = get_id(args, "target") else {
+                    return protocol_error("Missing target");
+                };
+                let nodes = state.table.closest(&target, K);
+                let mut values = vec![
+                    ("token", Value::Str(state.secrets.token(from.ip()))),
+                    ("nodes", Value::Str(encode_nodes(&nodes))),
+                ];
+                // A requester that already has `seq` only needs newer values.
+                let known_seq = args.get("seq").and_then(Value::as_int);
+                match state.items.get(&target) {
+                    Some(StoredItem::Immutable(value)) => values.push(("v", value.clone())),
+                    Some(StoredItem::Mutable(item)) => {
+                        values.push(("seq", Value::Int(item.seq)));
+                        values.push(("k", Value::Str(item.public_key.to_vec())));
+                        if known_seq.is_none_or(|seq| item.seq > seq) {
+                            values.push(("sig", Value::Str(item.signature.to_vec())));
+                            values.push(("v", item.value.clone()));
+                        }
+                    }
+                    None => {}
+                }
+                self.reply(values)
+            }
+            "put" => {
+                let token = args.get("token").and_then(Value::as_bytes).unwrap_or(&[]);
+                if !state.secrets.verify(from.ip(), token) {
+                    return protocol_error("Bad token");
+                }
+                let stored = if args.get("k").is_some() {
+                    let salt = args.get("salt").and_then(Value::as_bytes).unwrap_or(&[]);
+                    let Some(item) = MutableItem::from_dict(args, salt) else {
+                        return protocol_error("Malformed mutable item");
+                    };
+                    let cas = args.get("cas").and_then(Value::as_int);
+                    state.items.put_mutable(item, cas)
+                } else {
+                    let Some(value) = args.get("v") else {
+                        return protocol_error("Missing v");
+                    };
+                    state.items.put_immutable(value.clone()).map(|_| ())
+                };
+                match stored {
+                    Ok(()) => self.reply(vec![]),
+                    Err(e) => KrpcBody::Error {
+                        code: e.code(),
+                        message: e.to_string(),
+                    },
+                }
+            }
+            _ => KrpcBody::Error {
+                code: ERROR_METHOD_UNKNOWN,
+                message: "Method Unknown".to_owned(),
+            },
+        }
+    }
+
+    /// Sends one query and waits for its reply. Timeouts count against the
+    /// contact; any reply refreshes it.
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        mut args: Vec<(&str, Value)>,
+    ) -> Result<Value> {
+        args.push(("id", Value::Str(self.id.to_vec())));
+        let transaction_id = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let packet =
+            KrpcMessage::query(transaction_id.clone(), method, Value::dict(args)).encode()?;
+        let (reply, received) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id.clone(), PendingQuery { addr, reply });
+
+        let result = async {
+            self.socket.send_to(&packet, addr).await?;
+            tokio::time::timeout(self.config.query_timeout, received)
+                .await
+                .map_err(|_| anyhow!("{} query to {} timed out", method, addr))?
+                .map_err(|_| anyhow!("DHT node shut down"))
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&transaction_id);
+
+        match result {
+            Ok(KrpcBody::Response(values)) => {
+                if let Some(id) = get_id(&values, "id") {
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .table
+                        .insert(NodeInfo { id, addr });
+                }
+                Ok(values)
+            }
+            Ok(KrpcBody::Error { code, message }) => Err(anyhow!(
+                "{} query to {} failed with error {}: {}",
+                method,
+                addr,
+                code,
+                message
+            )),
+            Ok(KrpcBody::Query { .. }) => unreachable!("queries are answered, not forwarded"),
+            Err(e) => {
+                self.state.lock().unwrap().table.failed(addr);
+                Err(e)
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        let reply = self.query(addr, "ping", vec![]).await?;
+        get_id(&reply, "id").ok_or(anyhow!("ping reply from {} without id", addr))
+    }
+
+    pub async fn find_node(&self, addr: SocketAddr, target: NodeId) -> Result<Vec<NodeInfo>> {
+        let reply = self
+            .query(
+                addr,
+                "find_node",
+                vec![("target", Value::Str(target.to_vec()))],
+            )
+            .await?;
+        Ok(decode_nodes(
+            reply.get("nodes").and_then(Value::as_bytes).unwrap_or(&[]),
+        ))
+    }
+
+    pub async fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    ) -> Result<()> {
+        self.query(
+            addr,
+            "announce_peer",
+            vec![
+                ("info_hash", Value::Str(info_hash.to_vec())),
+                ("port", Value::Int(port as i64)),
+                ("token", Value::Str(token)),
+                ("implied_port", Value::Int(0)),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Iterative Kademlia lookup: keep sending `method` to the `ALPHA`
+    /// closest nodes not yet queried until the `K` closest known nodes have
+    /// all answered or failed.
+    async fn lookup(&self, target: NodeId, method: &str, args: Vec<(&str, Value)>) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+
+        loop {
+            let batch: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|node| node.addr));
+
+            let replies = join_all(
+                batch
+                    .iter()
+                    .map(|node| self.query(node.addr, method, args.clone())),
+            )
+            .await;
+
+            for (node, reply) in batch.into_iter().zip(replies) {
+                let key = distance(&node.id, &target);
+                match reply {
+                    Ok(reply) => {
+                        let nodes = reply.get("nodes").and_then(Value::as_bytes).unwrap_or(&[]);
+                        for found in decode_nodes(nodes) {
+                            if found.id != self.id {
+                                candidates
+                                    .entry(distance(&found.id, &target))
+                                    .or_insert(found);
+                            }
+                        }
+                        responded.insert(key, (node, reply));
+                    }
+                    Err(e) => {
+                        log::debug!("DHT lookup: {}", e);
+                        candidates.remove(&key);
+                    }
+                }
+            }
+        }
+
+        Lookup {
+            replies: responded.into_values().collect(),
+        }
+    }
+
+    /// Sends `put` with `args` to the closest nodes of a `get` lookup.
+    async fn put(&self, lookup: &Lookup, args: Vec<(&str, Value)>) -> Result<()> {
+        let puts = lookup.tokens().map(|(node, token)| {
+            let mut args = args.clone();
+            args.push(("token", Value::Str(token)));
+            async move { self.query(node.addr, "put", args).await }
+        });
+        let stored = join_all(puts)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+        if stored == 0 {
+            return Err(anyhow!("No DHT node accepted the put"));
+        }
+        Ok(())
+    }
+
+    /// Fills the routing table by asking the bootstrap nodes, and then our
+    /// neighbourhood, for nodes close to our own id. Returns the table size.
+    pub async fn bootstrap(&self) -> Result<usize> {
+        let mut routers = Vec::new();
+        for host in &self.config.bootstrap {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(addrs) => routers.extend(addrs.filter(SocketAddr::is_ipv4)),
+                Err(e) => log::warn!("Failed to resolve DHT bootstrap node {}: {}", host, e),
+            }
+        }
+        let replies = join_all(routers.iter().map(|&addr| self.find_node(addr, self.id))).await;
+        {
+            let mut state = self.state.lock().unwrap();
+            for node in replies.into_iter().flatten().flatten() {
+                state.table.insert(node);
+            }
+        }
+
+        let target = vec![("target", Value::Str(self.id.to_vec()))];
+        self.lookup(self.id, "find_node", target).await;
+        match self.routing_table_len() {
+            0 => Err(anyhow!("DHT bootstrap found no nodes")),
+            len => Ok(len),
+        }
+    }
+
+    /// Finds peers for `info_hash` across the DHT.
+    pub async fn lookup_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.get_peers_lookup(info_hash).await.peers()
+    }
+
+    async fn get_peers_lookup(&self, info_hash: [u8; 20]) -> Lookup {
+        let args = vec![("info_hash", Value::Str(info_hash.to_vec()))];
+        self.lookup(info_hash, "get_peers", args).await
+    }
+
+    /// Tells the nodes closest to `info_hash` that we accept peers on `port`.
+    /// Returns the peers found on the way.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Result<Vec<SocketAddr>> {
+        let lookup = self.get_peers_lookup(info_hash).await;
+        let announces = lookup
+            .tokens()
+            .map(|(node, token)| self.announce_peer(node.addr, info_hash, port, token));
+        let accepted = join_all(announces)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+        if accepted == 0 {
+            return Err(anyhow!("No DHT node accepted the announce"));
+        }
+        Ok(lookup.peers())
+    }
+
+    async fn get_lookup(&self, target: [u8; 20]) -> Lookup {
+        let args = vec![("target", Value::Str(target.to_vec()))];
+        self.lookup(target, "get", args).await
+    }
+
+    /// Fetches the BEP 44 immutable item stored under `target`.
+    pub async fn get_immutable(&self, target: [u8; 20]) -> Result<Value> {
+        let lookup = self.get_lookup(target).await;
+        lookup
+            .replies
+            .iter()
+            .filter_map(|(_, reply)| reply.get("v"))
+            .find(|value| immutable_target(value).is_ok_and(|hash| hash == target))
+            .cloned()
+            .ok_or(anyhow!("Item {} not found in the DHT", hex::encode(target)))
+    }
+
+    /// Stores `value` as an immutable item and returns its target.
+    pub async fn put_immutable(&self, value: Value) -> Result<[u8; 20]> {
+        if bencode::to_vec_u8(&value)?.len() > MAX_VALUE_SIZE {
+            return Err(PutError::TooBig.into());
+        }
+        let target = immutable_target(&value)?;
+        let lookup = self.get_lookup(target).await;
+        self.put(&lookup, vec![("v", value)]).await?;
+        Ok(target)
+    }
+
+    /// Fetches the newest validly signed version of a mutable item.
+    pub async fn get_mutable(&self, public_key: &[u8; 32], salt: &[u8]) -> Result<MutableItem> {
+        let lookup = self.get_lookup(mutable_target(public_key, salt)).await;
+        lookup
+            .replies
+            .iter()
+            .filter_map(|(_, reply)| MutableItem::from_dict(reply, salt))
+            .filter(|item| item.public_key == *public_key && item.verify())
+            .max_by_key(|item| item.seq)
+            .ok_or(anyhow!(
+                "Mutable item {} not found in the DHT",
+                hex::encode(public_key)
+            ))
+    }
+
+    pub async fn put_mutable(&self, item: &MutableItem) -> Result<()> {
+        let lookup = self.get_lookup(item.target()).await;
+        self.put(&lookup, item.put_args()).await
+    }
+
+    /// BEP 46: follows a `urn:btpk:` pointer to the info hash it currently
+    /// names.
+    pub async fn resolve_mutable_torrent(
+        &self,
+        public_key: &[u8; 32],
+        salt: &[u8],
+    ) -> Result<[u8; 20]> {
+        let item = self.get_mutable(public_key, salt).await?;
+        item.value
+            .get("ih")
+            .and_then(Value::as_bytes)
+            .and_then(|info_hash| info_hash.try_into().ok())
+            .ok_or(anyhow!("Mutable item does not point to a torrent"))
+    }
+
+    /// BEP 46: points the key's `urn:btpk:` link at `info_hash`, superseding
+    /// whatever it named before. Returns the new sequence number.
+    pub async fn publish_mutable_torrent(
+        &self,
+        key: &SigningKey,
+        salt: &[u8],
+        info_hash: [u8; 20],
+    ) -> Result<i64> {
+        let seq = match self.get_mutable(&key.public_key(), salt).await {
+            Ok(current) => current.seq + 1,
+            Err(_) => 1,
+        };
+        let value = Value::dict([("ih", Value::Str(info_hash.to_vec()))]);
+        self.put_mutable(&MutableItem::sign(key, salt, seq, value)?)
+            .await?;
+        Ok(seq)
+    }
+}
+
+fn load_state(path: &Path) -> Result<(NodeId, Vec<NodeInfo>)> {
+    let state = bencode::decode(&std::fs::read(path)?)?;
+    let id = get_id(&state, "id").ok_or(anyhow!("Missing node id"))?;
+    let nodes = decode_nodes(state.get("nodes").and_then(Value::as_bytes).unwrap_or(&[]));
+    Ok((id, nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first: u8, port: u16) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = first;
+        id[19] = port as u8;
+        NodeInfo {
+            id,
+            addr: SocketAddr::from(([10, 0, 0, first], port)),
+        }
+    }
+
+    #[test]
+    fn buckets_are_indexed_by_highest_differing_bit() {
+        let own = [0u8; 20];
+        assert_eq!(bucket_index(&own, &own), None);
+        let mut far = [0u8; 20];
+        far[0] = 0x80;
+        assert_eq!(bucket_index(&own, &far), Some(159));
+        let mut near = [0u8; 20];
+        near[19] = 1;
+        assert_eq!(bucket_index(&own, &near), Some(0));
+    }
+
+    #[test]
+    fn full_bucket_only_replaces_failing_contacts() {
+        let mut table = RoutingTable::new([0; 20]);
+        for port in 0..K as u16 {
+            assert!(table.insert(node(0x80, port)));
+        }
+        assert!(!table.insert(node(0x80, 100)));
+        assert_eq!(table.len(), K);
+
+        table.failed(node(0x80, 3).addr);
+        assert!(table.insert(node(0x80, 100)));
+        assert!(table.nodes().contains(&node(0x80, 100)));
+        assert!(!table.nodes().contains(&node(0x80, 3)));
+
+        // Other buckets are unaffected by a full one.
+        assert!(table.insert(node(0x01, 1)));
+        assert_eq!(table.closest(&[0; 20], 1), vec![node(0x01, 1)]);
+    }
+
+    #[test]
+    fn tokens_survive_one_rotation() {
+        let mut secrets = TokenSecrets::new();
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let token = secrets.token(ip);
+        assert!(secrets.verify(ip, &token));
+        assert!(!secrets.verify("10.1.2.4".parse().unwrap(), &token));
+        secrets.rotate();
+        assert!(secrets.verify(ip, &token));
+        secrets.rotate();
+        assert!(!secrets.verify(ip, &token));
+    }
+
+    fn loopback_config(bootstrap: Option<SocketAddr>) -> DhtConfig {
+        DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: bootstrap.iter().map(SocketAddr::to_string).collect(),
+            state_file: None,
+            query_timeout: Duration::from_millis(500),
+        }
+    }
+
+    /// A router plus `count` nodes bootstrapped from it.
+    async fn loopback_swarm(count: usize) -> (Arc<DhtNode>, Vec<Arc<DhtNode>>) {
+        let router = DhtNode::bind(loopback_config(None)).await.unwrap();
+        let router_addr = router.local_addr().unwrap();
+        let mut nodes = Vec::new();
+        for _ in 0..count {
+            let node = DhtNode::bind(loopback_config(Some(router_addr)))
+                .await
+                .unwrap();
+            node.bootstrap().await.unwrap();
+            nodes.push(node);
+        }
+        (router, nodes)
+    }
+
+    #[tokio::test]
+    async fn announce_and_get_peers_across_loopback_swarm() {
+        let (router, nodes) = loopback_swarm(6).await;
+        let router_addr = router.local_addr().unwrap();
+
+        let info_hash = [0x42; 20];
+        assert!(nodes[5].lookup_peers(info_hash).await.is_empty());
+        nodes[1].announce(info_hash, 7777).await.unwrap();
+        let peers = nodes[4].lookup_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:7777".parse().unwrap()]);
+
+        let err = nodes[2]
+            .announce_peer(router_addr, info_hash, 1, b"forged".to_vec())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("203"));
+    }
+
+    #[tokio::test]
+    async fn immutable_and_mutable_items_across_loopback_swarm() {
+        let (_router, nodes) = loopback_swarm(5).await;
+        let value = Value::Str(b"Hello World!".to_vec());
+        let target = nodes[0].put_immutable(value.clone()).await.unwrap();
+        assert_eq!(nodes[3].get_immutable(target).await.unwrap(), value);
+        assert!(nodes[3].get_immutable([9; 20]).await.is_err());
+
+        let key = SigningKey::from_seed(&[5; 32]);
+        let item = MutableItem::sign(&key, b"", 4, Value::Int(4)).unwrap();
+        nodes[1].put_mutable(&item).await.unwrap();
+        assert_eq!(
+            nodes[4].get_mutable(&key.public_key(), b"").await.unwrap(),
+            item
+        );
+        // Nodes holding the newer version refuse older ones, and readers
+        // keep seeing the newest.
+        let stale = MutableItem::sign(&key, b"", 3, Value::Int(3)).unwrap();
+        let _ = nodes[2].put_mutable(&stale).await;
+        assert_eq!(
+            nodes[0].get_mutable(&key.public_key(), b"").await.unwrap(),
+            item
+        );
+    }
+
+    #[tokio::test]
+    async fn bep46_pointer_follows_latest_publish() {
+        let (_router, nodes) = loopback_swarm(4).await;
+        let key = SigningKey::from_seed(&[6; 32]);
+        let salt = b"nightly";
+        let seq = nodes[0]
+            .publish_mutable_torrent(&key, salt, [1; 20])
+            .await
+            .unwrap();
+        assert_eq!(seq, 1);
+        assert_eq!(
+            nodes[2]
+                .resolve_mutable_torrent(&key.public_key(), salt)
+                .await
+                .unwrap(),
+            [1; 20]
+        );
+
+        let seq = nodes[1]
+            .publish_mutable_torrent(&key, salt, [2; 20])
+            .await
+            .unwrap();
+        assert_eq!(seq, 2);
+        assert_eq!(
+            nodes[3]
+                .resolve_mutable_torrent(&key.public_key(), salt)
+                .await
+                .unwrap(),
+            [2; 20]
+        );
+        assert!(nodes[3]
+            .resolve_mutable_torrent(&key.public_key(), b"other")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn routing_table_is_persisted() {
+        let path = std::env::temp_dir().join(format!("dht-state-{:016x}", random::next_u64()));
+        let router = DhtNode::bind(loopback_config(None)).await.unwrap();
+        let config = DhtConfig {
+            state_file: Some(path.clone()),
+            ..loopback_config(Some(router.local_addr().unwrap()))
+        };
+        let node = DhtNode::bind(config.clone()).await.unwrap();
+        node.bootstrap().await.unwrap();
+        node.save().unwrap();
+        let id = node.id();
+        drop(node);
+
+        let restored = DhtNode::bind(config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.id(), id);
+        assert_eq!(restored.routing_table_len(), 1);
+        assert_eq!(
+            restored.ping(router.local_addr().unwrap()).await.unwrap(),
+            router.id()
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use crate::app::bencode::{self, Value};
+use crate::app::ed25519::{self, SigningKey};
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// BEP 44 limit on the bencoded size of `v`.
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+/// Items must be kept for at least two hours after their last put.
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_ITEMS: usize = 1000;
+
+/// Reasons a `put` is refused, with their BEP 44 error codes.
+#[derive(Debug, Error, PartialEq)]
+pub enum PutError {
+    #[error("Message (v field) too big")]
+    TooBig,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Salt (salt field) too big")]
+    SaltTooBig,
+    #[error("The CAS hash mismatched, re-read value and try again")]
+    CasMismatch,
+    #[error("Sequence number less than current")]
+    SequenceTooLow,
+    #[error("Storage full")]
+    Full,
+}
+
+impl PutError {
+    pub fn code(&self) -> i64 {
+        match self {
+            PutError::TooBig => 205,
+            PutError::InvalidSignature => 206,
+            PutError::SaltTooBig => 207,
+            PutError::CasMismatch => 301,
+            PutError::SequenceTooLow => 302,
+            PutError::Full => 202,
+        }
+    }
+}
+
+/// Immutable items are stored under the SHA-1 of their bencoded value.
+pub fn immutable_target(value: &Value) -> Result<[u8; 20]> {
+    Ok(Sha1::digest(bencode::to_vec_u8(value)?).into())
+}
+
+/// Mutable items are stored under the SHA-1 of the public key and salt.
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+/// A value signed by the holder of `public_key`; newer versions carry a
+/// higher `seq`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutableItem {
+    pub public_key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub value: Value,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    pub fn sign(key: &SigningKey, salt: &[u8], seq: i64, value: Value) -> Result<Self> {
+        let signature = key.sign(&signed_buffer(salt, seq, &value)?);
+        Ok(Self {
+            public_key: key.public_key(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature,
+        })
+    }
+
+    pub fn verify(&self) -> bool {
+        signed_buffer(&self.salt, self.seq, &self.value)
+            .is_ok_and(|buffer| ed25519::verify(&self.public_key, &buffer, &self.signature))
+    }
+
+    pub fn target(&self) -> [u8; 20] {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    /// Reads `k`, `seq`, `sig` and `v` from a `put` query or `get` reply.
+    /// The salt is not part of a reply, so the caller supplies it.
+    pub fn from_dict(dict: &Value, salt: &[u8]) -> Option<Self> {
+        Some(Self {
+            public_key: dict.get("k")?.as_bytes()?.try_into().ok()?,
+            salt: salt.to_vec(),
+            seq: dict.get("seq")?.as_int()?,
+            value: dict.get("v")?.clone(),
+            signature: dict.get("sig")?.as_bytes()?.try_into().ok()?,
+        })
+    }
+
+    /// Arguments for a `put` query, apart from the token.
+    pub fn put_args(&self) -> Vec<(&'static str, Value)> {
+        let mut args = vec![
+            ("k", Value::Str(self.public_key.to_vec())),
+            ("seq", Value::Int(self.seq)),
+            ("sig", Value::Str(self.signature.to_vec())),
+            ("v", self.value.clone()),
+        ];
+        if !self.salt.is_empty() {
+            args.push(("salt", Value::Str(self.salt.clone())));
+        }
+        args
+    }
+}
+
+/// The signature covers the bencoded `salt`, `seq` and `v` entries as they
+/// would appear inside a dictionary, without the surrounding `d`/`e`.
+fn signed_buffer(salt: &[u8], seq: i64, value: &Value) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if !salt.is_empty() {
+        buffer.extend_from_slice(b"4:salt");
+        buffer.extend_from_slice(&bencode::to_vec_u8(&Value::Str(salt.to_vec()))?);
+    }
+    buffer.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buffer.extend_from_slice(&bencode::to_vec_u8(value)?);
+    Ok(buffer)
+}
+
+fn check_size(value: &Value) -> Result<(), PutError> {
+    match bencode::to_vec_u8(value) {
+        Ok(encoded) if encoded.len() <= MAX_VALUE_SIZE => Ok(()),
+        _ => Err(PutError::TooBig),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredItem {
+    Immutable(Value),
+    Mutable(MutableItem),
+}
+
+/// Items other nodes have put to us, by target.
+#[derive(Default)]
+pub struct ItemStore {
+    items: HashMap<[u8; 20], (StoredItem, Instant)>,
+}
+
+impl ItemStore {
+    pub fn get(&mut self, target: &[u8; 20]) -> Option<&StoredItem> {
+        self.expire();
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    pub fn put_immutable(&mut self, value: Value) -> Result<[u8; 20], PutError> {
+        check_size(&value)?;
+        let target = immutable_target(&value).map_err(|_| PutError::TooBig)?;
+        self.insert(target, StoredItem::Immutable(value))?;
+        Ok(target)
+    }
+
+    /// Stores `item` if it is validly signed and not older than what we
+    /// have. `cas` must match the stored sequence number when given.
+    pub fn put_mutable(&mut self, item: MutableItem, cas: Option<i64>) -> Result<(), PutError> {
+        check_size(&item.value)?;
+        if item.salt.len() > MAX_SALT_SIZE {
+            return Err(PutError::SaltTooBig);
+        }
+        if !item.verify() {
+            return Err(PutError::InvalidSignature);
+        }
+        let target = item.target();
+        if let Some((StoredItem::Mutable(current), _)) = self.items.get(&target) {
+            if cas.is_some_and(|cas| cas != current.seq) {
+                return Err(PutError::CasMismatch);
+            }
+            if item.seq < current.seq {
+                return Err(PutError::SequenceTooLow);
+            }
+        }
+        self.insert(target, StoredItem::Mutable(item))
+    }
+
+    fn insert(&mut self, target: [u8; 20], item: StoredItem) -> Result<(), PutError> {
+        self.expire();
+        if self.items.len() >= MAX_ITEMS && !self.items.contains_key(&target) {
+            return Err(PutError::Full);
+        }
+        self.items.insert(target, (item, Instant::now()));
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        self.items
+            .retain(|_, (_, stored)| stored.elapsed() < ITEM_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> Value {
+        Value::Str(b"Hello World!".to_vec())
+    }
+
+    /// Test vectors from BEP 44, which publishes the expanded private key.
+    fn bep44_key() -> SigningKey {
+        let expanded = hex::decode(
+            "e06d3183d14159228433ed599221b80bd0a5ce8352e4bdf0262f76786ef1c74d\
+             b7e7a9fea2c0eb269d61e3b38e450a22e754941ac78479d6c54e1faf6037881d",
+        )
+        .unwrap();
+        SigningKey::from_expanded(&expanded.try_into().unwrap())
+    }
+
+    #[test]
+    fn bep44_immutable_target() {
+        assert_eq!(
+            hex::encode(immutable_target(&hello()).unwrap()),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+    }
+
+    #[test]
+    fn bep44_mutable_signatures() {
+        let key = bep44_key();
+        assert_eq!(
+            hex::encode(key.public_key()),
+            "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548"
+        );
+        assert_eq!(
+            signed_buffer(b"", 1, &hello()).unwrap(),
+            b"3:seqi1e1:v12:Hello World!"
+        );
+        let item = MutableItem::sign(&key, b"", 1, hello()).unwrap();
+        assert_eq!(
+            hex::encode(item.signature),
+            "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+             1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"
+        );
+        assert!(item.verify());
+        assert_eq!(
+            hex::encode(item.target()),
+            "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+        );
+
+        let salted = MutableItem::sign(&key, b"foobar", 1, hello()).unwrap();
+        assert_eq!(
+            hex::encode(salted.signature),
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+             df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"
+        );
+        assert_eq!(
+            hex::encode(salted.target()),
+            "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+        );
+    }
+
+    #[test]
+    fn store_enforces_sequence_cas_and_signature() {
+        let key = bep44_key();
+        let mut store = ItemStore::default();
+        let v2 = MutableItem::sign(&key, b"", 2, hello()).unwrap();
+        store.put_mutable(v2.clone(), None).unwrap();
+        let v1 = MutableItem::sign(&key, b"", 1, hello()).unwrap();
+        assert_eq!(store.put_mutable(v1, None), Err(PutError::SequenceTooLow));
+        let v3 = MutableItem::sign(&key, b"", 3, Value::Int(3)).unwrap();
+        assert_eq!(
+            store.put_mutable(v3.clone(), Some(1)),
+            Err(PutError::CasMismatch)
+        );
+        let mut forged = v3.clone();
+        forged.value = Value::Int(4);
+        assert_eq!(
+            store.put_mutable(forged, None),
+            Err(PutError::InvalidSignature)
+        );
+        store.put_mutable(v3.clone(), Some(2)).unwrap();
+        assert_eq!(store.get(&v2.target()), Some(&StoredItem::Mutable(v3)));
+
+        let too_big = Value::Str(vec![0; MAX_VALUE_SIZE]);
+        assert_eq!(store.put_immutable(too_big), Err(PutError::TooBig));
+    }
+}
@@ -0,0 +1,512 @@
+use crate::app::sha512;
+use std::ops::{Add, Mul, Sub};
+use std::sync::OnceLock;
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// Element of GF(2^255 - 19) in five 51-bit limbs. Every operation leaves
+/// the limbs weakly reduced, i.e. only a few bits over 51.
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+/// Exponents (little endian) used with `Fe::pow`.
+const P_MINUS_2: [u8; 32] = exponent(0xeb, 0x7f);
+const P_MINUS_5_DIV_8: [u8; 32] = exponent(0xfd, 0x0f);
+const P_MINUS_1_DIV_4: [u8; 32] = exponent(0xfb, 0x1f);
+
+const fn exponent(low: u8, high: u8) -> [u8; 32] {
+    let mut bytes = [0xff; 32];
+    bytes[0] = low;
+    bytes[31] = high;
+    bytes
+}
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 5]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_u64(value: u64) -> Fe {
+        Fe([value & MASK, value >> 51, 0, 0, 0])
+    }
+
+    /// Ignores the top bit, which encodings use for the sign of x.
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let load = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Fe([
+            load(0) & MASK,
+            (load(6) >> 3) & MASK,
+            (load(12) >> 6) & MASK,
+            (load(19) >> 1) & MASK,
+            (load(24) >> 12) & MASK,
+        ])
+    }
+
+    fn carry(mut limbs: [u64; 5]) -> Fe {
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK;
+        }
+        limbs[0] += 19 * (limbs[4] >> 51);
+        limbs[4] &= MASK;
+        Fe(limbs)
+    }
+
+    /// Canonical little-endian encoding, fully reduced mod p.
+    fn to_bytes(self) -> [u8; 32] {
+        let mut l = Fe::carry(Fe::carry(self.0).0).0;
+        // Adding 19 carries out of bit 255 exactly when l >= p.
+        let mut q = (l[0] + 19) >> 51;
+        for limb in &l[1..] {
+            q = (limb + q) >> 51;
+        }
+        l[0] += 19 * q;
+        for i in 0..4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK;
+        }
+        l[4] &= MASK;
+
+        let words = [
+            l[0] | (l[1] << 51),
+            (l[1] >> 13) | (l[2] << 38),
+            (l[2] >> 26) | (l[3] << 25),
+            (l[3] >> 39) | (l[4] << 12),
+        ];
+        let mut bytes = [0u8; 32];
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn square(self) -> Fe {
+        self * self
+    }
+
+    fn pow(self, exponent: &[u8; 32]) -> Fe {
+        let mut result = Fe::ONE;
+        for bit in (0..256).rev() {
+            result = result.square();
+            if (exponent[bit / 8] >> (bit % 8)) & 1 == 1 {
+                result = result * self;
+            }
+        }
+        result
+    }
+
+    fn invert(self) -> Fe {
+        self.pow(&P_MINUS_2)
+    }
+
+    fn neg(self) -> Fe {
+        Fe::ZERO - self
+    }
+
+    fn is_negative(self) -> bool {
+        self.to_bytes()[0] & 1 == 1
+    }
+
+    fn is_zero(self) -> bool {
+        self.to_bytes() == [0; 32]
+    }
+
+    fn equals(self, other: Fe) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+
+    /// Swaps `a` and `b` when `swap` is 1 without branching on it.
+    fn conditional_swap(a: &mut Fe, b: &mut Fe, swap: u64) {
+        let mask = 0u64.wrapping_sub(swap);
+        for (x, y) in a.0.iter_mut().zip(b.0.iter_mut()) {
+            let t = mask & (*x ^ *y);
+            *x ^= t;
+            *y ^= t;
+        }
+    }
+}
+
+impl Add for Fe {
+    type Output = Fe;
+
+    fn add(self, rhs: Fe) -> Fe {
+        Fe::carry(std::array::from_fn(|i| self.0[i] + rhs.0[i]))
+    }
+}
+
+impl Sub for Fe {
+    type Output = Fe;
+
+    /// Adds 2p first so the limbs cannot underflow.
+    fn sub(self, rhs: Fe) -> Fe {
+        let rhs = Fe::carry(rhs.0);
+        let two_p = [2 * (MASK - 18), 2 * MASK, 2 * MASK, 2 * MASK, 2 * MASK];
+        Fe::carry(std::array::from_fn(|i| self.0[i] + two_p[i] - rhs.0[i]))
+    }
+}
+
+impl Mul for Fe {
+    type Output = Fe;
+
+    fn mul(self, rhs: Fe) -> Fe {
+        let a = self.0.map(u128::from);
+        let b = rhs.0.map(u128::from);
+        let b19 = b.map(|limb| limb * 19);
+        let r = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+        let mut limbs = [0u64; 5];
+        let mut carry = 0u128;
+        for i in 0..5 {
+            let value = r[i] + carry;
+            limbs[i] = value as u64 & MASK;
+            carry = value >> 51;
+        }
+        let value = limbs[0] as u128 + carry * 19;
+        limbs[0] = value as u64 & MASK;
+        limbs[1] += (value >> 51) as u64;
+        Fe(limbs)
+    }
+}
+
+/// Point on the twisted Edwards curve -x^2 + y^2 = 1 + d x^2 y^2 in extended
+/// coordinates (x = X/Z, y = Y/Z, xy = T/Z).
+#[derive(Clone, Copy)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+struct Curve {
+    d: Fe,
+    d2: Fe,
+    sqrt_m1: Fe,
+    base: Point,
+}
+
+fn curve() -> &'static Curve {
+    static CURVE: OnceLock<Curve> = OnceLock::new();
+    CURVE.get_or_init(|| {
+        let d = Fe::from_u64(121665).neg() * Fe::from_u64(121666).invert();
+        let sqrt_m1 = Fe::from_u64(2).pow(&P_MINUS_1_DIV_4);
+        let mut base = [0x66u8; 32];
+        base[0] = 0x58;
+        let base = Point::decompress_with(&base, d, sqrt_m1).expect("valid base point");
+        Curve {
+            d,
+            d2: d + d,
+            sqrt_m1,
+            base,
+        }
+    })
+}
+
+impl Point {
+    const IDENTITY: Point = Point {
+        x: Fe::ZERO,
+        y: Fe::ONE,
+        z: Fe::ONE,
+        t: Fe::ZERO,
+    };
+
+    /// Complete addition formula for a = -1; also used for doubling.
+    fn add(&self, other: &Point) -> Point {
+        let a = (self.y - self.x) * (other.y - other.x);
+        let b = (self.y + self.x) * (other.y + other.x);
+        let c = self.t * curve().d2 * other.t;
+        let d = self.z * other.z;
+        let d = d + d;
+        let (e, f, g, h) = (b - a, d - c, d + c, b + a);
+        Point {
+            x: e * f,
+            y: g * h,
+            z: f * g,
+            t: e * h,
+        }
+    }
+
+    fn neg(&self) -> Point {
+        Point {
+            x: self.x.neg(),
+            y: self.y,
+            z: self.z,
+            t: self.t.neg(),
+        }
+    }
+
+    /// Montgomery ladder over all 256 bits of a little-endian scalar, so the
+    /// sequence of operations does not depend on the secret.
+    fn mul(&self, scalar: &[u8; 32]) -> Point {
+        let (mut r0, mut r1) = (Point::IDENTITY, *self);
+        for bit in (0..256).rev() {
+            let swap = ((scalar[bit / 8] >> (bit % 8)) & 1) as u64;
+            Point::conditional_swap(&mut r0, &mut r1, swap);
+            r1 = r0.add(&r1);
+            r0 = r0.add(&r0);
+            Point::conditional_swap(&mut r0, &mut r1, swap);
+        }
+        r0
+    }
+
+    fn conditional_swap(a: &mut Point, b: &mut Point, swap: u64) {
+        Fe::conditional_swap(&mut a.x, &mut b.x, swap);
+        Fe::conditional_swap(&mut a.y, &mut b.y, swap);
+        Fe::conditional_swap(&mut a.z, &mut b.z, swap);
+        Fe::conditional_swap(&mut a.t, &mut b.t, swap);
+    }
+
+    fn compress(&self) -> [u8; 32] {
+        let z_inv = self.z.invert();
+        let x = self.x * z_inv;
+        let mut bytes = (self.y * z_inv).to_bytes();
+        bytes[31] |= (x.is_negative() as u8) << 7;
+        bytes
+    }
+
+    fn decompress(bytes: &[u8; 32]) -> Option<Point> {
+        let curve = curve();
+        Point::decompress_with(bytes, curve.d, curve.sqrt_m1)
+    }
+
+    /// RFC 8032 section 5.1.3: recover x from y and its sign bit.
+    fn decompress_with(bytes: &[u8; 32], d: Fe, sqrt_m1: Fe) -> Option<Point> {
+        let y = Fe::from_bytes(bytes);
+        if y.to_bytes()[..31] != bytes[..31] || y.to_bytes()[31] != bytes[31] & 0x7f {
+            return None;
+        }
+        let sign = bytes[31] >> 7 == 1;
+        let y2 = y.square();
+        let u = y2 - Fe::ONE;
+        let v = d * y2 + Fe::ONE;
+        let v3 = v.square() * v;
+        let mut x = u * v3 * (u * v3.square() * v).pow(&P_MINUS_5_DIV_8);
+        let vx2 = v * x.square();
+        if !vx2.equals(u) {
+            if vx2.equals(u.neg()) {
+                x = x * sqrt_m1;
+            } else {
+                return None;
+            }
+        }
+        if x.is_zero() && sign {
+            return None;
+        }
+        if x.is_negative() != sign {
+            x = x.neg();
+        }
+        Some(Point {
+            x,
+            y,
+            z: Fe::ONE,
+            t: x * y,
+        })
+    }
+}
+
+/// The group order 2^252 + 27742317777372353535851937790883648493, as
+/// little-endian 64-bit limbs.
+const L: [u64; 4] = [
+    0x5812631a5cf5d3ed,
+    0x14def9dea2f79cd6,
+    0x0000000000000000,
+    0x1000000000000000,
+];
+
+fn ge(a: &[u64; 4], b: &[u64; 4]) -> bool {
+    for i in (0..4).rev() {
+        if a[i] != b[i] {
+            return a[i] > b[i];
+        }
+    }
+    true
+}
+
+fn sub_assign(a: &mut [u64; 4], b: &[u64; 4]) {
+    let mut borrow = 0u64;
+    for i in 0..4 {
+        let (value, b1) = a[i].overflowing_sub(b[i]);
+        let (value, b2) = value.overflowing_sub(borrow);
+        a[i] = value;
+        borrow = (b1 || b2) as u64;
+    }
+}
+
+/// Reduces a little-endian number of up to 512 bits modulo L, one bit at a
+/// time from the top.
+fn reduce(bytes: &[u8]) -> [u8; 32] {
+    let mut r = [0u64; 4];
+    for bit in (0..bytes.len() * 8).rev() {
+        // r < L < 2^253, so shifting left cannot overflow 256 bits.
+        for i in (1..4).rev() {
+            r[i] = (r[i] << 1) | (r[i - 1] >> 63);
+        }
+        r[0] = (r[0] << 1) | ((bytes[bit / 8] >> (bit % 8)) & 1) as u64;
+        if ge(&r, &L) {
+            sub_assign(&mut r, &L);
+        }
+    }
+    let mut out = [0u8; 32];
+    for (chunk, limb) in out.chunks_exact_mut(8).zip(r) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    out
+}
+
+fn limbs(bytes: &[u8; 32]) -> [u64; 4] {
+    std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+}
+
+/// (a * b + c) mod L.
+fn mul_add(a: &[u8; 32], b: &[u8; 32], c: &[u8; 32]) -> [u8; 32] {
+    let (a, b) = (limbs(a), limbs(b));
+    let mut wide = [0u64; 8];
+    for (i, word) in limbs(c).into_iter().enumerate() {
+        wide[i] = word;
+    }
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let value = wide[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+            wide[i + j] = value as u64;
+            carry = value >> 64;
+        }
+        let mut k = i + 4;
+        while carry > 0 {
+            let value = wide[k] as u128 + carry;
+            wide[k] = value as u64;
+            carry = value >> 64;
+            k += 1;
+        }
+    }
+    let mut bytes = [0u8; 64];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(wide) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    reduce(&bytes)
+}
+
+/// An ed25519 private key, held in expanded form: the clamped secret scalar
+/// and the nonce prefix.
+#[derive(Clone)]
+pub struct SigningKey {
+    scalar: [u8; 32],
+    prefix: [u8; 32],
+    public: [u8; 32],
+}
+
+impl SigningKey {
+    /// Derives the key from a 32 byte seed as in RFC 8032.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let hash = sha512::digest(&[seed]);
+        let mut expanded = [0u8; 64];
+        expanded.copy_from_slice(&hash);
+        expanded[0] &= 248;
+        expanded[31] &= 127;
+        expanded[31] |= 64;
+        Self::from_expanded(&expanded)
+    }
+
+    /// Accepts the 64 byte expanded form other DHT implementations store.
+    pub fn from_expanded(expanded: &[u8; 64]) -> Self {
+        let scalar: [u8; 32] = expanded[..32].try_into().unwrap();
+        Self {
+            scalar,
+            prefix: expanded[32..].try_into().unwrap(),
+            public: curve().base.mul(&scalar).compress(),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        let r = reduce(&sha512::digest(&[&self.prefix, message]));
+        let big_r = curve().base.mul(&r).compress();
+        let k = reduce(&sha512::digest(&[&big_r, &self.public, message]));
+        let s = mul_add(&k, &self.scalar, &r);
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&big_r);
+        signature[32..].copy_from_slice(&s);
+        signature
+    }
+}
+
+/// Checks an RFC 8032 signature, rejecting non-canonical `S` values.
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let Some(a) = Point::decompress(public_key) else {
+        return false;
+    };
+    let s: [u8; 32] = signature[32..].try_into().unwrap();
+    if ge(&limbs(&s), &L) {
+        return false;
+    }
+    let k = reduce(&sha512::digest(&[&signature[..32], public_key, message]));
+    let check = curve().base.mul(&s).add(&a.neg().mul(&k));
+    check.compress()[..] == signature[..32]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn curve_constants_are_consistent() {
+        let curve = curve();
+        assert!((curve.sqrt_m1.square() + Fe::ONE).is_zero());
+        let mut l = [0u8; 32];
+        for (chunk, limb) in l.chunks_exact_mut(8).zip(L) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        assert_eq!(
+            curve.base.mul(&l).compress(),
+            Point::IDENTITY.compress(),
+            "L must be the order of the base point"
+        );
+    }
+
+    #[test]
+    fn rfc8032_test_vectors() {
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (seed, public, message, signature) in vectors {
+            let key = SigningKey::from_seed(&bytes(seed));
+            let message = hex::decode(message).unwrap();
+            assert_eq!(hex::encode(key.public_key()), public);
+            assert_eq!(hex::encode(key.sign(&message)), signature);
+            assert!(verify(&bytes(public), &message, &bytes(signature)));
+        }
+    }
+
+    #[test]
+    fn tampered_signatures_fail() {
+        let key = SigningKey::from_seed(&[7; 32]);
+        let mut signature = key.sign(b"message");
+        assert!(verify(&key.public_key(), b"message", &signature));
+        assert!(!verify(&key.public_key(), b"massage", &signature));
+        signature[40] ^= 1;
+        assert!(!verify(&key.public_key(), b"message", &signature));
+        assert!(!verify(&[0xff; 32], b"message", &key.sign(b"message")));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::app::bencode::{self, Value};
+use crate::app::messages::BTMessage;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+/// Extended message id 0 is always the BEP 10 handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+/// Outstanding requests we accept from one peer, advertised as `reqq`.
+pub const LOCAL_REQQ: u32 = 250;
+const CLIENT_NAME: &str = concat!("XX ", env!("CARGO_PKG_VERSION"));
+
+/// The bencoded dictionary carried by extended message 0.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExtendedHandshake {
+    /// Extension name to the id the sender wants it delivered under. An id
+    /// of 0 disables the extension, so such entries are left out.
+    pub messages: HashMap<String, u8>,
+    /// Client name and version (`v`).
+    pub client: Option<String>,
+    /// The sender's listen port (`p`).
+    pub listen_port: Option<u16>,
+    /// How many requests the sender queues before dropping more (`reqq`).
+    pub reqq: Option<u32>,
+    /// Our address as the sender sees it (`yourip`).
+    pub your_ip: Option<IpAddr>,
+    /// Size of the info dictionary, for ut_metadata (`metadata_size`).
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let dict = bencode::decode(payload)?;
+        if dict.as_dict().is_none() {
+            return Err(anyhow!("Extended handshake is not a dictionary"));
+        }
+        let messages = dict
+            .get("m")
+            .and_then(Value::as_dict)
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| {
+                        let id = u8::try_from(id.as_int()?).ok().filter(|&id| id != 0)?;
+                        Some((String::from_utf8_lossy(name).into_owned(), id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let int = |key: &str| dict.get(key).and_then(Value::as_int);
+        Ok(Self {
+            messages,
+            client: dict
+                .get("v")
+                .and_then(Value::as_bytes)
+                .map(|v| String::from_utf8_lossy(v).into_owned()),
+            listen_port: int("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: int("reqq").and_then(|reqq| u32::try_from(reqq).ok()),
+            your_ip: dict
+                .get("yourip")
+                .and_then(Value::as_bytes)
+                .and_then(|ip| match ip.len() {
+                    4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?))),
+                    16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?))),
+                    _ => None,
+                }),
+            metadata_size: int("metadata_size").and_then(|size| u64::try_from(size).ok()),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let m = self
+            .messages
+            .iter()
+            .map(|(name, &id)| (name.as_bytes().to_vec(), Value::Int(id as i64)))
+            .collect();
+        let mut entries = vec![("m", Value::Dict(m))];
+        if let Some(client) = &self.client {
+            entries.push(("v", Value::Str(client.as_bytes().to_vec())));
+        }
+        if let Some(port) = self.listen_port {
+            entries.push(("p", Value::Int(port as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            entries.push(("reqq", Value::Int(reqq as i64)));
+        }
+        if let Some(ip) = self.your_ip {
+            let compact = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            entries.push(("yourip", Value::Str(compact)));
+        }
+        if let Some(size) = self.metadata_size {
+            entries.push(("metadata_size", Value::Int(size as i64)));
+        }
+        bencode::to_vec_u8(&Value::dict(entries))
+    }
+}
+
+/// An extension negotiated through the BEP 10 handshake, such as ut_pex or
+/// ut_metadata. One instance serves one connection.
+pub trait ExtensionHandler: Send {
+    /// Name in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific fields, like `metadata_size`, to our
+    /// handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called with the peer's handshake if it lists this extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) {}
+
+    /// Handles a message the peer sent under our id for this extension.
+    fn on_message(&mut self, payload: &[u8]) -> Result<()>;
+
+    /// Payload to send the peer now, if any. Polled periodically.
+    fn poll_message(&mut self, _now: Instant) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// Extensions enabled on one connection. Handlers plug in by name and get
+/// local ids in registration order; the peer's ids come from its handshake.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    /// Handler for local id `i + 1` at index `i`.
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    remote: HashMap<String, u8>,
+    /// The peer's `reqq`, once its handshake arrived.
+    remote_reqq: Option<u32>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a handler and returns the id the peer should use for it.
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8 {
+        self.handlers.push(handler);
+        self.handlers.len() as u8
+    }
+
+    /// Our extended handshake, sent right after the BitTorrent handshake to
+    /// peers that set the BEP 10 reserved bit.
+    pub fn handshake(&self, listen_port: u16, peer_ip: Option<IpAddr>) -> Result<BTMessage> {
+        let mut handshake = ExtendedHandshake {
+            messages: self
+                .handlers
+                .iter()
+                .zip(1..)
+                .map(|(handler, id)| (handler.name().to_owned(), id))
+                .collect(),
+            client: Some(CLIENT_NAME.to_owned()),
+            listen_port: Some(listen_port),
+            reqq: Some(LOCAL_REQQ),
+            your_ip: peer_ip,
+            metadata_size: None,
+        };
+        for handler in &self.handlers {
+            handler.extend_handshake(&mut handshake);
+        }
+        Ok(BTMessage::Extended(HANDSHAKE_ID, handshake.to_bytes()?))
+    }
+
+    /// How many requests the peer says it queues, if it told us.
+    pub fn remote_reqq(&self) -> Option<u32> {
+        self.remote_reqq
+    }
+
+    /// Dispatches an extended message from the peer.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        if id == HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::parse(payload)?;
+            log::debug!(
+                "Extended handshake from {:?} (reqq {:?}): {:?}",
+                handshake.client,
+                handshake.reqq,
+                handshake.messages
+            );
+            for handler in &mut self.handlers {
+                if handshake.messages.contains_key(handler.name()) {
+                    handler.on_handshake(&handshake);
+                }
+            }
+            self.remote = handshake.messages;
+            self.remote_reqq = handshake.reqq;
+            return Ok(());
+        }
+        match self.handlers.get_mut(id as usize - 1) {
+            Some(handler) => handler.on_message(payload),
+            None => {
+                log::debug!("Ignoring extended message with unknown id {}", id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Collects what the handlers want to send, for extensions the peer
+    /// supports.
+    pub fn poll_messages(&mut self, now: Instant) -> Result<Vec<BTMessage>> {
+        let mut messages = Vec::new();
+        for handler in &mut self.handlers {
+            let Some(&id) = self.remote.get(handler.name()) else {
+                continue;
+            };
+            if let Some(payload) = handler.poll_message(now)? {
+                messages.push(BTMessage::Extended(id, payload));
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn handshake_round_trip() {
+        let encoded =
+            b"d1:md6:ut_pexi2e11:ut_metadatai0ee13:metadata_sizei31235e1:pi6881e4:reqqi500e1:v5:XX 1a6:yourip4:\x0a\x00\x00\x07e";
+        let handshake = ExtendedHandshake::parse(encoded).unwrap();
+        assert_eq!(
+            handshake.messages,
+            HashMap::from([("ut_pex".to_owned(), 2)])
+        );
+        assert_eq!(handshake.listen_port, Some(6881));
+        assert_eq!(handshake.client.as_deref(), Some("XX 1a"));
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.your_ip, Some("10.0.0.7".parse().unwrap()));
+        assert_eq!(handshake.metadata_size, Some(31235));
+        assert_eq!(
+            ExtendedHandshake::parse(&handshake.to_bytes().unwrap()).unwrap(),
+            handshake
+        );
+
+        let ipv6 = ExtendedHandshake {
+            your_ip: Some("2001:db8::7".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            ExtendedHandshake::parse(&ipv6.to_bytes().unwrap()).unwrap(),
+            ipv6
+        );
+        assert!(ExtendedHandshake::parse(b"i1e").is_err());
+    }
+
+    /// Records incoming payloads and sends one message when polled.
+    struct Echo {
+        name: &'static str,
+        received: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl ExtensionHandler for Echo {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake.metadata_size = Some(42);
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<()> {
+            self.received.lock().unwrap().push(payload.to_vec());
+            Ok(())
+        }
+
+        fn poll_message(&mut self, _now: Instant) -> Result<Option<Vec<u8>>> {
+            Ok(Some(self.name.as_bytes().to_vec()))
+        }
+    }
+
+    #[test]
+    fn registry_routes_by_local_and_remote_ids() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = ExtensionRegistry::new();
+        for name in ["ut_a", "ut_b"] {
+            registry.register(Box::new(Echo {
+                name,
+                received: received.clone(),
+            }));
+        }
+
+        let BTMessage::Extended(HANDSHAKE_ID, payload) = registry
+            .handshake(6881, Some("192.168.1.9".parse().unwrap()))
+            .unwrap()
+        else {
+            panic!("Expected an extended handshake");
+        };
+        let ours = ExtendedHandshake::parse(&payload).unwrap();
+        assert_eq!(ours.messages["ut_a"], 1);
+        assert_eq!(ours.messages["ut_b"], 2);
+        assert_eq!(ours.reqq, Some(LOCAL_REQQ));
+        assert_eq!(ours.metadata_size, Some(42));
+
+        // Nothing goes out before the peer has told us its ids.
+        assert!(registry.poll_messages(Instant::now()).unwrap().is_empty());
+        let theirs = ExtendedHandshake {
+            messages: HashMap::from([("ut_b".to_owned(), 9)]),
+            ..Default::default()
+        };
+        registry
+            .handle(HANDSHAKE_ID, &theirs.to_bytes().unwrap())
+            .unwrap();
+        let outgoing = registry.poll_messages(Instant::now()).unwrap();
+        assert!(matches!(&outgoing[..], [BTMessage::Extended(9, payload)] if payload == b"ut_b"));
+
+        registry.handle(2, b"hello").unwrap();
+        registry.handle(7, b"unknown").unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![b"hello".to_vec()]);
+    }
+}
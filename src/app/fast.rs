@@ -0,0 +1,111 @@
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Size of the allowed fast set we grant each peer.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// A block request: piece index, offset and length.
+pub type BlockRequest = (u32, u32, u32);
+
+/// The BEP 6 allowed fast set for a peer at `ip`: `count` piece indices
+/// derived from its /24 network and the info hash, so every client grants
+/// the same pieces. Only IPv4 has a canonical form; IPv6 peers get none.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8; 20],
+    num_pieces: u32,
+    count: usize,
+) -> Vec<u32> {
+    let IpAddr::V4(ip) = ip else {
+        return Vec::new();
+    };
+    let count = count.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(count);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffff_ff00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+/// Fast Extension state for one peer.
+#[derive(Debug, Default)]
+pub struct FastState {
+    /// Pieces the peer lets us request while it chokes us.
+    allowed_fast: HashSet<u32>,
+    /// Requests the peer rejected, not to be sent to it again until it
+    /// unchokes us anew.
+    rejected: HashSet<BlockRequest>,
+}
+
+impl FastState {
+    pub fn allow(&mut self, index: u32) {
+        self.allowed_fast.insert(index);
+    }
+
+    /// Whether the peer lets us request `index` while choking us.
+    pub fn allows(&self, index: u32) -> bool {
+        self.allowed_fast.contains(&index)
+    }
+
+    pub fn reject(&mut self, request: BlockRequest) {
+        self.rejected.insert(request);
+    }
+
+    pub fn rejected(&self, request: &BlockRequest) -> bool {
+        self.rejected.contains(request)
+    }
+
+    pub fn clear_rejected(&mut self) {
+        self.rejected.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_fast_set_matches_bep6_example() {
+        let ip = "80.4.4.200".parse().unwrap();
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // Same /24, same set; small torrents cap the set size.
+        assert_eq!(
+            allowed_fast_set("80.4.4.1".parse().unwrap(), &info_hash, 1313, 7),
+            allowed_fast_set(ip, &info_hash, 1313, 7)
+        );
+        assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
+        assert!(allowed_fast_set("::1".parse().unwrap(), &info_hash, 1313, 7).is_empty());
+    }
+
+    #[test]
+    fn rejected_requests_are_remembered_until_cleared() {
+        let mut fast = FastState::default();
+        fast.reject((1, 0, 16384));
+        fast.reject((1, 0, 16384));
+        assert!(fast.rejected(&(1, 0, 16384)));
+        assert!(!fast.rejected(&(2, 0, 16384)));
+        fast.clear_rejected();
+        assert!(!fast.rejected(&(1, 0, 16384)));
+    }
+}
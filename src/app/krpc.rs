@@ -0,0 +1,225 @@
+use crate::app::bencode::{self, Value};
+use anyhow::{anyhow, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+/// KRPC error codes from BEP 5.
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// Compact node info: 20 byte id followed by a 6 byte IPv4 address.
+const COMPACT_NODE_LEN: usize = 26;
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KrpcBody {
+    /// `y=q`: a method call with its argument dictionary.
+    Query { method: String, args: Value },
+    /// `y=r`: the return value dictionary.
+    Response(Value),
+    /// `y=e`: `[code, message]`.
+    Error { code: i64, message: String },
+}
+
+/// One KRPC datagram. The transaction id is echoed back by the responder so
+/// replies can be matched to outstanding queries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcMessage {
+    pub transaction_id: Vec<u8>,
+    pub body: KrpcBody,
+}
+
+impl KrpcMessage {
+    pub fn query(transaction_id: Vec<u8>, method: &str, args: Value) -> Self {
+        Self {
+            transaction_id,
+            body: KrpcBody::Query {
+                method: method.to_owned(),
+                args,
+            },
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let t = Value::Str(self.transaction_id.clone());
+        let message = match &self.body {
+            KrpcBody::Query { method, args } => Value::dict([
+                ("t", t),
+                ("y", Value::Str(b"q".to_vec())),
+                ("q", Value::Str(method.as_bytes().to_vec())),
+                ("a", args.clone()),
+            ]),
+            KrpcBody::Response(values) => Value::dict([
+                ("t", t),
+                ("y", Value::Str(b"r".to_vec())),
+                ("r", values.clone()),
+            ]),
+            KrpcBody::Error { code, message } => Value::dict([
+                ("t", t),
+                ("y", Value::Str(b"e".to_vec())),
+                (
+                    "e",
+                    Value::List(vec![
+                        Value::Int(*code),
+                        Value::Str(message.as_bytes().to_vec()),
+                    ]),
+                ),
+            ]),
+        };
+        bencode::to_vec_u8(&message)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let message = bencode::decode(bytes)?;
+        let transaction_id = message
+            .get("t")
+            .and_then(Value::as_bytes)
+            .ok_or(anyhow!("KRPC message without transaction id"))?
+            .to_vec();
+        let kind = message.get("y").and_then(Value::as_bytes);
+        let body = match kind {
+            Some(b"q") => {
+                let method = message
+                    .get("q")
+                    .and_then(Value::as_bytes)
+                    .ok_or(anyhow!("KRPC query without method name"))?;
+                let args = message
+                    .get("a")
+                    .filter(|args| args.as_dict().is_some())
+                    .ok_or(anyhow!("KRPC query without argument dictionary"))?;
+                KrpcBody::Query {
+                    method: String::from_utf8_lossy(method).into_owned(),
+                    args: args.clone(),
+                }
+            }
+            Some(b"r") => {
+                let values = message
+                    .get("r")
+                    .filter(|values| values.as_dict().is_some())
+                    .ok_or(anyhow!("KRPC response without return dictionary"))?;
+                KrpcBody::Response(values.clone())
+            }
+            Some(b"e") => {
+                let error = message.get("e").and_then(Value::as_list).unwrap_or(&[]);
+                KrpcBody::Error {
+                    code: error.first().and_then(Value::as_int).unwrap_or(0),
+                    message: error
+                        .get(1)
+                        .and_then(Value::as_bytes)
+                        .map(|m| String::from_utf8_lossy(m).into_owned())
+                        .unwrap_or_default(),
+                }
+            }
+            _ => return Err(anyhow!("Unknown KRPC message type {:?}", kind)),
+        };
+        Ok(Self {
+            transaction_id,
+            body,
+        })
+    }
+}
+
+/// Reads a 20 byte id (node id, info hash or target) from a dictionary.
+pub fn get_id(dict: &Value, key: &str) -> Option<NodeId> {
+    dict.get(key)?.as_bytes()?.try_into().ok()
+}
+
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        // Only IPv4 contacts fit the BEP 5 `nodes` format.
+        if let SocketAddr::V4(addr) = node.addr {
+            compact.extend_from_slice(&node.id);
+            compact.extend_from_slice(&encode_peer(addr));
+        }
+    }
+    compact
+}
+
+pub fn decode_nodes(compact: &[u8]) -> Vec<NodeInfo> {
+    compact
+        .chunks_exact(COMPACT_NODE_LEN)
+        .map(|chunk| NodeInfo {
+            id: chunk[..20].try_into().unwrap(),
+            addr: SocketAddr::V4(decode_peer(&chunk[20..]).unwrap()),
+        })
+        .collect()
+}
+
+/// Compact IPv4 peer info as used in `values`: address then port.
+pub fn encode_peer(addr: SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0u8; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+pub fn decode_peer(compact: &[u8]) -> Option<SocketAddrV4> {
+    let compact: [u8; 6] = compact.try_into().ok()?;
+    let ip = Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]);
+    Some(SocketAddrV4::new(
+        ip,
+        u16::from_be_bytes([compact[4], compact[5]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_round_trip_matches_bep5_example() {
+        let ping = KrpcMessage::query(
+            b"aa".to_vec(),
+            "ping",
+            Value::dict([("id", Value::Str(b"abcdefghij0123456789".to_vec()))]),
+        );
+        let encoded = ping.encode().unwrap();
+        assert_eq!(
+            encoded,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+        assert_eq!(KrpcMessage::decode(&encoded).unwrap(), ping);
+    }
+
+    #[test]
+    fn decode_error_and_reject_garbage() {
+        let error =
+            KrpcMessage::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            error.body,
+            KrpcBody::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".to_owned()
+            }
+        );
+        assert!(KrpcMessage::decode(b"d1:t2:aa1:y1:qe").is_err());
+        assert!(KrpcMessage::decode(b"d1:y1:re").is_err());
+        assert!(KrpcMessage::decode(b"\xff\x00garbage").is_err());
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let nodes = vec![
+            NodeInfo {
+                id: [1; 20],
+                addr: "10.0.0.1:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: [2; 20],
+                addr: "192.168.1.2:51413".parse().unwrap(),
+            },
+        ];
+        let compact = encode_nodes(&nodes);
+        assert_eq!(compact.len(), 52);
+        assert_eq!(decode_nodes(&compact), nodes);
+        // Trailing partial entries are ignored.
+        assert_eq!(decode_nodes(&compact[..30]), nodes[..1]);
+    }
+}
@@ -0,0 +1,390 @@
+use crate::app::network::Peer;
+use crate::app::peer_source::{PeerSource, PeerStream};
+use crate::app::random;
+use anyhow::{anyhow, Result};
+use futures::stream::{self, StreamExt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// BEP 14 multicast groups, both on port 6771.
+pub const LSD_GROUP_V4: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), 6771);
+pub const LSD_GROUP_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    6771,
+);
+/// BEP 14 asks for announces every five minutes and at most once a minute.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MAX_DATAGRAM: usize = 1400;
+
+/// A `BT-SEARCH` announcement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LsdAnnounce {
+    /// Port the announcing client accepts peer connections on.
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Random per-session token so clients can drop their own announces
+    /// looped back by the network.
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self, group: SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(data)?;
+        let mut lines = text.lines();
+        if lines.next().map(str::trim_end) != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(anyhow!("Not a BT-SEARCH announcement"));
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>()?),
+                "infohash" => {
+                    let info_hash = hex::decode(value)?;
+                    info_hashes.push(
+                        info_hash
+                            .try_into()
+                            .map_err(|_| anyhow!("Infohash {} is not 20 bytes", value))?,
+                    );
+                }
+                "cookie" => cookie = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            port: port.ok_or(anyhow!("BT-SEARCH without Port header"))?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Local Service Discovery on one multicast group.
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    /// Where announces are sent; a multicast group is also joined.
+    pub group: SocketAddr,
+    /// Where we listen for other clients' announces.
+    pub bind: SocketAddr,
+    pub interval: Duration,
+}
+
+impl LsdConfig {
+    pub fn ipv4() -> Self {
+        Self {
+            group: LSD_GROUP_V4,
+            bind: (Ipv4Addr::UNSPECIFIED, LSD_GROUP_V4.port()).into(),
+            interval: ANNOUNCE_INTERVAL,
+        }
+    }
+
+    pub fn ipv6() -> Self {
+        Self {
+            group: LSD_GROUP_V6,
+            bind: (Ipv6Addr::UNSPECIFIED, LSD_GROUP_V6.port()).into(),
+            interval: ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+/// Peers on the local network announcing the same torrent.
+pub struct LsdSource {
+    config: LsdConfig,
+    listen_port: u16,
+    info_hash: [u8; 20],
+}
+
+impl LsdSource {
+    pub fn new(config: LsdConfig, listen_port: u16, info_hash: [u8; 20]) -> Self {
+        Self {
+            config,
+            listen_port,
+            info_hash,
+        }
+    }
+}
+
+impl PeerSource for LsdSource {
+    fn name(&self) -> String {
+        format!("LSD {}", self.config.group)
+    }
+
+    fn peers(self: Box<Self>) -> PeerStream {
+        stream::once(async move {
+            match LocalDiscovery::bind(self.config, self.listen_port, self.info_hash).await {
+                Ok(discovery) => discovery.run(),
+                Err(e) => {
+                    log::warn!("Local service discovery unavailable: {}", e);
+                    stream::empty().boxed()
+                }
+            }
+        })
+        .flatten()
+        .boxed()
+    }
+}
+
+/// A socket that periodically announces our torrent to the group and
+/// reports other clients announcing it.
+pub struct LocalDiscovery {
+    socket: UdpSocket,
+    group: SocketAddr,
+    interval: Duration,
+    info_hash: [u8; 20],
+    announce: LsdAnnounce,
+    /// False when another process owns the group port, so we only announce.
+    listening: bool,
+}
+
+impl LocalDiscovery {
+    pub async fn bind(config: LsdConfig, listen_port: u16, info_hash: [u8; 20]) -> Result<Self> {
+        // Tokio cannot set SO_REUSEADDR on UDP sockets, so only one process
+        // (or dual-stack socket) can own the group port. The others would
+        // never see multicast on another port, so they only announce.
+        let (socket, listening) = match UdpSocket::bind(config.bind).await {
+            Ok(socket) => (socket, true),
+            Err(e) => {
+                log::warn!(
+                    "Cannot listen for LSD announces on {} ({}), probably another client \
+                     owns the port; announcing only",
+                    config.bind,
+                    e
+                );
+                let any = UdpSocket::bind(SocketAddr::new(config.bind.ip(), 0)).await?;
+                (any, false)
+            }
+        };
+        if listening {
+            match config.group.ip() {
+                IpAddr::V4(group) if group.is_multicast() => {
+                    socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?
+                }
+                IpAddr::V6(group) if group.is_multicast() => socket.join_multicast_v6(&group, 0)?,
+                _ => {}
+            }
+        }
+        Ok(Self {
+            socket,
+            group: config.group,
+            interval: config.interval,
+            info_hash,
+            announce: LsdAnnounce {
+                port: listen_port,
+                info_hashes: vec![info_hash],
+                cookie: Some(format!("{:016x}", random::next_u64())),
+            },
+            listening,
+        })
+    }
+
+    #[cfg(test)]
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    async fn announce(&self) -> Result<()> {
+        let message = self.announce.to_bytes(self.group);
+        self.socket.send_to(&message, self.group).await?;
+        Ok(())
+    }
+
+    /// The peer behind an announce from `from`, unless it is our own, for
+    /// another torrent, or malformed.
+    fn accept(&self, data: &[u8], from: SocketAddr) -> Option<Peer> {
+        let announce = match LsdAnnounce::parse(data) {
+            Ok(announce) => announce,
+            Err(e) => {
+                log::debug!("Ignoring LSD datagram from {}: {}", from, e);
+                return None;
+            }
+        };
+        if announce.cookie.is_some() && announce.cookie == self.announce.cookie {
+            return None;
+        }
+        if !announce.info_hashes.contains(&self.info_hash) || announce.port == 0 {
+            return None;
+        }
+        Some(Peer::new(SocketAddr::new(from.ip(), announce.port)))
+    }
+
+    /// Announces right away and then every interval, yielding local peers
+    /// as their announces arrive.
+    pub fn run(self) -> PeerStream {
+        let mut timer = time::interval_at(Instant::now(), self.interval);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        stream::unfold((self, timer), |(discovery, mut timer)| async move {
+            let mut buffer = [0u8; MAX_DATAGRAM];
+            loop {
+                tokio::select! {
+                    _ = timer.tick() => {
+                        if let Err(e) = discovery.announce().await {
+                            log::warn!("LSD announce to {} failed: {}", discovery.group, e);
+                        }
+                    }
+                    received = discovery.socket.recv_from(&mut buffer), if discovery.listening => {
+                        let (len, from) = match received {
+                            Ok(received) => received,
+                            Err(e) => {
+                                log::warn!("LSD receive failed: {}", e);
+                                continue;
+                            }
+                        };
+                        if let Some(peer) = discovery.accept(&buffer[..len], from) {
+                            return Some((peer, (discovery, timer)));
+                        }
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    #[test]
+    fn announce_round_trip() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![INFO_HASH, [1; 20]],
+            cookie: Some("c00k1e".to_owned()),
+        };
+        let bytes = announce.to_bytes(LSD_GROUP_V4);
+        let text = String::from_utf8(bytes.clone()).unwrap();
+        assert!(text.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert!(text.ends_with("\r\n\r\n\r\n"));
+        assert_eq!(LsdAnnounce::parse(&bytes).unwrap(), announce);
+        assert!(String::from_utf8(announce.to_bytes(LSD_GROUP_V6))
+            .unwrap()
+            .contains("Host: [ff15::efc0:988f]:6771\r\n"));
+
+        // Header names are case-insensitive and the cookie is optional.
+        let upper = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: x\r\nPORT: 7000\r\nINFOHASH: {}\r\n\r\n\r\n",
+            hex::encode_upper(INFO_HASH)
+        );
+        let parsed = LsdAnnounce::parse(upper.as_bytes()).unwrap();
+        assert_eq!(
+            (parsed.port, parsed.info_hashes, parsed.cookie),
+            (7000, vec![INFO_HASH], None)
+        );
+        assert!(LsdAnnounce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(LsdAnnounce::parse(b"BT-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    fn loopback_config(group: SocketAddr) -> LsdConfig {
+        LsdConfig {
+            group,
+            bind: "127.0.0.1:0".parse().unwrap(),
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn discovers_peers_and_ignores_own_announces() {
+        let unused: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let listener = LocalDiscovery::bind(loopback_config(unused), 7001, INFO_HASH)
+            .await
+            .unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        assert_eq!(
+            listener.accept(&listener.announce.to_bytes(unused), listener_addr),
+            None
+        );
+        let other_torrent = LsdAnnounce {
+            port: 7003,
+            info_hashes: vec![[1; 20]],
+            cookie: None,
+        };
+        assert_eq!(
+            listener.accept(&other_torrent.to_bytes(unused), listener_addr),
+            None
+        );
+
+        let announcer = LocalDiscovery::bind(loopback_config(listener_addr), 7002, INFO_HASH)
+            .await
+            .unwrap();
+        announcer.announce().await.unwrap();
+        let peer = time::timeout(Duration::from_secs(5), listener.run().next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.addr, "127.0.0.1:7002".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn announces_only_when_the_port_is_taken() {
+        let owner = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = loopback_config(LSD_GROUP_V4);
+        config.bind = owner.local_addr().unwrap();
+        let discovery = LocalDiscovery::bind(config, 7001, INFO_HASH).await.unwrap();
+        assert!(!discovery.listening);
+        assert_ne!(discovery.local_addr().unwrap(), owner.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn receives_announces_through_the_multicast_group() {
+        // A free port for a private group, so real LSD traffic stays out.
+        let port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddr::new(LSD_GROUP_V4.ip(), port);
+        let config = LsdConfig {
+            group,
+            bind: (Ipv4Addr::UNSPECIFIED, port).into(),
+            interval: Duration::from_secs(60),
+        };
+        let listener = match LocalDiscovery::bind(config.clone(), 7001, INFO_HASH).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Skipping, cannot join multicast group: {}", e);
+                return;
+            }
+        };
+        assert!(listener.listening);
+        let mut announcer_config = config;
+        announcer_config.bind = "0.0.0.0:0".parse().unwrap();
+        let announcer = LocalDiscovery::bind(announcer_config, 7002, INFO_HASH)
+            .await
+            .unwrap();
+        if let Err(e) = announcer.announce().await {
+            eprintln!("Skipping, no multicast route: {}", e);
+            return;
+        }
+        let Ok(peer) = time::timeout(Duration::from_secs(5), listener.run().next()).await else {
+            eprintln!("Skipping, multicast does not loop back here");
+            return;
+        };
+        assert_eq!(peer.unwrap().addr.port(), 7002);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Result};
+use percent_encoding::percent_decode_str;
+use std::net::SocketAddr;
+
+/// The parts of a `magnet:?` URI we act on.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MagnetLink {
+    /// `xt=urn:btih:`; absent for BEP 46 links until resolved.
+    pub info_hash: Option<[u8; 20]>,
+    /// `xs=urn:btpk:`: BEP 46 key whose DHT item names the info hash.
+    pub public_key: Option<[u8; 32]>,
+    /// `s`: BEP 46 salt, hex encoded in the link.
+    pub salt: Vec<u8>,
+    /// `dn`: suggested name while metadata is unknown.
+    pub display_name: Option<String>,
+    /// `tr`: tracker announce URLs.
+    pub trackers: Vec<String>,
+    /// `x.pe`: peers to try directly.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(anyhow!("Not a magnet link: {}", uri))?;
+        let mut magnet = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        magnet.info_hash = Some(decode_btih(hash)?);
+                    }
+                }
+                "xs" => {
+                    if let Some(key) = value.strip_prefix("urn:btpk:") {
+                        let key = hex::decode(key)?
+                            .try_into()
+                            .map_err(|_| anyhow!("btpk must be a 32 byte key"))?;
+                        magnet.public_key = Some(key);
+                    }
+                }
+                "s" => magnet.salt = hex::decode(&value)?,
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                // Host names would need a lookup; only literal addresses are kept.
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.public_key.is_none() {
+            return Err(anyhow!("Magnet link has no urn:btih: or urn:btpk: topic"));
+        }
+        Ok(magnet)
+    }
+
+    /// The BEP 46 link for a key and salt.
+    pub fn mutable(public_key: &[u8; 32], salt: &[u8]) -> String {
+        let mut uri = format!("magnet:?xs=urn:btpk:{}", hex::encode(public_key));
+        if !salt.is_empty() {
+            uri += &format!("&s={}", hex::encode(salt));
+        }
+        uri
+    }
+}
+
+/// Info hashes appear as 40 hex digits or, in older links, 32 base32 digits.
+fn decode_btih(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash)?,
+        32 => base32_decode(hash)?,
+        _ => return Err(anyhow!("Invalid btih length {}", hash.len())),
+    };
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("btih must decode to 20 bytes"))
+}
+
+fn base32_decode(input: &str) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u64, 0u32);
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(anyhow!("Invalid base32 character {:?}", c as char)),
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_magnet_with_trackers_and_peers() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt\
+             &tr=http%3A%2F%2Ftracker.example%2Fannounce&x.pe=10.0.0.1:6881&x.pe=%5B%3A%3A1%5D:51413",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet.info_hash.map(hex::encode).as_deref(),
+            Some("d69f91e6b2ae4c542468d1073a71d4ea13879a7f")
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("sample.txt"));
+        assert_eq!(magnet.trackers, vec!["http://tracker.example/announce"]);
+        assert_eq!(
+            magnet.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_base32_btih() {
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap();
+        assert_eq!(
+            magnet.info_hash.map(hex::encode).as_deref(),
+            Some("d69f91e6b2ae4c542468d1073a71d4ea13879a7f")
+        );
+        assert!(MagnetLink::parse("magnet:?dn=x").is_err());
+    }
+
+    #[test]
+    fn parse_bep46_mutable_link() {
+        let key = [0xab; 32];
+        let uri = MagnetLink::mutable(&key, b"build");
+        let magnet = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(magnet.info_hash, None);
+        assert_eq!(magnet.public_key, Some(key));
+        assert_eq!(magnet.salt, b"build");
+        assert!(MagnetLink::parse("magnet:?xs=urn:btpk:abcd").is_err());
+    }
+}
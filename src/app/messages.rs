@@ -1,23 +1,168 @@
 use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::app::bitfield::Bitfield;
 
 use anyhow::{anyhow, Result};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum BTMessage {
+    /// A bare zero length prefix.
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
     NotInterested,
     Have(u32),
-    Bitfield(String),
+    Bitfield(Bitfield),
     Request(u32, u32, u32),
-    Piece(u32, u32, Vec<u8>),
+    /// Block data, shared with the read buffer rather than copied.
+    Piece(u32, u32, Bytes),
     Cancel(u32, u32, u32),
+    /// BEP 5: the UDP port of the peer's DHT node.
+    Port(u16),
+    /// BEP 6: suggests a piece to download next.
+    SuggestPiece(u32),
+    /// BEP 6: replaces a full bitfield.
+    HaveAll,
+    /// BEP 6: replaces an empty bitfield.
+    HaveNone,
+    /// BEP 6: the request will not be served.
+    RejectRequest(u32, u32, u32),
+    /// BEP 6: the piece may be requested while choked.
+    AllowedFast(u32),
+    /// BEP 10 extended message: extension id and its payload.
+    Extended(u8, Vec<u8>),
 }
 
-pub struct BTMessageFramer;
+/// Features advertised in the 8 reserved handshake bytes, read as a
+/// big-endian integer so bit 0 is the last bit of the last byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// BEP 5: we run a DHT node and will send `Port`.
+    pub const DHT: Self = Self(1 << 0);
+    /// BEP 6 Fast Extension.
+    pub const FAST: Self = Self(1 << 2);
+    /// BEP 52: the connection may be upgraded to v2.
+    pub const V2_UPGRADE: Self = Self(1 << 4);
+    /// BEP 10 extension protocol (byte 5, 0x10).
+    pub const EXTENSION_PROTOCOL: Self = Self(1 << 20);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// What both sides support, i.e. what the session may use.
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Parses a comma separated list of names from [`CAPABILITY_NAMES`].
+    pub fn parse(list: &str) -> Result<Self> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Self::empty(), |capabilities, name| {
+                CAPABILITY_NAMES
+                    .iter()
+                    .find(|(known, _)| *known == name)
+                    .map(|(_, bit)| capabilities.with(*bit))
+                    .ok_or(anyhow!("Unknown capability {}", name))
+            })
+    }
+}
+
+/// Names used by `--capabilities` and in logs.
+pub const CAPABILITY_NAMES: [(&str, Capabilities); 4] = [
+    ("dht", Capabilities::DHT),
+    ("fast", Capabilities::FAST),
+    ("v2", Capabilities::V2_UPGRADE),
+    ("extensions", Capabilities::EXTENSION_PROTOCOL),
+];
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = CAPABILITY_NAMES
+            .iter()
+            .filter(|(_, bit)| self.contains(*bit))
+            .map(|(name, _)| *name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Largest message we buffer by default: a 16 KiB block with room to spare,
+/// and the bitfield of a torrent with up to a million pieces.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// Reasons to drop a peer that sent a malformed message.
+#[derive(Debug, Error, PartialEq)]
+pub enum MessageError {
+    #[error("Message of {len} bytes exceeds the limit of {max}")]
+    TooLarge { len: usize, max: usize },
+    #[error("Message type {id} has a {got} byte payload, expected {expected}")]
+    WrongLength {
+        id: u8,
+        got: usize,
+        expected: &'static str,
+    },
+    #[error("Unexpected message type: {0}")]
+    UnknownType(u8),
+}
+
+/// Peers drop connections that stay silent for about two minutes.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+pub struct BTMessageFramer {
+    last_sent: Instant,
+    /// Length prefixes above this are rejected before anything is buffered.
+    max_message_size: usize,
+}
+
+impl BTMessageFramer {
+    pub fn new() -> Self {
+        Self::with_max_message_size(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_max_message_size(max_message_size: usize) -> Self {
+        Self {
+            last_sent: Instant::now(),
+            max_message_size,
+        }
+    }
+
+    /// Time since we last encoded a message on this connection.
+    pub fn idle_for(&self) -> Duration {
+        self.last_sent.elapsed()
+    }
+}
+
+impl Default for BTMessageFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Encoder<BTMessage> for BTMessageFramer {
     type Error = anyhow::Error;
@@ -28,6 +173,7 @@ impl Encoder<BTMessage> for BTMessageFramer {
     ) -> std::result::Result<(), Self::Error> {
         let serialized = item.serialize()?;
         dst.extend_from_slice(&serialized);
+        self.last_sent = Instant::now();
         Ok(())
     }
 }
@@ -40,68 +186,98 @@ impl Decoder for BTMessageFramer {
             // Not enough data to determine message length
             return Ok(None);
         }
-        // Determine if we have a complete message based on length prefix
-        let length_prefix = u32::from_be_bytes(src[0..4].try_into().unwrap());
-
-        if (length_prefix as usize) + 4 <= src.len() {
-            if src.len() == 4 {
-                println!("Received keepalive message.");
-                src.advance(4 + length_prefix as usize);
-                return Ok(None);
+        let length_prefix = u32::from_be_bytes(src[0..4].try_into().unwrap()) as usize;
+        if length_prefix > self.max_message_size {
+            return Err(MessageError::TooLarge {
+                len: length_prefix,
+                max: self.max_message_size,
             }
-            let message_type = src[4];
-            let payload = src[5..(4 + length_prefix as usize)].to_vec();
-
-            src.advance(4 + length_prefix as usize);
-            Ok(Some(BTMessage::new(message_type, payload)?))
-        } else {
-            // Complete message not yet received
-            Ok(None)
+            .into());
+        }
+        if src.len() < 4 + length_prefix {
+            // Make room for the rest of the message in one allocation.
+            src.reserve(4 + length_prefix - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        if length_prefix == 0 {
+            return Ok(Some(BTMessage::KeepAlive));
         }
+        let mut frame = src.split_to(length_prefix).freeze();
+        let message_type = frame.get_u8();
+        Ok(Some(BTMessage::new(message_type, frame)?))
     }
 }
 impl BTMessage {
-    pub(crate) fn new(message_type: u8, payload: Vec<u8>) -> Result<Self> {
-        let m: Option<BTMessage> = match message_type {
-            0 => Some(BTMessage::Choke),
-            1 => Some(BTMessage::Unchoke),
-            2 => Some(BTMessage::Interested),
-            3 => Some(BTMessage::NotInterested),
-            4 => Some(BTMessage::Have(u32::from_be_bytes(
-                payload[0..4].try_into()?,
-            ))),
-            5 => Some(BTMessage::Bitfield(
-                payload
-                    .iter()
-                    .map(|byte| format!("{:08b}", byte))
-                    .collect::<String>(),
-            )),
-            6 => Some(BTMessage::Request(
-                u32::from_be_bytes(payload[0..4].try_into()?),
-                u32::from_be_bytes(payload[4..8].try_into()?),
-                u32::from_be_bytes(payload[8..12].try_into()?),
-            )),
-            7 => Some(BTMessage::Piece(
-                u32::from_be_bytes(payload[0..4].try_into()?),
-                u32::from_be_bytes(payload[4..8].try_into()?),
-                payload[8..].to_vec(),
-            )),
-            8 => Some(BTMessage::Cancel(
-                u32::from_be_bytes(payload[0..4].try_into()?),
-                u32::from_be_bytes(payload[4..8].try_into()?),
-                u32::from_be_bytes(payload[8..12].try_into()?),
-            )),
-            _ => None,
+    /// Parses a message body, checking the payload length for its type.
+    pub(crate) fn new(message_type: u8, mut payload: Bytes) -> Result<Self, MessageError> {
+        let len = payload.len();
+        let expect = |ok: bool, expected: &'static str| {
+            if ok {
+                Ok(())
+            } else {
+                Err(MessageError::WrongLength {
+                    id: message_type,
+                    got: len,
+                    expected,
+                })
+            }
         };
-        m.ok_or(anyhow!(format!(
-            "Unexpected message type: {}",
-            message_type
-        )))
+        let message = match message_type {
+            0..=3 | 14 | 15 => {
+                expect(len == 0, "0")?;
+                match message_type {
+                    0 => BTMessage::Choke,
+                    1 => BTMessage::Unchoke,
+                    2 => BTMessage::Interested,
+                    3 => BTMessage::NotInterested,
+                    14 => BTMessage::HaveAll,
+                    _ => BTMessage::HaveNone,
+                }
+            }
+            4 | 13 | 17 => {
+                expect(len == 4, "4")?;
+                let index = payload.get_u32();
+                match message_type {
+                    4 => BTMessage::Have(index),
+                    13 => BTMessage::SuggestPiece(index),
+                    _ => BTMessage::AllowedFast(index),
+                }
+            }
+            5 => BTMessage::Bitfield(Bitfield::from_bytes(payload.to_vec())),
+            6 | 8 | 16 => {
+                expect(len == 12, "12")?;
+                let (index, begin, length) =
+                    (payload.get_u32(), payload.get_u32(), payload.get_u32());
+                match message_type {
+                    6 => BTMessage::Request(index, begin, length),
+                    8 => BTMessage::Cancel(index, begin, length),
+                    _ => BTMessage::RejectRequest(index, begin, length),
+                }
+            }
+            7 => {
+                expect(len >= 8, "at least 8")?;
+                BTMessage::Piece(payload.get_u32(), payload.get_u32(), payload)
+            }
+            9 => {
+                expect(len == 2, "2")?;
+                BTMessage::Port(payload.get_u16())
+            }
+            20 => {
+                expect(len >= 1, "at least 1")?;
+                BTMessage::Extended(payload.get_u8(), payload.to_vec())
+            }
+            _ => return Err(MessageError::UnknownType(message_type)),
+        };
+        Ok(message)
     }
     pub fn serialize(&self) -> Result<Vec<u8>> {
         let mut buf = BytesMut::new();
 
         match self {
+            BTMessage::KeepAlive => {
+                buf.put_u32(0);
+            }
             BTMessage::Choke => {
                 buf.put_u32(1); // Message length
                 buf.put_u8(0); // Message ID
@@ -124,10 +300,10 @@ impl BTMessage {
                 buf.put_u32(*piece_index); // Piece index
             }
             BTMessage::Bitfield(bitfield) => {
-                let bitfield_bytes = hex::decode(bitfield)?;
+                let bitfield_bytes = bitfield.as_bytes();
                 buf.put_u32(1 + bitfield_bytes.len() as u32); // Message length
                 buf.put_u8(5); // Message ID
-                buf.extend_from_slice(&bitfield_bytes); // Bitfield
+                buf.extend_from_slice(bitfield_bytes); // Bitfield
             }
             BTMessage::Request(index, begin, length) => {
                 buf.put_u32(13); // Message length: 1 byte ID + 3 * 4 bytes
@@ -150,12 +326,65 @@ impl BTMessage {
                 buf.put_u32(*begin); // Block begin
                 buf.put_u32(*length); // Block length
             }
+            BTMessage::Port(port) => {
+                buf.put_u32(3);
+                buf.put_u8(9);
+                buf.put_u16(*port);
+            }
+            BTMessage::SuggestPiece(piece_index) => {
+                buf.put_u32(5);
+                buf.put_u8(13);
+                buf.put_u32(*piece_index);
+            }
+            BTMessage::HaveAll => {
+                buf.put_u32(1);
+                buf.put_u8(14);
+            }
+            BTMessage::HaveNone => {
+                buf.put_u32(1);
+                buf.put_u8(15);
+            }
+            BTMessage::RejectRequest(index, begin, length) => {
+                buf.put_u32(13);
+                buf.put_u8(16);
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_u32(*length);
+            }
+            BTMessage::AllowedFast(piece_index) => {
+                buf.put_u32(5);
+                buf.put_u8(17);
+                buf.put_u32(*piece_index);
+            }
+            BTMessage::Extended(id, payload) => {
+                buf.put_u32(2 + payload.len() as u32); // Message length
+                buf.put_u8(20); // Message ID
+                buf.put_u8(*id); // Extended message ID
+                buf.extend_from_slice(payload);
+            }
         }
 
         Ok(buf.to_vec())
     }
 }
-#[derive(Debug, PartialEq, Default)]
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+/// Length prefix, protocol string, reserved bytes, info hash and peer id.
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Reasons to drop a peer during the handshake.
+#[derive(Debug, Error, PartialEq)]
+pub enum HandshakeError {
+    #[error("Handshake truncated after {0} of {HANDSHAKE_LEN} bytes")]
+    Truncated(usize),
+    #[error("Peer does not speak the BitTorrent protocol")]
+    WrongProtocol,
+    #[error("Peer serves info hash {} instead of ours", hex::encode(.0))]
+    InfoHashMismatch([u8; 20]),
+    #[error("Connected to ourselves")]
+    SelfConnection,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Handshake {
     length: u8,
     protocol: [u8; 19],
@@ -164,13 +393,7 @@ pub struct Handshake {
     peer_id: [u8; 20],
 }
 impl Handshake {
-    pub fn new(peer_id: &[u8], info_hash: &[u8]) -> Self {
-        // Ensure the protocol string is exactly 19 bytes.
-        let protocol_str = "BitTorrent protocol";
-        let protocol_bytes = protocol_str.as_bytes();
-        let mut protocol_array = [0u8; 19]; // Initialize with zeros.
-        protocol_array.copy_from_slice(protocol_bytes);
-
+    pub fn new(peer_id: &[u8], info_hash: &[u8], capabilities: Capabilities) -> Self {
         let mut info_hash_array = [0u8; 20]; // Initialize with zeros.
         info_hash_array.copy_from_slice(info_hash);
 
@@ -179,8 +402,8 @@ impl Handshake {
 
         Self {
             length: 19,
-            protocol: protocol_array,
-            reserved: 0,
+            protocol: *PROTOCOL,
+            reserved: capabilities.bits(),
             info_hash: info_hash_array,
             peer_id: peer_id_array,
         }
@@ -198,23 +421,42 @@ impl Handshake {
         serialized
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Self {
-        let mut protocol: [u8; 19] = [0u8; 19];
-        protocol.copy_from_slice(&bytes[1..20]);
+    /// Parses a peer's handshake, rejecting anything that is not the
+    /// BitTorrent protocol. The reserved bits are kept as sent.
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, HandshakeError> {
+        if bytes.len() < HANDSHAKE_LEN {
+            return Err(HandshakeError::Truncated(bytes.len()));
+        }
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(HandshakeError::WrongProtocol);
+        }
+        Ok(Self {
+            length: bytes[0],
+            protocol: *PROTOCOL,
+            reserved: u64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        })
+    }
 
-        let mut info_hash: [u8; 20] = [0u8; 20];
-        info_hash.copy_from_slice(&bytes[28..48]);
+    /// Checks that the peer is on our torrent and is not us.
+    pub fn validate(&self, info_hash: &[u8], peer_id: &[u8]) -> Result<(), HandshakeError> {
+        if self.info_hash != info_hash {
+            return Err(HandshakeError::InfoHashMismatch(self.info_hash));
+        }
+        if self.peer_id == peer_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(())
+    }
 
-        let mut peer_id: [u8; 20] = [0u8; 20];
-        peer_id.copy_from_slice(&bytes[48..68]);
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
 
-        Self {
-            length: bytes[0],
-            protocol,
-            reserved: 0,
-            info_hash,
-            peer_id,
-        }
+    /// Everything the peer advertised, including bits we do not know.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_bits(self.reserved)
     }
 
     pub fn peer_id(&self) -> String {
@@ -227,6 +469,50 @@ impl Handshake {
     }
 }
 
+/// Frames the 68 byte handshake that opens every connection. Reserved bits
+/// are passed through untouched, so peers advertising BEP 10, the v2
+/// upgrade or bits we do not know are all accepted. Whatever follows the
+/// handshake, such as an extended handshake sent right behind it, stays in
+/// the buffer for the next codec.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Handshake, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&item.serialize());
+        Ok(())
+    }
+}
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        // Fail on the first byte rather than waiting for 68 from a peer
+        // speaking something else.
+        if src.first().is_some_and(|&len| len as usize != PROTOCOL.len()) {
+            return Err(HandshakeError::WrongProtocol.into());
+        }
+        if src.len() < HANDSHAKE_LEN {
+            src.reserve(HANDSHAKE_LEN - src.len());
+            return Ok(None);
+        }
+        let handshake = Handshake::deserialize(&src[..HANDSHAKE_LEN])?;
+        src.advance(HANDSHAKE_LEN);
+        Ok(Some(handshake))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.decode(src)? {
+            Some(handshake) => Ok(Some(handshake)),
+            None => Err(HandshakeError::Truncated(src.len()).into()),
+        }
+    }
+}
+
 impl fmt::Display for Handshake {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let protocol_str = match std::str::from_utf8(&self.protocol) {
@@ -253,3 +539,197 @@ impl fmt::Display for Handshake {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0x11; 20];
+
+    fn decode_all(bytes: &[u8]) -> Vec<BTMessage> {
+        let mut src = BytesMut::from(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = BTMessageFramer::new().decode(&mut src).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn keep_alives_and_ports_between_messages() {
+        let mut bytes = Vec::new();
+        for message in [
+            BTMessage::KeepAlive,
+            BTMessage::Have(7),
+            BTMessage::KeepAlive,
+            BTMessage::KeepAlive,
+            BTMessage::Port(6881),
+            BTMessage::Unchoke,
+        ] {
+            bytes.extend(message.serialize().unwrap());
+        }
+        assert_eq!(&bytes[..4], &[0, 0, 0, 0]);
+        assert_eq!(
+            decode_all(&bytes),
+            vec![
+                BTMessage::KeepAlive,
+                BTMessage::Have(7),
+                BTMessage::KeepAlive,
+                BTMessage::KeepAlive,
+                BTMessage::Port(6881),
+                BTMessage::Unchoke,
+            ]
+        );
+    }
+
+    #[test]
+    fn decoding_is_bounded_and_checks_payload_lengths() {
+        let mut framer = BTMessageFramer::with_max_message_size(64);
+        let mut src = BytesMut::from(&[0, 0, 0, 65, 7][..]);
+        let error = framer.decode(&mut src).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&MessageError::TooLarge { len: 65, max: 64 })
+        );
+
+        // A partial piece reserves room for the rest and decodes once it
+        // arrives, leaving the following message in the buffer.
+        let piece = BTMessage::Piece(3, 16, Bytes::from_static(b"block data"))
+            .serialize()
+            .unwrap();
+        let mut src = BytesMut::from(&piece[..6]);
+        assert_eq!(framer.decode(&mut src).unwrap(), None);
+        assert!(src.capacity() >= piece.len());
+        src.extend_from_slice(&piece[6..]);
+        src.extend_from_slice(&BTMessage::Choke.serialize().unwrap());
+        assert_eq!(
+            framer.decode(&mut src).unwrap(),
+            Some(BTMessage::Piece(3, 16, Bytes::from_static(b"block data")))
+        );
+        assert_eq!(framer.decode(&mut src).unwrap(), Some(BTMessage::Choke));
+
+        for (id, payload) in [
+            (4, &[0, 0, 1][..]),
+            (6, &[0; 13][..]),
+            (7, &[0; 7][..]),
+            (9, &[0][..]),
+            (14, &[0][..]),
+            (20, &[][..]),
+        ] {
+            assert!(matches!(
+                BTMessage::new(id, Bytes::copy_from_slice(payload)),
+                Err(MessageError::WrongLength { .. })
+            ));
+        }
+        assert_eq!(
+            BTMessage::new(42, Bytes::new()),
+            Err(MessageError::UnknownType(42))
+        );
+    }
+
+    #[test]
+    fn bitfield_wire_encoding() {
+        let mut bits = Bitfield::new(10);
+        bits.set(0, true);
+        bits.set(9, true);
+        let encoded = BTMessage::Bitfield(bits.clone()).serialize().unwrap();
+        assert_eq!(encoded, [0, 0, 0, 3, 5, 0x80, 0x40]);
+        let decoded = decode_all(&encoded);
+        let [BTMessage::Bitfield(received)] = &decoded[..] else {
+            panic!("Expected a bitfield, got {:?}", decoded);
+        };
+        assert_eq!(received.clone().validate(10).unwrap(), bits);
+    }
+
+    #[test]
+    fn handshake_round_trip_keeps_reserved_bits() {
+        let ours = Capabilities::EXTENSION_PROTOCOL.with(Capabilities::FAST);
+        let mut handshake = Handshake::new(b"-XX0001-aaaaaaaaaaaa", &INFO_HASH, ours);
+        handshake.reserved |= 1 << 40;
+        let bytes = handshake.serialize();
+        assert_eq!(bytes.len(), HANDSHAKE_LEN);
+        assert_eq!(&bytes[20..28], &[0, 0, 1, 0, 0, 0x10, 0, 0x04]);
+        let parsed = Handshake::deserialize(&bytes).unwrap();
+        assert_eq!(parsed, handshake);
+        assert_eq!(parsed.reserved, ours.bits() | 1 << 40);
+    }
+
+    #[test]
+    fn capabilities_negotiate_the_common_subset() {
+        let ours = Capabilities::EXTENSION_PROTOCOL.with(Capabilities::DHT);
+        let theirs = Capabilities::from_bits(1 << 40)
+            .with(Capabilities::DHT)
+            .with(Capabilities::FAST);
+        let common = ours.intersection(theirs);
+        assert_eq!(common, Capabilities::DHT);
+        assert!(common.contains(Capabilities::DHT));
+        assert!(!common.contains(Capabilities::EXTENSION_PROTOCOL));
+        assert!(Capabilities::empty().contains(Capabilities::empty()));
+
+        let parsed = Capabilities::parse("extensions, dht,v2").unwrap();
+        assert_eq!(parsed, ours.with(Capabilities::V2_UPGRADE));
+        assert_eq!(parsed.to_string(), "dht,v2,extensions");
+        assert_eq!(Capabilities::parse("").unwrap(), Capabilities::empty());
+        assert!(Capabilities::parse("ext").is_err());
+    }
+
+    #[test]
+    fn handshake_codec_leaves_following_messages_buffered() {
+        let v2 = Capabilities::V2_UPGRADE.with(Capabilities::EXTENSION_PROTOCOL);
+        let handshake = Handshake::new(b"-XX0001-bbbbbbbbbbbb", &INFO_HASH, v2);
+        let mut src = BytesMut::from(&handshake.serialize()[..30]);
+        assert_eq!(HandshakeCodec.decode(&mut src).unwrap(), None);
+        src.clear();
+        src.extend_from_slice(&handshake.serialize());
+        src.extend_from_slice(&BTMessage::HaveAll.serialize().unwrap());
+        let decoded = HandshakeCodec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.capabilities(), v2);
+        assert_eq!(decode_all(&src), vec![BTMessage::HaveAll]);
+
+        let mut truncated = BytesMut::from(&handshake.serialize()[..50]);
+        let error = HandshakeCodec.decode_eof(&mut truncated).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&HandshakeError::Truncated(50)));
+        let error = HandshakeCodec
+            .decode(&mut BytesMut::from(&b"GET / HTTP/1.1"[..]))
+            .unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&HandshakeError::WrongProtocol));
+    }
+
+    #[test]
+    fn handshake_validation_errors() {
+        let ours = b"-XX0001-aaaaaaaaaaaa";
+        let bytes = Handshake::new(
+            b"-XX0001-bbbbbbbbbbbb",
+            &INFO_HASH,
+            Capabilities::EXTENSION_PROTOCOL,
+        )
+        .serialize();
+        assert_eq!(
+            Handshake::deserialize(&bytes[..40]),
+            Err(HandshakeError::Truncated(40))
+        );
+        let mut wrong = bytes.clone();
+        wrong[1] = b'b';
+        assert_eq!(
+            Handshake::deserialize(&wrong),
+            Err(HandshakeError::WrongProtocol)
+        );
+        wrong = bytes.clone();
+        wrong[0] = 18;
+        assert_eq!(
+            Handshake::deserialize(&wrong),
+            Err(HandshakeError::WrongProtocol)
+        );
+
+        let peer = Handshake::deserialize(&bytes).unwrap();
+        assert_eq!(peer.validate(&INFO_HASH, ours), Ok(()));
+        assert_eq!(
+            peer.validate(&[0x22; 20], ours),
+            Err(HandshakeError::InfoHashMismatch(INFO_HASH))
+        );
+        assert_eq!(
+            peer.validate(&INFO_HASH, b"-XX0001-bbbbbbbbbbbb"),
+            Err(HandshakeError::SelfConnection)
+        );
+    }
+}
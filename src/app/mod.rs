@@ -1,23 +1,65 @@
 mod bencode;
+mod bitfield;
+mod config;
+mod connection;
+mod dht;
+mod dht_items;
+mod ed25519;
+mod extension;
+mod fast;
+mod krpc;
+mod lsd;
+mod magnet;
 mod messages;
+mod mse;
 mod network;
 mod peer;
+mod peer_source;
+mod pex;
+mod picker;
+mod random;
+mod request_queue;
+mod sha512;
+mod swarm;
 mod tracker;
-use anyhow::Result;
+mod tracker_client;
+mod tracker_server;
+mod udp_tracker;
+mod udp_tracker_server;
+mod utp;
+use anyhow::{anyhow, Result};
 use futures::stream::StreamExt;
 
 use std::fs;
 
-use crate::app::messages::{BTMessage, BTMessageFramer, Handshake};
+use crate::app::config::ClientConfig;
+use crate::app::dht::{DhtConfig, DhtNode};
+use crate::app::ed25519::SigningKey;
+use crate::app::fast::BlockRequest;
+use crate::app::lsd::{LsdConfig, LsdSource};
+use crate::app::magnet::MagnetLink;
+use crate::app::messages::{BTMessage, BTMessageFramer};
 use crate::app::network::*;
 use crate::app::peer::PeerManager;
+use crate::app::peer_source::{DhtSource, MergedPeers, StaticPeers, TrackerSource};
+use crate::app::request_queue::RequestQueue;
+use crate::app::swarm::{Swarm, BLOCK_SIZE, REQUEST_CHECK_INTERVAL};
 use crate::app::tracker::MetaData;
+use crate::app::tracker_client::{TrackerClient, TransferStats};
+use crate::app::tracker_server::{expire_periodically, serve_http, SwarmRegistry, TrackerConfig};
+use crate::app::udp_tracker_server::serve_udp;
 use futures::SinkExt;
+use sha1::{Digest, Sha1};
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time;
 use tokio_util::codec::Framed;
 
-static mut DOWNLOADED: u64 = 0;
 fn read_binary_file(path: &str) -> Result<Vec<u8>> {
     let data = fs::read(path)?;
     Ok(data)
@@ -28,159 +70,483 @@ fn decode_bencoded_value(value: &str) -> Result<String> {
     let decoded = bencode::decode(buffer)?;
     bencode::to_string(&decoded)
 }
-pub async fn download_piece(
+/// The block requests that make up piece `index`.
+fn piece_requests(index: usize, torrent_info: &MetaData) -> VecDeque<BlockRequest> {
+    let size = torrent_info.info.piece_size(index) as u32;
+    (0..size.div_ceil(BLOCK_SIZE))
+        .map(|block| {
+            let begin = block * BLOCK_SIZE;
+            (index as u32, begin, BLOCK_SIZE.min(size - begin))
+        })
+        .collect()
+}
+
+/// Sends the next of `blocks` while the peer's request queue has room.
+pub async fn download_piece<T: AsyncRead + AsyncWrite + Unpin>(
+    blocks: &mut VecDeque<BlockRequest>,
+    requests: &mut RequestQueue,
+    peer: &mut Framed<T, BTMessageFramer>,
+) -> Result<()> {
+    while requests.wants_more() {
+        let Some(request) = blocks.pop_front() else {
+            break;
+        };
+        requests.push(request, Instant::now());
+        let (index, begin, length) = request;
+        peer.send(BTMessage::Request(index, begin, length)).await?;
+    }
+    Ok(())
+}
+
+/// Downloads piece `index` from a single peer into `file_name` and checks
+/// its hash. Requests dropped by a choke or timed out are asked for again.
+async fn fetch_piece<T: AsyncRead + AsyncWrite + Unpin>(
+    peer: &mut Framed<T, BTMessageFramer>,
     index: usize,
     torrent_info: &MetaData,
-    peer: &mut Framed<TcpStream, BTMessageFramer>,
-    _file_name: &str,
+    file_name: &str,
 ) -> Result<()> {
-    let block_size = 16 * 1024; // 16 KiB
-    let mut total_pieces = torrent_info.info.length / torrent_info.info.piece_length;
-    if torrent_info.info.length % torrent_info.info.piece_length > 0 {
-        total_pieces += 1;
-    }
-    let mut last_piece_size = torrent_info.info.length % torrent_info.info.piece_length;
-    if last_piece_size == 0 {
-        // If the total size is a perfect multiple of the piece size
-        last_piece_size = torrent_info.info.piece_length; // The last piece is a full piece
-    }
-    let mut number_of_blocks_in_last_piece = last_piece_size / block_size;
-    if last_piece_size % block_size != 0 {
-        // If there's a remainder
-        number_of_blocks_in_last_piece += 1; // There's an additional, partially-filled block
-    }
-    let mut size_of_last_block_in_last_piece = last_piece_size % block_size;
-    if size_of_last_block_in_last_piece == 0 && last_piece_size != 0 {
-        size_of_last_block_in_last_piece = block_size; // The last block is a full block if no remainder
+    let piece_offset = index as u64 * torrent_info.info.piece_length as u64;
+    let mut blocks = piece_requests(index, torrent_info);
+    let mut requests = RequestQueue::new();
+    let mut choked = true;
+    let mut ticks = time::interval(REQUEST_CHECK_INTERVAL);
+    while !blocks.is_empty() || requests.len() > 0 {
+        let msg = tokio::select! {
+            msg = peer.next() => msg.ok_or(anyhow!("Peer closed the connection"))??,
+            _ = ticks.tick() => {
+                for request in requests.expire(Instant::now()) {
+                    blocks.push_front(request);
+                }
+                if !choked {
+                    download_piece(&mut blocks, &mut requests, peer).await?;
+                }
+                continue;
+            }
+        };
+        match msg {
+            BTMessage::Choke => {
+                choked = true;
+                for request in requests.drain() {
+                    blocks.push_front(request);
+                }
+            }
+            BTMessage::Unchoke => {
+                choked = false;
+                download_piece(&mut blocks, &mut requests, peer).await?;
+            }
+            BTMessage::Bitfield(_) | BTMessage::HaveAll => {
+                peer.send(BTMessage::Interested).await?;
+            }
+            BTMessage::Piece(idx, begin, data) => {
+                let request = (idx, begin, data.len() as u32);
+                // A block already in flight when we were choked may still
+                // arrive after being put back.
+                let wanted = requests.complete(&request, Instant::now()) || {
+                    let queued = blocks.iter().position(|block| *block == request);
+                    queued.and_then(|i| blocks.remove(i)).is_some()
+                };
+                if wanted {
+                    peer::write_at_offset(file_name, piece_offset + begin as u64, &data).await?;
+                }
+                if !choked {
+                    download_piece(&mut blocks, &mut requests, peer).await?;
+                }
+            }
+            BTMessage::RejectRequest(idx, begin, length)
+                if requests.remove(&(idx, begin, length)) =>
+            {
+                blocks.push_back((idx, begin, length));
+            }
+            _ => {}
+        }
     }
-    let i = index;
-    let piece_length = torrent_info.info.piece_length as u32;
-    const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB in bytes
+    verify_piece(index, torrent_info, file_name).await
+}
 
-    let mut total_blocks = (piece_length as f32 / BLOCK_SIZE as f32).ceil() as u32;
-    if i == total_pieces as usize - 1usize {
-        total_blocks = number_of_blocks_in_last_piece as u32;
+/// Reads a fully received piece back from disk and checks it against the
+/// hash from the metainfo.
+async fn verify_piece(index: usize, torrent_info: &MetaData, file_name: &str) -> Result<()> {
+    let offset = index as u64 * torrent_info.info.piece_length as u64;
+    let size = torrent_info.info.piece_size(index) as usize;
+    let data = peer::read_at_offset(file_name, offset, size).await?;
+    let expected = torrent_info
+        .info
+        .piece_hash(index)
+        .ok_or(anyhow!("No hash for piece {}", index))?;
+    if Sha1::digest(&data).as_slice() != expected {
+        return Err(anyhow!("Piece {} failed hash verification", index));
     }
+    Ok(())
+}
 
-    for block_index in 0..total_blocks {
-        let begin = block_index * BLOCK_SIZE;
-        let length = if block_index == total_blocks - 1 && i == total_pieces as usize - 1 {
-            // Last block, calculate remaining bytes
-            size_of_last_block_in_last_piece
-        } else {
-            // All blocks except the last one are of BLOCK_SIZE
-            BLOCK_SIZE as i64
-        };
+/// A peer manager fed by `--peer` addresses and one announce to the
+/// torrent's tracker.
+fn peer_manager_with_tracker(torrent_info: &MetaData, config: ClientConfig) -> Result<PeerManager> {
+    let stats = Arc::new(TransferStats::new(torrent_info.info.length as u64));
+    let tracker = TrackerClient::new(torrent_info, &config, stats)?;
+    let mut peer_manager = PeerManager::new(torrent_info.clone(), config);
+    peer_manager.add_source(Box::new(TrackerSource::once(tracker)));
+    Ok(peer_manager)
+}
 
-        let r = BTMessage::Request(i as u32, begin, length as u32);
-        peer.send(r).await?;
-        unsafe {
-            DOWNLOADED += 1;
+/// Lists peers for a magnet link from its `x.pe` hints and `tr` trackers.
+async fn magnet_peers(uri: &str, config: &ClientConfig) -> Result<()> {
+    let magnet = MagnetLink::parse(uri)?;
+    // BEP 46 links need the DHT to learn the info hash, and without trackers
+    // it is the only way to find the swarm.
+    let dht = if magnet.info_hash.is_none() || magnet.trackers.is_empty() {
+        let node = DhtNode::bind(dht_config(config)).await?;
+        if let Err(e) = node.bootstrap().await {
+            log::warn!("{}", e);
         }
-    }
-    Ok(())
-}
+        Some(node)
+    } else {
+        None
+    };
+    let info_hash = match (magnet.info_hash, magnet.public_key, &dht) {
+        (Some(info_hash), _, _) => info_hash,
+        (None, Some(public_key), Some(node)) => {
+            let info_hash = node
+                .resolve_mutable_torrent(&public_key, &magnet.salt)
+                .await?;
+            log::info!("{} resolves to {}", uri, hex::encode(info_hash));
+            info_hash
+        }
+        _ => return Err(anyhow!("Magnet link has no info hash")),
+    };
 
-pub async fn download_pieces(
-    _index: usize,
-    torrent_info: &MetaData,
-    peer: &mut Framed<TcpStream, BTMessageFramer>,
-    _file_name: &str,
-) -> Result<()> {
-    let block_size = 16 * 1024; // 16 KiB
-    let mut total_pieces = torrent_info.info.length / torrent_info.info.piece_length;
-    if torrent_info.info.length % torrent_info.info.piece_length > 0 {
-        total_pieces += 1;
+    let mut peers = MergedPeers::new();
+    peers.add(Box::new(StaticPeers::from_magnet(&magnet)));
+    for tracker in &magnet.trackers {
+        // The real size is unknown until we have the metadata.
+        let request = AnnounceRequest::from_info_hash(info_hash, 16 * 1024, config);
+        let stats = Arc::new(TransferStats::new(request.left));
+        let client = TrackerClient::from_request(tracker, request, stats);
+        peers.add(Box::new(TrackerSource::once(client)));
     }
-    let mut last_piece_size = torrent_info.info.length % torrent_info.info.piece_length;
-    if last_piece_size == 0 {
-        // If the total size is a perfect multiple of the piece size
-        last_piece_size = torrent_info.info.piece_length; // The last piece is a full piece
+    if let Some(node) = &dht {
+        peers.add(Box::new(DhtSource::new(node.clone(), info_hash)));
     }
-    let mut number_of_blocks_in_last_piece = last_piece_size / block_size;
-    if last_piece_size % block_size != 0 {
-        // If there's a remainder
-        number_of_blocks_in_last_piece += 1; // There's an additional, partially-filled block
+    while let Some(peer) = peers.next().await {
+        println!("{}", peer.addr);
     }
-    let mut size_of_last_block_in_last_piece = last_piece_size % block_size;
-    if size_of_last_block_in_last_piece == 0 && last_piece_size != 0 {
-        size_of_last_block_in_last_piece = block_size; // The last block is a full block if no remainder
+    if let Some(node) = dht {
+        node.save()?;
+    }
+    Ok(())
+}
+
+/// DHT settings shared by the `dht` command and trackerless magnets: the node
+/// listens on our peer port and keeps its routing table in `dht.dat`.
+fn dht_config(config: &ClientConfig) -> DhtConfig {
+    DhtConfig {
+        bind: SocketAddr::from(([0, 0, 0, 0], config.port)),
+        state_file: Some(DHT_STATE_FILE.into()),
+        ..DhtConfig::default()
     }
-    for i in 0..total_pieces {
-        let piece_length = torrent_info.info.piece_length as u32;
-        const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB in bytes
+}
 
-        let mut total_blocks = (piece_length as f32 / BLOCK_SIZE as f32).ceil() as u32;
-        if i as usize == total_pieces as usize - 1usize {
-            total_blocks = number_of_blocks_in_last_piece as u32;
+const DHT_STATE_FILE: &str = "dht.dat";
+/// A download receiving nothing for this long reannounces to its tracker.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn no_args(config: ClientConfig) -> Result<()> {
+    let _content = read_binary_file("sample.torrent")?;
+    let torrent_info = MetaData::new(bencode::decode(&_content)?)?;
+    let file_name = torrent_info.info.name.clone();
+    download(torrent_info, &file_name, &config).await
+}
+
+/// Downloads the whole torrent into `file_name` from every peer source,
+/// telling the tracker when it completes and when we leave.
+async fn download(torrent_info: MetaData, file_name: &str, config: &ClientConfig) -> Result<()> {
+    let stats = Arc::new(TransferStats::new(torrent_info.info.length as u64));
+    let (source, tracker) =
+        TrackerSource::spawn(TrackerClient::new(&torrent_info, config, stats.clone())?);
+    let mut peer_manager = PeerManager::new(torrent_info.clone(), config.clone());
+    peer_manager.add_source(Box::new(source));
+    if config.lsd {
+        let info_hash: [u8; 20] = torrent_info
+            .raw()
+            .info_hash_u8()?
+            .try_into()
+            .map_err(|_| anyhow!("Info hash must be 20 bytes."))?;
+        for lsd in [LsdConfig::ipv4(), LsdConfig::ipv6()] {
+            peer_manager.add_source(Box::new(LsdSource::new(lsd, config.port, info_hash)));
         }
+    }
 
-        for block_index in 0..total_blocks {
-            let begin = block_index * BLOCK_SIZE;
-            let length =
-                if block_index == total_blocks - 1 && i as usize == total_pieces as usize - 1 {
-                    // Last block, calculate remaining bytes
-                    size_of_last_block_in_last_piece
-                } else {
-                    // All blocks except the last one are of BLOCK_SIZE
-                    BLOCK_SIZE as i64
-                };
+    let swarm = Swarm::new(torrent_info, file_name, config.clone(), stats.clone());
+    let download = swarm.download(&mut peer_manager);
+    tokio::pin!(download);
+    // A download that stalls asks the tracker for more peers early.
+    let mut checks = time::interval_at(time::Instant::now() + STALL_TIMEOUT, STALL_TIMEOUT);
+    let mut downloaded = stats.downloaded();
+    let result = loop {
+        tokio::select! {
+            result = &mut download => break result,
+            _ = checks.tick() => {
+                if stats.downloaded() == downloaded {
+                    tracker.reannounce();
+                }
+                downloaded = stats.downloaded();
+            }
+        }
+    };
+    if result.is_ok() {
+        tracker.completed();
+    }
+    tracker.stop().await;
+    result
+}
 
-            let r = BTMessage::Request(i as u32, begin, length as u32);
-            peer.send(r).await?;
-            unsafe {
-                DOWNLOADED += 1;
+/// `scrape <torrent>...` scrapes every torrent from its own tracker, while
+/// `scrape <announce-url> <info-hash>...` queries a tracker directly. Hashes
+/// sharing a tracker are requested together.
+async fn scrape_command(args: &[String]) -> Result<()> {
+    let mut by_tracker: Vec<(String, Vec<[u8; 20]>)> = Vec::new();
+    if args.first().is_some_and(|arg| arg.contains("://")) {
+        let hashes = args[1..]
+            .iter()
+            .map(|hash| parse_info_hash(hash))
+            .collect::<Result<Vec<[u8; 20]>>>()?;
+        by_tracker.push((args[0].clone(), hashes));
+    } else {
+        for path in args {
+            let torrent_info = MetaData::new(bencode::decode(&read_binary_file(path)?)?)?;
+            let info_hash: [u8; 20] = torrent_info
+                .raw()
+                .info_hash_u8()?
+                .try_into()
+                .map_err(|_| anyhow!("Info hash must be 20 bytes."))?;
+            match by_tracker
+                .iter_mut()
+                .find(|(announce, _)| *announce == torrent_info.announce)
+            {
+                Some((_, hashes)) => hashes.push(info_hash),
+                None => by_tracker.push((torrent_info.announce.clone(), vec![info_hash])),
             }
         }
     }
+    for (announce, hashes) in by_tracker {
+        let stats = scrape(&announce, &hashes).await?;
+        for (info_hash, stats) in hashes.iter().zip(stats) {
+            println!(
+                "{} seeders: {} completed: {} leechers: {}",
+                hex::encode(info_hash),
+                stats.complete,
+                stats.downloaded,
+                stats.incomplete
+            );
+        }
+    }
     Ok(())
 }
 
-async fn no_args() -> Result<()> {
-    let path = "sample.torrent";
-    let _content = read_binary_file(path)?;
-    let torrent_info = MetaData::new(bencode::decode(&_content)?)?;
+fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    hex::decode(hash)?
+        .try_into()
+        .map_err(|_| anyhow!("Info hash {} must be 20 bytes.", hash))
+}
 
-    let mut peer_manager = PeerManager::new(torrent_info.clone()).await?;
-    let stream = peer_manager.connect_to_peer().await?;
+/// `tracker serve [--http <addr>] [--udp <addr>] [--interval <secs>] [--allow <info-hash>]...`
+///
+/// Without `--http` or `--udp` an HTTP tracker is started on port 6969.
+async fn tracker_command(args: &[String]) -> Result<()> {
+    if args.first().map(String::as_str) != Some("serve") {
+        return Err(anyhow!(
+            "Usage: tracker serve [--http <addr>] [--udp <addr>] [--interval <secs>] [--allow <info-hash>]..."
+        ));
+    }
+    let mut config = TrackerConfig::default();
+    let mut http: Option<SocketAddr> = None;
+    let mut udp: Option<SocketAddr> = None;
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(anyhow!("Missing value for option {}", arg))
+        };
+        match arg.as_str() {
+            "--http" => http = Some(value()?.parse()?),
+            "--udp" => udp = Some(value()?.parse()?),
+            "--interval" => {
+                let interval = Duration::from_secs(value()?.parse()?);
+                config.interval = interval;
+                config.peer_timeout = interval * 2 + Duration::from_secs(60);
+            }
+            "--allow" => {
+                let info_hash = parse_info_hash(value()?)?;
+                config
+                    .allowlist
+                    .get_or_insert_with(HashSet::new)
+                    .insert(info_hash);
+            }
+            _ => return Err(anyhow!("Unknown tracker option {}", arg)),
+        }
+    }
+    if http.is_none() && udp.is_none() {
+        http = Some("0.0.0.0:6969".parse()?);
+    }
+    let registry = Arc::new(SwarmRegistry::new(config));
+    tokio::spawn(expire_periodically(registry.clone()));
+    let http = match http {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            println!("HTTP tracker listening on {}", listener.local_addr()?);
+            Some(serve_http(listener, registry.clone()))
+        }
+        None => None,
+    };
+    let udp = match udp {
+        Some(addr) => {
+            let socket = UdpSocket::bind(addr).await?;
+            println!("UDP tracker listening on {}", socket.local_addr()?);
+            Some(serve_udp(socket, registry))
+        }
+        None => None,
+    };
+    match (http, udp) {
+        (Some(http), Some(udp)) => tokio::try_join!(http, udp).map(|_| ()),
+        (Some(http), None) => http.await,
+        (None, Some(udp)) => udp.await,
+        (None, None) => Ok(()),
+    }
+}
 
-    let mut peer = tokio_util::codec::Framed::new(stream, BTMessageFramer);
+/// `dht <command> <argument> [--bootstrap <host:port>]... [--bind <addr>] [--state <file>]`
+///
+/// * `get_peers <info-hash>` / `announce <info-hash>`: find peers, and for
+///   `announce` also register our peer port.
+/// * `get <target>` / `put <string>`: BEP 44 immutable items.
+/// * `publish <info-hash> --key <file> [--salt <salt>]`: point a BEP 46 link
+///   at a torrent. The key file holds a 32 byte seed and is created if missing.
+/// * `resolve <magnet>`: print the info hash a BEP 46 link points at.
+///
+/// Bootstrap nodes replace the default routers when given.
+async fn dht_command(args: &[String], config: &ClientConfig) -> Result<()> {
+    let usage = || {
+        anyhow!(
+            "Usage: dht get_peers|announce|get|put|publish|resolve <argument> \
+             [--bootstrap <host:port>]... [--bind <addr>] [--state <file>] [--key <file>] [--salt <salt>]"
+        )
+    };
+    let (command, argument) = match args {
+        [command, argument, ..] => (command.as_str(), argument.as_str()),
+        _ => return Err(usage()),
+    };
+    if !["get_peers", "announce", "get", "put", "publish", "resolve"].contains(&command) {
+        return Err(usage());
+    }
+    let mut dht_config = dht_config(config);
+    let mut bootstrap = Vec::new();
+    let mut key_file: Option<String> = None;
+    let mut salt = Vec::new();
+    let mut args = args[2..].iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(anyhow!("Missing value for option {}", arg))
+        };
+        match arg.as_str() {
+            "--bootstrap" => bootstrap.push(value()?.clone()),
+            "--bind" => dht_config.bind = value()?.parse()?,
+            "--state" => dht_config.state_file = Some(value()?.into()),
+            "--key" => key_file = Some(value()?.clone()),
+            "--salt" => salt = value()?.as_bytes().to_vec(),
+            _ => return Err(anyhow!("Unknown dht option {}", arg)),
+        }
+    }
+    if !bootstrap.is_empty() {
+        dht_config.bootstrap = bootstrap;
+    }
 
-    while let Some(msg) = peer.next().await {
-        match msg? {
-            BTMessage::Choke => {}
-            BTMessage::Unchoke => {
-                download_pieces(0, &torrent_info, &mut peer, "sample.txt").await?;
-            }
-            BTMessage::Interested => {}
-            BTMessage::NotInterested => {}
-            BTMessage::Have(_) => {}
-            BTMessage::Bitfield(_) => {
-                let intr = BTMessage::Interested;
-                peer.send(intr).await?;
+    let node = DhtNode::bind(dht_config).await?;
+    log::info!(
+        "DHT node {} on {}",
+        hex::encode(node.id()),
+        node.local_addr()?
+    );
+    let nodes = node.bootstrap().await?;
+    log::info!("DHT routing table has {} nodes", nodes);
+    match command {
+        "get_peers" | "announce" => {
+            let info_hash = parse_info_hash(argument)?;
+            let peers = if command == "announce" {
+                node.announce(info_hash, config.port).await?
+            } else {
+                node.lookup_peers(info_hash).await
+            };
+            for peer in peers {
+                println!("{}", peer);
             }
-            BTMessage::Request(_, _, _) => {}
-            BTMessage::Piece(idx, offset, data) => {
-                //println!("Piece: {} {} {:?} ", idx, offset, data.len());
-                peer::write_at_offset(
-                    &torrent_info.info.name,
-                    (idx * torrent_info.info.piece_length as u32 + offset) as u64,
-                    &data,
-                )
+        }
+        "get" => {
+            let value = node.get_immutable(parse_info_hash(argument)?).await?;
+            println!("{}", bencode::to_string(&value)?);
+        }
+        "put" => {
+            let value = bencode::Value::Str(argument.as_bytes().to_vec());
+            println!("{}", hex::encode(node.put_immutable(value).await?));
+        }
+        "publish" => {
+            let key_file = key_file.ok_or(anyhow!("publish needs --key <file>"))?;
+            let key = load_or_create_key(&key_file)?;
+            let seq = node
+                .publish_mutable_torrent(&key, &salt, parse_info_hash(argument)?)
                 .await?;
-                break;
-            }
-            BTMessage::Cancel(_, _, _) => {}
+            println!("{}", MagnetLink::mutable(&key.public_key(), &salt));
+            println!("Sequence: {}", seq);
+        }
+        _ => {
+            let magnet = MagnetLink::parse(argument)?;
+            let public_key = magnet
+                .public_key
+                .ok_or(anyhow!("{} is not a urn:btpk: link", argument))?;
+            let info_hash = node
+                .resolve_mutable_torrent(&public_key, &magnet.salt)
+                .await?;
+            println!("{}", hex::encode(info_hash));
         }
     }
+    node.save()
+}
 
+/// Reads an ed25519 seed from `path`, generating and saving one first if the
+/// file does not exist.
+fn load_or_create_key(path: &str) -> Result<SigningKey> {
+    let seed: [u8; 32] = match fs::read(path) {
+        Ok(seed) => seed
+            .try_into()
+            .map_err(|_| anyhow!("Key file {} must hold a 32 byte seed", path))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut seed = [0u8; 32];
+            random::fill_secure(&mut seed)?;
+            write_private_file(path, &seed)?;
+            seed
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(SigningKey::from_seed(&seed))
+}
+
+/// Creates `path` readable only by us, failing if it already exists.
+fn write_private_file(path: &str, data: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, data)?;
     Ok(())
 }
+
 // can_parse_message now also removes the processed message from the buffer
 
 pub(crate) async fn entrypoint(args: Vec<String>) -> Result<()> {
+    let (config, args) = ClientConfig::from_args(args)?;
     if args.len() < 2 {
-        no_args().await?;
+        no_args(config).await?;
         println!("{}", &args[0]);
     } else {
         let command = &args[1]; // &args[1];
@@ -198,126 +564,146 @@ pub(crate) async fn entrypoint(args: Vec<String>) -> Result<()> {
             println!("Info Hash: {}", torrent_info.raw().info_hash()?);
             println!("Piece Length: {}", torrent_info.info.piece_length);
             println!("Piece Hashes:\n{}", torrent_info.info.hashes().join("\n"));
+        } else if command == "peers" && args[2].starts_with("magnet:") {
+            magnet_peers(&args[2], &config).await?;
         } else if command == "peers" {
             let path = &args[2];
             let _content = read_binary_file(path)?;
             let data = bencode::decode(&_content)?;
             let torrent_info = MetaData::new(data.clone())?;
-            let peers = discover_peers(&torrent_info).await?;
-            for (ip, port) in peers.iter() {
-                println!("{}:{}", ip, port);
+            let response = discover_peers(&torrent_info, &config).await?;
+            if let Some(warning) = &response.warning {
+                eprintln!("Tracker warning: {}", warning);
+            }
+            for peer in response.peers.iter() {
+                println!("{}", peer.addr);
+            }
+            if let Some(complete) = response.complete {
+                println!("Seeders: {}", complete);
+            }
+            if let Some(incomplete) = response.incomplete {
+                println!("Leechers: {}", incomplete);
             }
+        } else if command == "scrape" {
+            scrape_command(&args[2..]).await?;
+        } else if command == "tracker" {
+            tracker_command(&args[2..]).await?;
+        } else if command == "dht" {
+            dht_command(&args[2..], &config).await?;
         } else if command == "handshake" {
             let _peer = &args[3];
             println!("peer: {}", _peer);
             let _content = read_binary_file(&args[2])?;
             let torrent_info = MetaData::new(bencode::decode(&_content)?)?;
-            let _peers = discover_peers(&torrent_info).await?;
-            let _handshake =
-                Handshake::new(b"00112233445566778899", &torrent_info.raw().info_hash_u8()?);
-            let mut peer_manager = PeerManager::new(torrent_info.clone()).await?;
-            // let (peer_ip, peer_port) = peers.iter().next().ok_or(anyhow!("Failed to get first peer"))?;
-            let mut p = _peer.split(':');
-            let _peer_ip = p.next().unwrap();
-            let _peer_port = p.next().unwrap().parse::<u16>()?;
-            let _stream = peer_manager.connect_to_peer().await?;
+            let mut peer_manager = PeerManager::new(torrent_info.clone(), config.clone());
+            let peer_addr = _peer.parse::<SocketAddr>()?;
+            peer_manager.add_source(Box::new(StaticPeers::new("command line", vec![peer_addr])));
+            let connection = peer_manager.connect().await?;
+            println!("Peer ID: {}", connection.handshake.peer_id());
         } else if command == "download_piece" {
-            println!("no args {} {:#?}", args.len(), args);
-            println!("file_name: {}, _content {}", &args[3], &args[4]);
             let file_name = &args[3];
             let _content = read_binary_file(&args[4])?;
             let _piece_number = &args[5].parse::<usize>()?;
             let torrent_info = MetaData::new(bencode::decode(&_content)?)?;
-            let _peers = discover_peers(&torrent_info).await?;
-            let _handshake =
-                Handshake::new(b"00112233445566778899", &torrent_info.raw().info_hash_u8()?);
-            let mut peer_manager = PeerManager::new(torrent_info.clone()).await?;
-            let stream = peer_manager.connect_to_peer().await?;
-
-            let mut peer = tokio_util::codec::Framed::new(stream, BTMessageFramer);
-
-            while let Some(msg) = peer.next().await {
-                //println!("{:#?}", msg);
-                match msg? {
-                    BTMessage::Choke => {}
-                    BTMessage::Unchoke => {
-                        download_piece(*_piece_number, &torrent_info, &mut peer, file_name).await?;
-                    }
-                    BTMessage::Interested => {}
-                    BTMessage::NotInterested => {}
-                    BTMessage::Have(_) => {}
-                    BTMessage::Bitfield(_) => {
-                        let intr = BTMessage::Interested;
-                        peer.send(intr).await?;
-                    }
-                    BTMessage::Request(_, _, _) => {}
-                    BTMessage::Piece(_idx, offset, data) => {
-                        peer::write_at_offset(
-                            file_name,
-                            (offset) as u64,
-                            &data,
-                        )
-                        .await?;
-                        unsafe {
-                            DOWNLOADED -= 1;
-                            if DOWNLOADED == 0 {
-                                break;
-                            }
-                        }
-                    }
-                    BTMessage::Cancel(_, _, _) => {}
-                }
-            }
+            let mut peer_manager = peer_manager_with_tracker(&torrent_info, config.clone())?;
+            let mut peer = peer_manager.connect_to_peer().await?;
+            fetch_piece(&mut peer, *_piece_number, &torrent_info, file_name).await?;
         } else if command == "download" {
-            println!("no args {} {:#?}", args.len(), args);
-            println!("file_name: {}, _content {}", &args[3], &args[4]);
             let file_name = &args[3];
             let _content = read_binary_file(&args[4])?;
-            let _piece_number = 0;
             let torrent_info = MetaData::new(bencode::decode(&_content)?)?;
-            let _peers = discover_peers(&torrent_info).await?;
-            let _handshake =
-                Handshake::new(b"00112233445566778899", &torrent_info.raw().info_hash_u8()?);
-            let mut peer_manager = PeerManager::new(torrent_info.clone()).await?;
-            let stream = peer_manager.connect_to_peer().await?;
-
-            let mut peer = tokio_util::codec::Framed::new(stream, BTMessageFramer);
-
-            while let Some(msg) = peer.next().await {
-                //println!("{:#?}", msg);
-                match msg? {
-                    BTMessage::Choke => {}
-                    BTMessage::Unchoke => {
-                        download_pieces(_piece_number, &torrent_info, &mut peer, file_name).await?;
-                    }
-                    BTMessage::Interested => {}
-                    BTMessage::NotInterested => {}
-                    BTMessage::Have(_) => {}
-                    BTMessage::Bitfield(_) => {
-                        let intr = BTMessage::Interested;
-                        peer.send(intr).await?;
-                    }
-                    BTMessage::Request(_, _, _) => {}
-                    BTMessage::Piece(idx, offset, data) => {
-                        peer::write_at_offset(
-                            file_name,
-                            (idx * torrent_info.info.piece_length as u32 + offset) as u64,
-                            &data,
-                        )
-                        .await?;
-                        unsafe {
-                            DOWNLOADED -= 1;
-                            if DOWNLOADED == 0 {
-                                break;
-                            }
-                        }
-                    }
-                    BTMessage::Cancel(_, _, _) => {}
-                }
-            }
+            download(torrent_info, file_name, &config).await?;
         } else {
             println!("unknown command: {}", args[1])
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::bencode::Value;
+    use bytes::Bytes;
+
+    #[test]
+    fn created_keys_are_private_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("key-{:016x}", random::next_u64()));
+        let path = path.to_str().unwrap();
+        let key = load_or_create_key(path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let again = load_or_create_key(path).unwrap();
+        assert_eq!(again.public_key(), key.public_key());
+        assert!(write_private_file(path, &[0; 32]).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetched_pieces_land_at_their_offset_after_a_choke() {
+        const PIECE_LENGTH: usize = 32 * 1024;
+        let data: Vec<u8> = (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+        let pieces = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let info = Value::dict([
+            ("length", Value::Int(data.len() as i64)),
+            ("name", Value::Str(b"piece".to_vec())),
+            ("piece length", Value::Int(PIECE_LENGTH as i64)),
+            ("pieces", Value::Str(pieces)),
+        ]);
+        let announce = Value::Str(b"http://127.0.0.1/announce".to_vec());
+        let torrent = MetaData::new(Value::dict([("announce", announce), ("info", info)])).unwrap();
+
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut peer = Framed::new(ours, BTMessageFramer::default());
+        let mut seeder = Framed::new(theirs, BTMessageFramer::default());
+        tokio::spawn(async move {
+            seeder.send(BTMessage::HaveAll).await.unwrap();
+            seeder.send(BTMessage::Unchoke).await.unwrap();
+            let mut choked_once = false;
+            while let Some(Ok(msg)) = seeder.next().await {
+                let BTMessage::Request(index, begin, length) = msg else {
+                    continue;
+                };
+                // Choking drops the first request, which must be asked again.
+                if !choked_once {
+                    choked_once = true;
+                    seeder.send(BTMessage::Choke).await.unwrap();
+                    seeder.send(BTMessage::Unchoke).await.unwrap();
+                    continue;
+                }
+                let start = index as usize * PIECE_LENGTH + begin as usize;
+                let block = Bytes::copy_from_slice(&data[start..start + length as usize]);
+                let piece = BTMessage::Piece(index, begin, block);
+                if seeder.send(piece).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let path = std::env::temp_dir().join(format!("piece-{:016x}", random::next_u64()));
+        let file_name = path.to_str().unwrap();
+        time::timeout(
+            Duration::from_secs(5),
+            fetch_piece(&mut peer, 1, &torrent, file_name),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let written = fs::read(file_name).unwrap();
+        assert_eq!(written.len(), 2 * PIECE_LENGTH);
+        assert_eq!(
+            &written[PIECE_LENGTH..],
+            &(PIECE_LENGTH..2 * PIECE_LENGTH)
+                .map(|i| (i % 251) as u8)
+                .collect::<Vec<_>>()[..]
+        );
+        fs::remove_file(file_name).unwrap();
+    }
+}
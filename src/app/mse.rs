@@ -0,0 +1,715 @@
+use crate::app::random;
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The 768-bit MSE prime, big endian. The generator is 2.
+const P: [u8; 96] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const KEY_LEN: usize = 96;
+/// Private exponents are 160 bits, as the spec recommends.
+const PRIVATE_KEY_LEN: usize = 20;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+/// RC4 output dropped before use, hiding the weak start of the stream.
+const RC4_DISCARD: usize = 1024;
+/// A peer that ignores MSE tends to sit waiting for a plain handshake.
+pub const MSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `crypto_provide` / `crypto_select` bits.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Start of a plaintext BitTorrent handshake, used to tell unencrypted
+/// incoming connections apart.
+const PLAINTEXT_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MseError {
+    #[error("Connection closed during the encryption handshake")]
+    Closed,
+    #[error("No {0} within the padding")]
+    NotFound(&'static str),
+    #[error("Peer connects for a torrent we do not serve")]
+    UnknownInfoHash,
+    #[error("Peer offered crypto methods {0:#x}, none acceptable")]
+    NoCommonMethod(u32),
+    #[error("Peer selected crypto method {0:#x}, which we did not offer")]
+    BadSelect(u32),
+    #[error("Padding of {0} bytes exceeds {MAX_PAD}")]
+    PadTooLong(usize),
+    #[error("Encryption is disabled")]
+    Disabled,
+    #[error("Peer sent a plaintext handshake but encryption is forced")]
+    PlaintextRefused,
+}
+
+/// When to use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Plain BitTorrent only.
+    Disabled,
+    /// Offer encryption, but accept and fall back to plaintext.
+    #[default]
+    Enabled,
+    /// RC4 only; plaintext peers are dropped.
+    Forced,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            _ => Err(anyhow!("Unknown encryption policy {}", s)),
+        }
+    }
+}
+
+const LIMBS: usize = KEY_LEN / 8;
+
+/// A number below 2^768 in little-endian 64-bit limbs.
+type Limbs = [u64; LIMBS];
+
+fn limbs(bytes: &[u8; KEY_LEN]) -> Limbs {
+    std::array::from_fn(|i| {
+        let end = KEY_LEN - i * 8;
+        u64::from_be_bytes(bytes[end - 8..end].try_into().unwrap())
+    })
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; KEY_LEN] {
+    let mut out = [0u8; KEY_LEN];
+    for (i, limb) in limbs.iter().enumerate() {
+        let end = KEY_LEN - i * 8;
+        out[end - 8..end].copy_from_slice(&limb.to_be_bytes());
+    }
+    out
+}
+
+fn ge(a: &Limbs, b: &Limbs) -> bool {
+    for i in (0..LIMBS).rev() {
+        if a[i] != b[i] {
+            return a[i] > b[i];
+        }
+    }
+    true
+}
+
+fn sub_assign(a: &mut Limbs, b: &Limbs) {
+    let mut borrow = 0u64;
+    for i in 0..LIMBS {
+        let (value, b1) = a[i].overflowing_sub(b[i]);
+        let (value, b2) = value.overflowing_sub(borrow);
+        a[i] = value;
+        borrow = (b1 || b2) as u64;
+    }
+}
+
+/// (a * b) mod p: a full product, then reduced one bit at a time from the
+/// top.
+fn mul_mod(a: &Limbs, b: &Limbs, p: &Limbs) -> Limbs {
+    let mut product = [0u64; 2 * LIMBS];
+    for i in 0..LIMBS {
+        let mut carry = 0u128;
+        for j in 0..LIMBS {
+            let t = product[i + j] as u128 + a[i] as u128 * b[j] as u128 + carry;
+            product[i + j] = t as u64;
+            carry = t >> 64;
+        }
+        product[i + LIMBS] = carry as u64;
+    }
+    let mut r = [0u64; LIMBS];
+    for bit in (0..2 * LIMBS * 64).rev() {
+        // r < p, but p uses all 768 bits, so the shift can carry out.
+        let overflow = r[LIMBS - 1] >> 63 == 1;
+        for i in (1..LIMBS).rev() {
+            r[i] = (r[i] << 1) | (r[i - 1] >> 63);
+        }
+        r[0] = (r[0] << 1) | ((product[bit / 64] >> (bit % 64)) & 1);
+        if overflow || ge(&r, p) {
+            sub_assign(&mut r, p);
+        }
+    }
+    r
+}
+
+/// base^exponent mod P, with a big-endian exponent.
+fn pow_mod(base: &[u8; KEY_LEN], exponent: &[u8]) -> [u8; KEY_LEN] {
+    let p = limbs(&P);
+    let base = limbs(base);
+    let mut result = [0u64; LIMBS];
+    result[0] = 1;
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            result = mul_mod(&result, &result, &p);
+            if (byte >> bit) & 1 == 1 {
+                result = mul_mod(&result, &base, &p);
+            }
+        }
+    }
+    to_bytes(&result)
+}
+
+/// One side of the Diffie-Hellman exchange.
+struct KeyPair {
+    private: [u8; PRIVATE_KEY_LEN],
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    /// A fresh key pair. The private exponent comes from the OS, since
+    /// anyone who can guess it can derive the RC4 keys.
+    fn generate() -> std::io::Result<Self> {
+        let mut private = [0u8; PRIVATE_KEY_LEN];
+        random::fill_secure(&mut private)?;
+        let mut generator = [0u8; KEY_LEN];
+        generator[KEY_LEN - 1] = 2;
+        Ok(Self {
+            public: pow_mod(&generator, &private),
+            private,
+        })
+    }
+
+    fn shared_secret(&self, their_public: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        pow_mod(their_public, &self.private)
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// The MSE cipher for one direction, keyed by `HASH(label, S, SKEY)`.
+    fn for_direction(label: &[u8], secret: &[u8; KEY_LEN], skey: &[u8; 20]) -> Self {
+        let mut rc4 = Rc4::new(&hash(&[label, secret, skey]));
+        rc4.apply(&mut [0u8; RC4_DISCARD]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// Random padding of up to `MAX_PAD` bytes.
+fn padding() -> Vec<u8> {
+    let mut pad = vec![0u8; random::next_u32() as usize % (MAX_PAD + 1)];
+    random::fill(&mut pad);
+    pad
+}
+
+/// Reads the handshake from a transport, keeping bytes read ahead for
+/// whatever comes next.
+struct Reader<T> {
+    io: T,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Reader<T> {
+    fn new(io: T, buffer: Vec<u8>) -> Self {
+        Self { io, buffer, pos: 0 }
+    }
+
+    async fn fill(&mut self, len: usize) -> Result<()> {
+        let mut chunk = [0u8; 1024];
+        while self.buffer.len() - self.pos < len {
+            match self.io.read(&mut chunk).await? {
+                0 => return Err(MseError::Closed.into()),
+                read => self.buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+        Ok(())
+    }
+
+    async fn take(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.fill(len).await?;
+        let bytes = self.buffer[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Skips past `pattern`, which must start within `MAX_PAD` bytes.
+    async fn skip_past(&mut self, pattern: &[u8], what: &'static str) -> Result<()> {
+        loop {
+            let window = &self.buffer[self.pos..];
+            if let Some(at) = window
+                .windows(pattern.len())
+                .position(|candidate| candidate == pattern)
+            {
+                self.pos += at + pattern.len();
+                return Ok(());
+            }
+            let buffered = window.len();
+            if buffered >= MAX_PAD + pattern.len() {
+                return Err(MseError::NotFound(what).into());
+            }
+            self.fill(buffered + 1).await?;
+        }
+    }
+
+    /// Reads `len` bytes and decrypts them.
+    async fn take_decrypted(&mut self, len: usize, rc4: &mut Rc4) -> Result<Vec<u8>> {
+        let mut bytes = self.take(len).await?;
+        rc4.apply(&mut bytes);
+        Ok(bytes)
+    }
+
+    fn into_parts(self) -> (T, Vec<u8>) {
+        (self.io, self.buffer[self.pos..].to_vec())
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// Opens MSE on a connection we made for `info_hash`. The returned stream
+/// carries the BitTorrent handshake and everything after it.
+pub async fn initiate<T: AsyncRead + AsyncWrite + Unpin>(
+    mut io: T,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<T>> {
+    if policy == EncryptionPolicy::Disabled {
+        return Err(MseError::Disabled.into());
+    }
+    let keys = KeyPair::generate()?;
+    let mut hello = keys.public.to_vec();
+    hello.extend(padding());
+    io.write_all(&hello).await?;
+
+    let mut reader = Reader::new(io, Vec::new());
+    let their_public: [u8; KEY_LEN] = reader.take(KEY_LEN).await?.try_into().unwrap();
+    let secret = keys.shared_secret(&their_public);
+    let mut encrypt = Rc4::for_direction(b"keyA", &secret, info_hash);
+    let mut decrypt = Rc4::for_direction(b"keyB", &secret, info_hash);
+
+    let mut request = hash(&[b"req1", &secret]).to_vec();
+    request.extend(xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])));
+    let pad = padding();
+    let mut offer = VC.to_vec();
+    offer.extend(policy.crypto_provide().to_be_bytes());
+    offer.extend((pad.len() as u16).to_be_bytes());
+    offer.extend(pad);
+    // No initial payload: the BitTorrent handshake follows once the
+    // method is agreed.
+    offer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut offer);
+    request.extend(offer);
+    reader.io.write_all(&request).await?;
+
+    // The peer's reply starts with VC encrypted under its key.
+    let mut encrypted_vc = VC;
+    let mut probe = decrypt.clone();
+    probe.apply(&mut encrypted_vc);
+    reader
+        .skip_past(&encrypted_vc, "verification constant")
+        .await?;
+    decrypt = probe;
+    let reply = reader.take_decrypted(6, &mut decrypt).await?;
+    let select = read_u32(&reply);
+    if select.count_ones() != 1 || select & policy.crypto_provide() == 0 {
+        return Err(MseError::BadSelect(select).into());
+    }
+    let pad_len = read_u16(&reply[4..]);
+    if pad_len > MAX_PAD {
+        return Err(MseError::PadTooLong(pad_len).into());
+    }
+    reader.take_decrypted(pad_len, &mut decrypt).await?;
+
+    let (io, rest) = reader.into_parts();
+    Ok(MseStream::negotiated(io, select, encrypt, decrypt, rest))
+}
+
+/// Answers a connection the peer opened, which may be plain BitTorrent or
+/// MSE for one of `info_hashes`. Returns the stream and, for MSE, the
+/// info hash the peer asked for.
+#[allow(dead_code)]
+pub async fn accept<T: AsyncRead + AsyncWrite + Unpin>(
+    io: T,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<T>, Option<[u8; 20]>)> {
+    let mut reader = Reader::new(io, Vec::new());
+    reader.fill(PLAINTEXT_HEADER.len()).await?;
+    let plaintext = reader.buffer.starts_with(PLAINTEXT_HEADER);
+    match (plaintext, policy) {
+        (true, EncryptionPolicy::Forced) => return Err(MseError::PlaintextRefused.into()),
+        (true, _) => {
+            let (io, rest) = reader.into_parts();
+            return Ok((MseStream::plaintext_with(io, rest), None));
+        }
+        (false, EncryptionPolicy::Disabled) => return Err(MseError::Disabled.into()),
+        (false, _) => {}
+    }
+
+    let their_public: [u8; KEY_LEN] = reader.take(KEY_LEN).await?.try_into().unwrap();
+    let keys = KeyPair::generate()?;
+    let mut hello = keys.public.to_vec();
+    hello.extend(padding());
+    reader.io.write_all(&hello).await?;
+    let secret = keys.shared_secret(&their_public);
+
+    reader
+        .skip_past(&hash(&[b"req1", &secret]), "req1 hash")
+        .await?;
+    let obfuscated: [u8; 20] = reader.take(20).await?.try_into().unwrap();
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| xor(hash(&[b"req2", *info_hash]), req3) == obfuscated)
+        .ok_or(MseError::UnknownInfoHash)?;
+    let mut decrypt = Rc4::for_direction(b"keyA", &secret, &info_hash);
+    let mut encrypt = Rc4::for_direction(b"keyB", &secret, &info_hash);
+
+    let offer = reader.take_decrypted(14, &mut decrypt).await?;
+    if offer[..8] != VC {
+        return Err(MseError::NotFound("verification constant").into());
+    }
+    let provide = read_u32(&offer[8..]);
+    let pad_len = read_u16(&offer[12..]);
+    if pad_len > MAX_PAD {
+        return Err(MseError::PadTooLong(pad_len).into());
+    }
+    reader.take_decrypted(pad_len, &mut decrypt).await?;
+    let ia_len = read_u16(&reader.take_decrypted(2, &mut decrypt).await?);
+    let initial_payload = reader.take_decrypted(ia_len, &mut decrypt).await?;
+
+    let acceptable = provide & policy.crypto_provide();
+    let select = if acceptable & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if acceptable & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(MseError::NoCommonMethod(provide).into());
+    };
+    let pad = padding();
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend((pad.len() as u16).to_be_bytes());
+    reply.extend(pad);
+    encrypt.apply(&mut reply);
+    reader.io.write_all(&reply).await?;
+
+    let (io, rest) = reader.into_parts();
+    let mut stream = MseStream::negotiated(io, select, encrypt, decrypt, rest);
+    stream.pending.splice(0..0, initial_payload);
+    Ok((stream, Some(info_hash)))
+}
+
+/// A peer transport after MSE, encrypting with RC4 if that was selected.
+/// Also wraps unencrypted connections so either kind has the same type.
+pub struct MseStream<T> {
+    io: T,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    /// Plaintext read during the handshake but not yet returned.
+    pending: Vec<u8>,
+    /// Encrypted bytes accepted by `poll_write` but not yet written.
+    unwritten: Vec<u8>,
+}
+
+impl<T> MseStream<T> {
+    pub fn plaintext(io: T) -> Self {
+        Self::plaintext_with(io, Vec::new())
+    }
+
+    fn plaintext_with(io: T, pending: Vec<u8>) -> Self {
+        Self {
+            io,
+            encrypt: None,
+            decrypt: None,
+            pending,
+            unwritten: Vec::new(),
+        }
+    }
+
+    /// `rest` is what was read past the handshake, still as sent.
+    fn negotiated(io: T, select: u32, encrypt: Rc4, mut decrypt: Rc4, mut rest: Vec<u8>) -> Self {
+        if select != CRYPTO_RC4 {
+            return Self::plaintext_with(io, rest);
+        }
+        decrypt.apply(&mut rest);
+        Self {
+            io,
+            encrypt: Some(encrypt),
+            decrypt: Some(decrypt),
+            pending: rest,
+            unwritten: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+}
+
+impl<T: AsyncWrite + Unpin> MseStream<T> {
+    fn poll_write_unwritten(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.unwritten.is_empty() {
+            let written = ready!(Pin::new(&mut self.io).poll_write(cx, &self.unwritten))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.unwritten.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MseStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let len = this.pending.len().min(buf.remaining());
+            buf.put_slice(&this.pending[..len]);
+            this.pending.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.io).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MseStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.io).poll_write(cx, buf);
+        }
+        // Once encrypted the keystream has moved on, so bytes are owned
+        // here until the transport takes them.
+        ready!(this.poll_write_unwritten(cx))?;
+        let mut encrypted = buf.to_vec();
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut encrypted);
+        }
+        this.unwritten = encrypted;
+        if let Poll::Ready(Err(e)) = this.poll_write_unwritten(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unwritten(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unwritten(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const INFO_HASH: [u8; 20] = [0x44; 20];
+
+    #[test]
+    fn rc4_matches_reference_vectors() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+        let mut data = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut data);
+        assert_eq!(hex::encode(data), "45a01f645fc35b383552544b9bf5");
+    }
+
+    #[test]
+    fn diffie_hellman_agrees() {
+        let mut two = [0u8; KEY_LEN];
+        two[KEY_LEN - 1] = 2;
+        let mut expected = [0u8; KEY_LEN];
+        expected[KEY_LEN - 2] = 4;
+        assert_eq!(pow_mod(&two, &[10]), expected);
+        // Fermat: 2^(p-1) = 1 mod p for the prime p.
+        let mut p_minus_1 = P;
+        p_minus_1[KEY_LEN - 1] -= 1;
+        let mut one = [0u8; KEY_LEN];
+        one[KEY_LEN - 1] = 1;
+        assert_eq!(pow_mod(&two, &p_minus_1), one);
+
+        let (a, b) = (KeyPair::generate().unwrap(), KeyPair::generate().unwrap());
+        assert_ne!(a.public, b.public);
+        assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    }
+
+    async fn exchange(
+        initiator: EncryptionPolicy,
+        acceptor: EncryptionPolicy,
+    ) -> Result<(
+        MseStream<tokio::io::DuplexStream>,
+        MseStream<tokio::io::DuplexStream>,
+    )> {
+        let (a, b) = duplex(4096);
+        let served = [[1; 20], INFO_HASH];
+        let (outgoing, incoming) = tokio::join!(
+            initiate(a, &INFO_HASH, initiator),
+            accept(b, &served, acceptor)
+        );
+        let (incoming, info_hash) = incoming?;
+        assert_eq!(info_hash, Some(INFO_HASH));
+        Ok((outgoing?, incoming))
+    }
+
+    #[tokio::test]
+    async fn negotiates_rc4_and_carries_data() {
+        let (mut outgoing, mut incoming) =
+            exchange(EncryptionPolicy::Enabled, EncryptionPolicy::Enabled)
+                .await
+                .unwrap();
+        assert!(outgoing.is_encrypted() && incoming.is_encrypted());
+        outgoing
+            .write_all(b"\x13BitTorrent protocol")
+            .await
+            .unwrap();
+        outgoing.flush().await.unwrap();
+        let mut received = [0u8; 20];
+        incoming.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, PLAINTEXT_HEADER);
+        incoming.write_all(b"reply").await.unwrap();
+        incoming.flush().await.unwrap();
+        let mut reply = [0u8; 5];
+        outgoing.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"reply");
+    }
+
+    #[tokio::test]
+    async fn policies_decide_the_method() {
+        let (outgoing, incoming) = exchange(EncryptionPolicy::Forced, EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert!(outgoing.is_encrypted() && incoming.is_encrypted());
+
+        let error = exchange(EncryptionPolicy::Enabled, EncryptionPolicy::Disabled)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MseError::Disabled | MseError::Closed)
+        ));
+        assert!(
+            initiate(duplex(64).0, &INFO_HASH, EncryptionPolicy::Disabled)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_plaintext_unless_forced() {
+        let mut handshake = PLAINTEXT_HEADER.to_vec();
+        handshake.extend([0; 48]);
+        for policy in [EncryptionPolicy::Disabled, EncryptionPolicy::Enabled] {
+            let (mut a, b) = duplex(1024);
+            a.write_all(&handshake).await.unwrap();
+            let (mut stream, info_hash) = accept(b, &[INFO_HASH], policy).await.unwrap();
+            assert_eq!(info_hash, None);
+            assert!(!stream.is_encrypted());
+            let mut received = vec![0u8; handshake.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, handshake);
+        }
+        let (mut a, b) = duplex(1024);
+        a.write_all(&handshake).await.unwrap();
+        let error = accept(b, &[INFO_HASH], EncryptionPolicy::Forced)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref(), Some(&MseError::PlaintextRefused));
+    }
+
+    #[tokio::test]
+    async fn unknown_info_hash_is_rejected() {
+        let (a, b) = duplex(4096);
+        let (_, incoming) = tokio::join!(
+            initiate(a, &[9; 20], EncryptionPolicy::Enabled),
+            accept(b, &[INFO_HASH], EncryptionPolicy::Enabled)
+        );
+        let error = incoming.err().unwrap();
+        assert_eq!(error.downcast_ref(), Some(&MseError::UnknownInfoHash));
+    }
+}
@@ -1,13 +1,68 @@
+use crate::app::config::ClientConfig;
 use crate::app::tracker::MetaData;
 
 use crate::app::bencode;
 use crate::app::bencode::Value;
+use crate::app::udp_tracker::UdpTrackerClient;
 use anyhow::{anyhow, Result};
 use reqwest::Client;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
 use url::Url;
 
+/// A peer candidate returned by a tracker.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            peer_id: None,
+        }
+    }
+}
+
+/// Decoded tracker announce reply.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AnnounceResponse {
+    pub peers: Vec<Peer>,
+    pub warning: Option<String>,
+    /// Seconds the client should wait between regular announces.
+    pub interval: Option<u64>,
+    /// Seconds the client must wait before announcing again, if set.
+    pub min_interval: Option<u64>,
+    /// Opaque bytes to send back on subsequent announces.
+    pub tracker_id: Option<Vec<u8>>,
+    /// Number of seeders in the swarm.
+    pub complete: Option<u64>,
+    /// Number of leechers in the swarm.
+    pub incomplete: Option<u64>,
+}
+
+/// Per-torrent swarm statistics from a scrape (BEP 48).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrapeStats {
+    /// Number of seeders.
+    pub complete: u64,
+    /// Number of times the torrent has been fully downloaded.
+    pub downloaded: u64,
+    /// Number of leechers.
+    pub incomplete: u64,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TrackerError {
+    #[error("Tracker returned failure: {0}")]
+    Failure(String),
+}
+
 // Define characters that do NOT require encoding
-fn urlencode(data: &[u8]) -> String {
+pub(crate) fn urlencode(data: &[u8]) -> String {
     let lookup = b"0123456789abcdef";
     let mut encoded = String::new();
     for &byte in data {
@@ -25,56 +80,467 @@ fn urlencode(data: &[u8]) -> String {
     encoded
 }
 
-pub(crate) async fn discover_peers(torrent: &MetaData) -> Result<Vec<(String, u16)>> {
-    let announce = &torrent.announce;
+/// Lifecycle events sent with an announce (BEP 3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+
+    /// Parses the `event` announce parameter; `empty` and unknown values mean
+    /// a regular announce.
+    pub fn parse(event: &str) -> Option<Self> {
+        match event {
+            "started" => Some(AnnounceEvent::Started),
+            "completed" => Some(AnnounceEvent::Completed),
+            "stopped" => Some(AnnounceEvent::Stopped),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub tracker_id: Option<Vec<u8>>,
+    pub numwant: Option<u32>,
+    pub key: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub no_peer_id: bool,
+}
+
+impl AnnounceRequest {
+    pub(crate) fn new(torrent: &MetaData, config: &ClientConfig) -> Result<Self> {
+        let info_hash: [u8; 20] = torrent
+            .raw()
+            .info_hash_u8()?
+            .try_into()
+            .map_err(|_| anyhow!("Info hash must be 20 bytes."))?;
+        Ok(Self::from_info_hash(
+            info_hash,
+            torrent.info.length as u64,
+            config,
+        ))
+    }
+
+    pub(crate) fn from_info_hash(info_hash: [u8; 20], left: u64, config: &ClientConfig) -> Self {
+        Self {
+            info_hash,
+            peer_id: config.peer_id,
+            port: config.port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            tracker_id: None,
+            numwant: config.numwant,
+            key: Some(config.key.clone()),
+            ip: config.ip,
+            no_peer_id: config.no_peer_id,
+        }
+    }
+
+    fn query(&self) -> String {
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            urlencode(&self.info_hash),
+            urlencode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if let Some(event) = self.event {
+            query.push_str("&event=");
+            query.push_str(event.as_str());
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            query.push_str("&trackerid=");
+            query.push_str(&urlencode(tracker_id));
+        }
+        if let Some(numwant) = self.numwant {
+            query.push_str(&format!("&numwant={}", numwant));
+        }
+        if let Some(key) = &self.key {
+            query.push_str("&key=");
+            query.push_str(&urlencode(key.as_bytes()));
+        }
+        if let Some(ip) = self.ip {
+            query.push_str("&ip=");
+            query.push_str(&urlencode(ip.to_string().as_bytes()));
+        }
+        if self.no_peer_id {
+            query.push_str("&no_peer_id=1");
+        }
+        query
+    }
+}
+
+pub(crate) async fn announce(
+    announce: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse> {
+    if announce.starts_with("udp://") {
+        let mut client = UdpTrackerClient::connect(announce).await?;
+        return client.announce(request).await;
+    }
+    let mut url = Url::parse(announce)?;
+    append_query(&mut url, &request.query());
+
+    let client = Client::new();
+    let res = client.get(url).send().await?.bytes().await?;
+    let decoded = bencode::decode(&res)?;
+    parse_announce_response(&decoded)
+}
+
+pub(crate) async fn discover_peers(
+    torrent: &MetaData,
+    config: &ClientConfig,
+) -> Result<AnnounceResponse> {
+    announce(&torrent.announce, &AnnounceRequest::new(torrent, config)?).await
+}
+
+/// Derives the scrape URL from an HTTP announce URL: the last path segment
+/// must start with `announce`, which is replaced by `scrape`.
+pub(crate) fn scrape_url(announce: &str) -> Result<Url> {
     let mut url = Url::parse(announce)?;
-    let encoded_hash = urlencode(&torrent.raw().info_hash_u8()?).to_string();
-    //  println!("{:#?}", torrent.raw().info_hash_u8()?);
-    //  println!("{:#?}", encoded_hash);
-    let peer_id = "00112233445566778892";
-    let port = "6881";
-    let uploaded = "0";
-    let downloaded = "0";
-    let left = torrent.info.length.to_string(); // Assuming this is how you get the length
-
-    let query = format!(
-        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
-        encoded_hash, peer_id, port, uploaded, downloaded, left
-    );
+    let path = url.path().to_owned();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", &path));
+    let rest = last
+        .strip_prefix("announce")
+        .ok_or(anyhow!("Tracker {} does not support scraping.", announce))?;
+    url.set_path(&format!("{}/scrape{}", dir, rest));
+    Ok(url)
+}
 
+/// Adds `query` after any query the tracker URL already has, such as a
+/// private tracker's passkey.
+fn append_query(url: &mut Url, query: &str) {
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{}&{}", existing, query),
+        _ => query.to_owned(),
+    };
     url.set_query(Some(&query));
+}
 
-    // println!("{:#?}", url);
+/// Scrapes several torrents from the same tracker in one request, over
+/// HTTP(S) or UDP depending on the announce URL. Results are in the order of
+/// `info_hashes`; torrents the tracker doesn't know report zeroes.
+pub(crate) async fn scrape(announce: &str, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+    if announce.starts_with("udp://") {
+        let mut client = UdpTrackerClient::connect(announce).await?;
+        return client.scrape(info_hashes).await;
+    }
+    let mut url = scrape_url(announce)?;
+    let query = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", urlencode(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&");
+    append_query(&mut url, &query);
 
     let client = Client::new();
     let res = client.get(url).send().await?.bytes().await?;
-    // let res = get(url)?.bytes()?;
-    // println!("{:#?}", res);
-    // println!("Started decoding response.");
-    let decoded = bencode::decode(&res)?;
-    // println!("{:#?}", decoded);
-
-    let peers: Option<Vec<(String, u16)>> = match decoded {
-        bencode::Dict(ref dict) => {
-            let peers: Option<Vec<(String, u16)>> = match &dict["peers"] {
-                Value::Str(peers) => {
-                    let parsed_peers: Vec<(String, u16)> = peers
-                        .chunks(6)
-                        .map(|chunk| {
-                            let ip =
-                                format!("{}.{}.{}.{}", &chunk[0], &chunk[1], &chunk[2], &chunk[3]);
-                            let port: u16 = chunk[5] as u16 | ((chunk[4] as u16) << 8u16);
-                            (ip, port)
-                        })
-                        .collect();
-                    Some(parsed_peers)
-                }
-                _ => None,
+    let files = parse_scrape_response(&bencode::decode(&res)?)?;
+    Ok(info_hashes
+        .iter()
+        .map(|info_hash| files.get(info_hash).copied().unwrap_or_default())
+        .collect())
+}
+
+pub(crate) fn parse_scrape_response(decoded: &Value) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    if let Some(reason) = decoded.get("failure reason").and_then(Value::as_bytes) {
+        return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()).into());
+    }
+    let files = decoded
+        .get("files")
+        .and_then(Value::as_dict)
+        .ok_or(anyhow!("Missing or invalid 'files' in scrape response."))?;
+    files
+        .iter()
+        .map(|(info_hash, stats)| {
+            let info_hash: [u8; 20] = info_hash
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow!("Scrape response key is not a 20 byte info hash."))?;
+            let field = |key| {
+                stats
+                    .get(key)
+                    .and_then(Value::as_int)
+                    .and_then(|n| u64::try_from(n).ok())
+                    .unwrap_or(0)
             };
-            peers
-        }
-        _ => None,
+            Ok((
+                info_hash,
+                ScrapeStats {
+                    complete: field("complete"),
+                    downloaded: field("downloaded"),
+                    incomplete: field("incomplete"),
+                },
+            ))
+        })
+        .collect()
+}
+
+pub(crate) fn parse_announce_response(decoded: &Value) -> Result<AnnounceResponse> {
+    let dict = match decoded {
+        bencode::Dict(dict) => dict,
+        _ => return Err(anyhow!("Expected tracker response to be a dictionary.")),
+    };
+
+    if let Some(reason) = dict_str(dict, "failure reason") {
+        return Err(TrackerError::Failure(reason).into());
+    }
+
+    let mut peers = match dict.get("peers".as_bytes()) {
+        Some(Value::Str(compact)) => parse_compact_peers(compact, false)?,
+        Some(Value::List(list)) => parse_peer_list(list)?,
+        Some(_) => return Err(anyhow!("Invalid 'peers' field in tracker response.")),
+        None => Vec::new(),
     };
-    //   println!("{:#?}", peers);
-    peers.ok_or(anyhow!("Failed to parse peers into ip and port."))
+    match dict.get("peers6".as_bytes()) {
+        Some(Value::Str(compact)) => peers.extend(parse_compact_peers(compact, true)?),
+        Some(_) => return Err(anyhow!("Invalid 'peers6' field in tracker response.")),
+        None => {}
+    }
+
+    Ok(AnnounceResponse {
+        peers,
+        warning: dict_str(dict, "warning message"),
+        interval: dict_u64(dict, "interval"),
+        min_interval: dict_u64(dict, "min interval"),
+        tracker_id: dict_bytes(dict, "tracker id"),
+        complete: dict_u64(dict, "complete"),
+        incomplete: dict_u64(dict, "incomplete"),
+    })
+}
+
+fn dict_str(dict: &HashMap<Vec<u8>, Value>, key: &str) -> Option<String> {
+    dict_bytes(dict, key).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn dict_bytes(dict: &HashMap<Vec<u8>, Value>, key: &str) -> Option<Vec<u8>> {
+    dict.get(key.as_bytes()).and_then(|value| match value {
+        Value::Str(bytes) => Some(bytes.clone()),
+        _ => None,
+    })
+}
+
+fn dict_u64(dict: &HashMap<Vec<u8>, Value>, key: &str) -> Option<u64> {
+    dict.get(key.as_bytes()).and_then(|value| match value {
+        Value::Int(n) => u64::try_from(*n).ok(),
+        _ => None,
+    })
+}
+
+/// Parses the compact peer format: 6 bytes per IPv4 peer, or 18 bytes per
+/// IPv6 peer as used by `peers6` (BEP 7).
+pub(crate) fn parse_compact_peers(bytes: &[u8], ipv6: bool) -> Result<Vec<Peer>> {
+    let ip_len = if ipv6 { 16 } else { 4 };
+    if !bytes.len().is_multiple_of(ip_len + 2) {
+        return Err(anyhow!(
+            "Compact peer list length {} is not a multiple of {}.",
+            bytes.len(),
+            ip_len + 2
+        ));
+    }
+    Ok(bytes
+        .chunks(ip_len + 2)
+        .map(|chunk| {
+            let ip = if ipv6 {
+                let octets: [u8; 16] = chunk[..16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+            };
+            let port = u16::from_be_bytes([chunk[ip_len], chunk[ip_len + 1]]);
+            Peer::new(SocketAddr::new(ip, port))
+        })
+        .collect())
+}
+
+/// Parses the original, non-compact form: a list of dictionaries with
+/// `ip`, `port` and optionally `peer id`.
+fn parse_peer_list(list: &[Value]) -> Result<Vec<Peer>> {
+    list.iter()
+        .map(|entry| {
+            let dict = match entry {
+                Value::Dict(dict) => dict,
+                _ => return Err(anyhow!("Expected peer entry to be a dictionary.")),
+            };
+            let ip = dict
+                .get("ip".as_bytes())
+                .and_then(|ip| match ip {
+                    Value::Str(ip) => std::str::from_utf8(ip).ok(),
+                    _ => None,
+                })
+                .ok_or(anyhow!("Missing or invalid peer 'ip'"))?
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("Peer 'ip' is not an IP address."))?;
+            let port = dict
+                .get("port".as_bytes())
+                .and_then(|port| match port {
+                    Value::Int(port) => u16::try_from(*port).ok(),
+                    _ => None,
+                })
+                .ok_or(anyhow!("Missing or invalid peer 'port'"))?;
+            let peer_id = dict.get("peer id".as_bytes()).and_then(|id| match id {
+                Value::Str(id) => <[u8; 20]>::try_from(id.as_slice()).ok(),
+                _ => None,
+            });
+            Ok(Peer {
+                addr: SocketAddr::new(ip, port),
+                peer_id,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_compact_ipv4_and_ipv6() {
+        let mut body = b"d5:peers6:".to_vec();
+        body.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        body.extend_from_slice(b"6:peers618:");
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&[0x1a, 0xe2]);
+        body.push(b'e');
+
+        let response = parse_announce_response(&bencode::decode(&body).unwrap()).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![
+                Peer::new("127.0.0.1:6881".parse().unwrap()),
+                Peer::new("[::1]:6882".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_non_compact_peer_list() {
+        let body = b"d5:peersld2:ip8:10.0.0.17:peer id20:-XX0001-0123456789ab4:porti51413eed2:ip3:::14:porti6881eeee";
+        let response = parse_announce_response(&bencode::decode(body).unwrap()).unwrap();
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(response.peers[0].addr, "10.0.0.1:51413".parse().unwrap());
+        assert_eq!(response.peers[0].peer_id, Some(*b"-XX0001-0123456789ab"));
+        assert_eq!(response.peers[1].addr, "[::1]:6881".parse().unwrap());
+        assert_eq!(response.peers[1].peer_id, None);
+    }
+
+    #[test]
+    fn parse_announce_metadata() {
+        let body = b"d8:completei12e10:incompletei3e8:intervali1800e12:min intervali60e5:peers0:10:tracker id3:a\xffc15:warning message4:slowe";
+        let response = parse_announce_response(&bencode::decode(body).unwrap()).unwrap();
+        assert_eq!(response.complete, Some(12));
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.tracker_id.as_deref(), Some(&b"a\xffc"[..]));
+        assert_eq!(response.warning.as_deref(), Some("slow"));
+        assert!(response.peers.is_empty());
+    }
+
+    #[test]
+    fn parse_failure_reason() {
+        let body = b"d14:failure reason17:unregistered hashe";
+        let err = parse_announce_response(&bencode::decode(body).unwrap()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure("unregistered hash".to_owned()))
+        );
+    }
+
+    #[test]
+    fn announce_query_includes_event_and_tracker_id() {
+        let request = AnnounceRequest {
+            info_hash: [0xab; 20],
+            peer_id: *b"-XX0001-0123456789ab",
+            port: 6881,
+            uploaded: 10,
+            downloaded: 20,
+            left: 30,
+            event: Some(AnnounceEvent::Started),
+            tracker_id: Some(b"t \xff1".to_vec()),
+            numwant: Some(50),
+            key: Some("k".to_owned()),
+            ip: Some("::1".parse().unwrap()),
+            no_peer_id: true,
+        };
+        let query = request.query();
+        assert!(query.contains("uploaded=10&downloaded=20&left=30"));
+        assert!(query.contains("&event=started&trackerid=t%20%ff1"));
+        assert!(query.ends_with("&numwant=50&key=k&ip=%3a%3a1&no_peer_id=1"));
+    }
+
+    #[test]
+    fn scrape_url_follows_convention() {
+        let scrape = |announce| scrape_url(announce).map(|url| url.to_string()).ok();
+        assert_eq!(
+            scrape("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce.php?passkey=1").as_deref(),
+            Some("http://example.com/x/scrape.php?passkey=1")
+        );
+        assert_eq!(
+            scrape("http://example.com/announce?x2%0644").as_deref(),
+            Some("http://example.com/scrape?x2%0644")
+        );
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn tracker_queries_keep_the_passkey() {
+        let mut url = Url::parse("http://example.com/announce?passkey=1").unwrap();
+        append_query(&mut url, "info_hash=%ab&left=0");
+        assert_eq!(
+            url.as_str(),
+            "http://example.com/announce?passkey=1&info_hash=%ab&left=0"
+        );
+        let mut url = Url::parse("http://example.com/scrape").unwrap();
+        append_query(&mut url, "info_hash=%ab");
+        assert_eq!(url.as_str(), "http://example.com/scrape?info_hash=%ab");
+    }
+
+    #[test]
+    fn parse_scrape_binary_keys() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[0xff; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let files = parse_scrape_response(&bencode::decode(&body).unwrap()).unwrap();
+        assert_eq!(
+            files.get(&[0xff; 20]),
+            Some(&ScrapeStats {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10
+            })
+        );
+    }
+
+    #[test]
+    fn parse_compact_rejects_truncated_list() {
+        assert!(parse_compact_peers(&[127, 0, 0, 1, 0x1a], false).is_err());
+    }
 }
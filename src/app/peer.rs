@@ -1,54 +1,226 @@
-use crate::app::messages::Handshake;
-use crate::app::network::discover_peers;
+use crate::app::config::ClientConfig;
+use crate::app::connection::{PeerConnection, Transport};
+use crate::app::messages::BTMessageFramer;
+use crate::app::mse::{self, EncryptionPolicy, MseStream, MSE_TIMEOUT};
+use crate::app::network::Peer;
+use crate::app::peer_source::{MergedPeers, PeerSource, StaticPeers};
+use crate::app::pex::{PexSender, PexSource, MAX_ACCEPTED_PER_MINUTE};
 use crate::app::tracker::MetaData;
+use crate::app::utp::UtpSocket;
 use anyhow::{anyhow, Result};
+use futures::StreamExt;
 
-
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{self};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::codec::Framed;
 
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncSeekExt;
+
+/// How long to wait for a uTP connection before trying TCP.
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long connecting, encryption and the handshake may take together.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
 pub struct PeerManager {
-    peers: Vec<(String, u16)>,
+    candidates: MergedPeers,
     pub torrent: MetaData,
+    config: ClientConfig,
     handshake_received: bool,
+    utp: Option<Arc<UtpSocket>>,
 }
 
 impl PeerManager {
-    pub(crate) async fn new(torrent: MetaData) -> Result<Self> {
-        let peers = discover_peers(&torrent).await?;
-        // println!("Piece len {} total {}", torrent.info.piece_length, torrent.info.length);
-        Ok(Self {
-            peers,
+    /// Starts with the `--peer` addresses from `config`; further candidates
+    /// come from sources added with [`PeerManager::add_source`].
+    pub(crate) fn new(torrent: MetaData, config: ClientConfig) -> Self {
+        let mut candidates = MergedPeers::new();
+        if !config.peers.is_empty() {
+            candidates.add(Box::new(StaticPeers::new("--peer", config.peers.clone())));
+        }
+        Self {
+            candidates,
             torrent,
+            config,
             handshake_received: false,
+            utp: None,
+        }
+    }
+
+    pub(crate) fn add_source(&mut self, source: Box<dyn PeerSource>) {
+        self.candidates.add(source);
+    }
+
+    /// Next not yet seen candidate from any source.
+    pub(crate) async fn next_candidate(&mut self) -> Option<Peer> {
+        self.candidates.next().await
+    }
+
+    /// Whether every source except PEX has ended, so no new candidates
+    /// can come without live connections.
+    pub(crate) fn exhausted(&self) -> bool {
+        self.candidates.exhausted()
+    }
+
+    /// Feeds peers learned through PEX into the candidates until the
+    /// returned sender and all its clones are dropped.
+    pub(crate) fn pex_sender(&mut self) -> PexSender {
+        let (source, sender) = PexSource::new(MAX_ACCEPTED_PER_MINUTE);
+        self.candidates.add_follower(Box::new(source));
+        sender
+    }
+
+    /// The uTP socket, bound on first use to our port or, if that is
+    /// taken, any port. `None` when uTP is off or cannot be used. It only
+    /// dials out: without a listener, incoming SYNs are reset.
+    async fn utp_socket(&mut self) -> Option<Arc<UtpSocket>> {
+        if self.config.utp && self.utp.is_none() {
+            let any = |port| SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+            let socket = match UtpSocket::bind(any(self.config.port)).await {
+                Ok(socket) => Ok(socket),
+                Err(_) => UtpSocket::bind(any(0)).await,
+            };
+            match socket {
+                Ok(socket) => self.utp = Some(Arc::new(socket)),
+                Err(e) => {
+                    log::warn!("uTP unavailable: {}", e);
+                    self.config.utp = false;
+                }
+            }
+        }
+        self.utp.clone()
+    }
+
+    /// A handle that opens connections to this torrent's peers without
+    /// borrowing the manager, so several can be in progress at once.
+    pub(crate) async fn connector(&mut self) -> Result<Connector> {
+        let info_hash = self
+            .torrent
+            .raw()
+            .info_hash_u8()?
+            .try_into()
+            .map_err(|_| anyhow!("Info hash must be 20 bytes."))?;
+        Ok(Connector {
+            config: self.config.clone(),
+            info_hash,
+            utp: self.utp_socket().await,
+            timeout: CONNECT_TIMEOUT,
         })
     }
 
-    pub(crate) async fn connect_to_peer(&mut self) -> Result<TcpStream> {
-        let (peer_ip, peer_port) = self
-            .peers
-            .first()
-            .ok_or(anyhow!("Failed to get first peer"))?;
-        let handshake =
-            Handshake::new(b"00112233445566778899", &self.torrent.raw().info_hash_u8()?);
-        let stream = connect_to_peer((peer_ip, *peer_port), handshake).await;
-        let (data, stream) = read_exact_bytes(stream?, 68).await?;
-        let peer_handshake = Handshake::deserialize(&data[..68]);
-        //println!("Received peer handshake: {}", peer_handshake);
-        println!("Peer ID: {}", peer_handshake.peer_id());
-        self.handshake_received = true;
-        Ok(stream)
+    /// Connects to candidates in the order sources produce them until one
+    /// completes the handshake.
+    pub(crate) async fn connect_to_peer(
+        &mut self,
+    ) -> Result<Framed<MseStream<Transport>, BTMessageFramer>> {
+        Ok(self.connect().await?.framed)
+    }
+
+    /// Like [`PeerManager::connect_to_peer`], also returning the peer's
+    /// handshake and the capabilities both sides advertised.
+    pub(crate) async fn connect(&mut self) -> Result<PeerConnection<MseStream<Transport>>> {
+        let connector = self.connector().await?;
+        while let Some(peer) = self.next_candidate().await {
+            match connector.connect(&peer).await {
+                Ok(connection) => {
+                    self.handshake_received = true;
+                    return Ok(connection);
+                }
+                Err(e) => log::warn!("Peer {} failed: {}", peer.addr, e),
+            }
+        }
+        Err(anyhow!("Ran out of peers to connect to"))
+    }
+}
+
+/// Opens and handshakes peer connections for one torrent.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    config: ClientConfig,
+    info_hash: [u8; 20],
+    utp: Option<Arc<UtpSocket>>,
+    timeout: Duration,
+}
+
+impl Connector {
+    pub(crate) async fn connect(
+        &self,
+        peer: &Peer,
+    ) -> Result<PeerConnection<MseStream<Transport>>> {
+        time::timeout(self.timeout, self.handshake(peer))
+            .await
+            .map_err(|_| anyhow!("Connecting to peer {} timed out", peer.addr))?
+    }
+
+    async fn handshake(&self, peer: &Peer) -> Result<PeerConnection<MseStream<Transport>>> {
+        let policy = self.config.encryption;
+        let stream = open(peer.addr, &self.info_hash, policy, self.utp.as_deref()).await?;
+        let encrypted = stream.is_encrypted();
+        let connection = PeerConnection::outgoing(stream, &self.config, self.info_hash).await?;
+        log::debug!(
+            "Peer {} supports [{}], encrypted: {}",
+            peer.addr,
+            connection.capabilities,
+            encrypted
+        );
+        Ok(connection)
     }
 }
 
+/// Connects to `address`, encrypting as `policy` asks. With encryption
+/// merely enabled, a peer that fails MSE is retried in plaintext.
+async fn open(
+    address: SocketAddr,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+    utp: Option<&UtpSocket>,
+) -> Result<MseStream<Transport>> {
+    let stream = dial(address, utp).await?;
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(MseStream::plaintext(stream));
+    }
+    let error = match time::timeout(MSE_TIMEOUT, mse::initiate(stream, info_hash, policy)).await {
+        Ok(Ok(stream)) => return Ok(stream),
+        Ok(Err(e)) => e,
+        Err(_) => anyhow!("Encryption handshake timed out"),
+    };
+    if policy == EncryptionPolicy::Forced {
+        return Err(error);
+    }
+    log::debug!(
+        "Peer {} refused encryption ({}), retrying in plaintext",
+        address,
+        error
+    );
+    Ok(MseStream::plaintext(dial(address, utp).await?))
+}
+
+/// Tries uTP first when enabled, then TCP.
+async fn dial(address: SocketAddr, utp: Option<&UtpSocket>) -> Result<Transport> {
+    if let Some(utp) = utp {
+        match time::timeout(UTP_CONNECT_TIMEOUT, utp.connect(address)).await {
+            Ok(Ok(stream)) => return Ok(Transport::Utp(stream)),
+            Ok(Err(e)) => log::debug!("{}, trying TCP", e),
+            Err(_) => log::debug!("uTP connect to {} timed out, trying TCP", address),
+        }
+    }
+    let stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to peer {}: {}", address, e))?;
+    Ok(Transport::Tcp(stream))
+}
+
 pub(crate) async fn write_at_offset(file_path: &str, offset: u64, data: &[u8]) -> io::Result<()> {
     // Open or create the file with write and read access
     let file = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .open(file_path)
         .await?;
 
@@ -63,24 +235,46 @@ pub(crate) async fn write_at_offset(file_path: &str, offset: u64, data: &[u8]) -
     Ok(())
 }
 
-// Now the function takes the stream by value and returns it along with the read data
-async fn read_exact_bytes(mut stream: TcpStream, num_bytes: usize) -> Result<(Vec<u8>, TcpStream)> {
-    let mut buffer = vec![0u8; num_bytes];
-    stream.read_exact(&mut buffer).await?;
-    Ok((buffer, stream)) // Return both the buffer and the stream
+pub(crate) async fn read_at_offset(
+    file_path: &str,
+    offset: u64,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut file = OpenOptions::new().read(true).open(file_path).await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut data = vec![0u8; len];
+    file.read_exact(&mut data).await?;
+    Ok(data)
 }
 
-pub async fn connect_to_peer(peer: (&str, u16), handshake: Handshake) -> Result<TcpStream> {
-    let (ip, port) = peer;
-    let address = format!("{}:{}", ip, port);
-    let mut stream = TcpStream::connect(&address)
-        .await
-        .map_err(|e| anyhow!("Failed to connect to peer {}: {}", address, e))?;
-    let bytes = &handshake.serialize();
-    stream
-        .write_all(bytes)
-        .await
-        .map_err(|e| anyhow!("Failed to write handshake to peer {}: {}", address, e))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
 
-    Ok(stream)
+    #[tokio::test]
+    async fn connect_gives_up_on_a_silent_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accepts the connection, then never answers the handshake.
+        let silent = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let connector = Connector {
+            config: ClientConfig {
+                encryption: EncryptionPolicy::Disabled,
+                utp: false,
+                ..Default::default()
+            },
+            info_hash: [1; 20],
+            utp: None,
+            timeout: Duration::from_millis(200),
+        };
+        let attempt = time::timeout(Duration::from_secs(5), connector.connect(&Peer::new(addr)))
+            .await
+            .unwrap();
+        match attempt {
+            Ok(_) => panic!("Handshake without a peer"),
+            Err(e) => assert!(e.to_string().contains("timed out"), "{}", e),
+        }
+        silent.await.unwrap();
+    }
 }
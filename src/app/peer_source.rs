@@ -0,0 +1,351 @@
+use crate::app::dht::DhtNode;
+use crate::app::magnet::MagnetLink;
+use crate::app::network::{AnnounceEvent, Peer};
+use crate::app::tracker_client::{TrackerClient, TrackerHandle};
+use futures::stream::{self, BoxStream, SelectAll, Stream, StreamExt};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+pub type PeerStream = BoxStream<'static, Peer>;
+
+/// Anything that can come up with candidate peers for a torrent.
+pub trait PeerSource: Send {
+    /// Short description for logs, e.g. the tracker URL.
+    fn name(&self) -> String;
+
+    /// Turns the source into a stream of candidates. The stream ends when the
+    /// source has nothing more to offer.
+    fn peers(self: Box<Self>) -> PeerStream;
+}
+
+/// A fixed list of addresses, from `--peer` options or magnet `x.pe` hints.
+pub struct StaticPeers {
+    name: String,
+    addrs: Vec<SocketAddr>,
+}
+
+impl StaticPeers {
+    pub fn new(name: &str, addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            name: name.to_owned(),
+            addrs,
+        }
+    }
+
+    pub fn from_magnet(magnet: &MagnetLink) -> Self {
+        Self::new("magnet x.pe", magnet.peers.clone())
+    }
+}
+
+impl PeerSource for StaticPeers {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn peers(self: Box<Self>) -> PeerStream {
+        stream::iter(self.addrs.into_iter().map(Peer::new)).boxed()
+    }
+}
+
+enum TrackerPeers {
+    /// Announce once, end the stream with its reply, then stop.
+    Once(Box<TrackerClient>),
+    /// Follow a spawned client's periodic announces until it stops.
+    Background(mpsc::UnboundedReceiver<Vec<Peer>>),
+}
+
+/// Peers from an HTTP or UDP tracker.
+pub struct TrackerSource {
+    name: String,
+    peers: TrackerPeers,
+}
+
+impl TrackerSource {
+    /// A source that sends a single `started` announce, and `stopped`
+    /// when its peers are used up or the stream is dropped.
+    pub fn once(client: TrackerClient) -> Self {
+        Self {
+            name: client.announce_url().to_owned(),
+            peers: TrackerPeers::Once(Box::new(client)),
+        }
+    }
+
+    /// Spawns the client's announce loop; the handle reports completion and
+    /// stops it.
+    pub fn spawn(client: TrackerClient) -> (Self, TrackerHandle) {
+        let name = client.announce_url().to_owned();
+        let (handle, peers) = client.spawn();
+        (
+            Self {
+                name,
+                peers: TrackerPeers::Background(peers),
+            },
+            handle,
+        )
+    }
+}
+
+impl PeerSource for TrackerSource {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn peers(self: Box<Self>) -> PeerStream {
+        let name = self.name;
+        match self.peers {
+            TrackerPeers::Once(client) => {
+                stream::unfold(
+                    (Stopper(Some(client)), false),
+                    move |(mut stopper, started)| {
+                        let name = name.clone();
+                        async move {
+                            if started {
+                                stopper.stop().await;
+                                return None;
+                            }
+                            let client = stopper.0.as_mut()?;
+                            let peers = match client.announce(Some(AnnounceEvent::Started)).await {
+                                Ok(response) => response.peers,
+                                Err(e) => {
+                                    log::warn!("Announce to {} failed: {}", name, e);
+                                    // Nothing to stop.
+                                    stopper.0 = None;
+                                    Vec::new()
+                                }
+                            };
+                            Some((peers, (stopper, true)))
+                        }
+                    },
+                )
+                .flat_map(stream::iter)
+                .boxed()
+            }
+            TrackerPeers::Background(mut peers) => stream::poll_fn(move |cx| peers.poll_recv(cx))
+                .flat_map(stream::iter)
+                .boxed(),
+        }
+    }
+}
+
+/// Sends `stopped` for a one-shot announce once its peers are used up, or
+/// in the background if the stream is dropped before that.
+struct Stopper(Option<Box<TrackerClient>>);
+
+impl Stopper {
+    async fn stop(&mut self) {
+        if let Some(mut client) = self.0.take() {
+            if let Err(e) = client.announce(Some(AnnounceEvent::Stopped)).await {
+                log::warn!("Failed to send stopped announce: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Stopper {
+    fn drop(&mut self) {
+        let Some(mut client) = self.0.take() else {
+            return;
+        };
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = client.announce(Some(AnnounceEvent::Stopped)).await {
+                    log::warn!("Failed to send stopped announce: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Peers found by a DHT lookup for one info hash.
+pub struct DhtSource {
+    node: Arc<DhtNode>,
+    info_hash: [u8; 20],
+}
+
+impl DhtSource {
+    pub fn new(node: Arc<DhtNode>, info_hash: [u8; 20]) -> Self {
+        Self { node, info_hash }
+    }
+}
+
+impl PeerSource for DhtSource {
+    fn name(&self) -> String {
+        "DHT".to_owned()
+    }
+
+    fn peers(self: Box<Self>) -> PeerStream {
+        stream::once(async move { self.node.lookup_peers(self.info_hash).await })
+            .flat_map(stream::iter)
+            .map(Peer::new)
+            .boxed()
+    }
+}
+
+/// Merges several sources, yielding each address only the first time any
+/// source reports it.
+pub struct MergedPeers {
+    sources: SelectAll<PeerStream>,
+    /// Sources fed by live connections, such as PEX.
+    followers: SelectAll<PeerStream>,
+    /// Whether every source other than the followers has ended.
+    exhausted: bool,
+    seen: HashSet<SocketAddr>,
+}
+
+impl MergedPeers {
+    pub fn new() -> Self {
+        Self {
+            sources: SelectAll::new(),
+            followers: SelectAll::new(),
+            exhausted: false,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn add(&mut self, source: Box<dyn PeerSource>) {
+        log::debug!("Adding peer source {}", source.name());
+        self.sources.push(source.peers());
+        self.exhausted = false;
+    }
+
+    /// Adds a source that only passes on peers learned from our
+    /// connections. It stays open while they do, so it does not count
+    /// when deciding whether the candidates have run out.
+    pub fn add_follower(&mut self, source: Box<dyn PeerSource>) {
+        log::debug!("Adding peer source {}", source.name());
+        self.followers.push(source.peers());
+    }
+
+    /// Whether the sources other than the followers have all ended. Only
+    /// known once the stream has been polled after their last peer.
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+}
+
+impl Default for MergedPeers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MergedPeers {
+    type Item = Peer;
+
+    /// Ends once the sources and the followers have all ended.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Peer>> {
+        loop {
+            let peer = match self.sources.poll_next_unpin(cx) {
+                Poll::Ready(Some(peer)) => Some(peer),
+                Poll::Ready(None) => {
+                    self.exhausted = true;
+                    None
+                }
+                Poll::Pending => None,
+            };
+            let peer = match peer {
+                Some(peer) => peer,
+                None => match self.followers.poll_next_unpin(cx) {
+                    Poll::Ready(Some(peer)) => peer,
+                    Poll::Ready(None) if self.exhausted => return Poll::Ready(None),
+                    _ => return Poll::Pending,
+                },
+            };
+            if self.seen.insert(peer.addr) {
+                return Poll::Ready(Some(peer));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::config::ClientConfig;
+    use crate::app::network::AnnounceRequest;
+    use crate::app::pex::PexSource;
+    use crate::app::tracker_client::TransferStats;
+    use crate::app::tracker_server::{serve_http, AnnounceParams, SwarmRegistry, TrackerConfig};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn merged_sources_are_deduplicated() {
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:2".parse().unwrap();
+        let c: SocketAddr = "[::1]:3".parse().unwrap();
+        let mut merged = MergedPeers::new();
+        merged.add(Box::new(StaticPeers::new("cli", vec![a, b])));
+        merged.add(Box::new(StaticPeers::new("magnet", vec![b, c, a])));
+        let mut addrs: Vec<SocketAddr> = merged.map(|peer| peer.addr).collect().await;
+        addrs.sort();
+        assert_eq!(addrs, vec![a, b, c]);
+    }
+
+    #[tokio::test]
+    async fn followers_do_not_keep_candidates_from_running_out() {
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let (pex, sender) = PexSource::new(10);
+        let mut merged = MergedPeers::new();
+        merged.add(Box::new(StaticPeers::new("cli", vec![a])));
+        merged.add_follower(Box::new(pex));
+        assert_eq!(merged.next().await.unwrap().addr, a);
+        assert!(!merged.exhausted());
+        let next = tokio::time::timeout(Duration::from_millis(50), merged.next()).await;
+        assert!(next.is_err(), "Ended while PEX is open");
+        assert!(merged.exhausted());
+        drop(sender);
+        assert!(merged.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn one_shot_tracker_source_sends_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let registry = Arc::new(SwarmRegistry::new(TrackerConfig::default()));
+        tokio::spawn(serve_http(listener, registry.clone()));
+        let info_hash = [4; 20];
+        let other = "10.0.0.1:6881".parse().unwrap();
+        registry
+            .announce(&AnnounceParams {
+                info_hash,
+                peer_id: [1; 20],
+                addr: other,
+                left: 0,
+                event: None,
+                numwant: None,
+            })
+            .unwrap();
+        let source = || {
+            let request = AnnounceRequest::from_info_hash(info_hash, 100, &ClientConfig::default());
+            let stats = Arc::new(TransferStats::new(100));
+            Box::new(TrackerSource::once(TrackerClient::from_request(
+                &url, request, stats,
+            )))
+            .peers()
+        };
+        let leechers = || registry.scrape(&info_hash).incomplete;
+
+        // Used up: `stopped` goes out before the stream ends.
+        let peers: Vec<SocketAddr> = source().map(|peer| peer.addr).collect().await;
+        assert_eq!(peers, vec![other]);
+        assert_eq!(leechers(), 0);
+
+        // Dropped early: `stopped` follows in the background.
+        let mut peers = source();
+        assert_eq!(peers.next().await.unwrap().addr, other);
+        assert_eq!(leechers(), 1);
+        drop(peers);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while leechers() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,494 @@
+use crate::app::bencode::{self, Value};
+use crate::app::extension::ExtensionHandler;
+use crate::app::network::{parse_compact_peers, Peer};
+use crate::app::peer_source::{PeerSource, PeerStream};
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Name of the extension in the BEP 10 `m` dictionary.
+pub const UT_PEX: &str = "ut_pex";
+/// BEP 11 allows at most one PEX message per minute to each peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// BEP 11 caps the `added` and `dropped` lists at 50 entries each.
+const MAX_PEX_PEERS: usize = 50;
+/// Peers accepted from PEX per minute, across all connections.
+pub const MAX_ACCEPTED_PER_MINUTE: usize = 100;
+
+/// Peers we are connected to with their flags, shared by every
+/// connection's PEX handler.
+pub type ConnectedPeers = Arc<Mutex<HashMap<SocketAddr, u8>>>;
+
+/// Bits of the `added.f` / `added6.f` flag bytes.
+#[allow(dead_code)]
+pub mod flags {
+    pub const PREFERS_ENCRYPTION: u8 = 0x01;
+    pub const SEED: u8 = 0x02;
+    pub const SUPPORTS_UTP: u8 = 0x04;
+    pub const SUPPORTS_HOLEPUNCH: u8 = 0x08;
+    /// We connected to the peer ourselves, so it accepts connections.
+    pub const REACHABLE: u8 = 0x10;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+/// Peers the sender connected to or lost since its previous message.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let dict = bencode::decode(payload)?;
+        let mut message = Self::default();
+        for (key, flags_key, ipv6) in [("added", "added.f", false), ("added6", "added6.f", true)] {
+            let flags = dict.get(flags_key).and_then(Value::as_bytes).unwrap_or(&[]);
+            let added = compact_peers(&dict, key, ipv6)?;
+            message
+                .added
+                .extend(added.into_iter().enumerate().map(|(i, addr)| PexPeer {
+                    addr,
+                    flags: flags.get(i).copied().unwrap_or(0),
+                }));
+        }
+        message.dropped = compact_peers(&dict, "dropped", false)?;
+        message
+            .dropped
+            .extend(compact_peers(&dict, "dropped6", true)?);
+        Ok(message)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (mut added, mut added_flags) = (Vec::new(), Vec::new());
+        let (mut added6, mut added6_flags) = (Vec::new(), Vec::new());
+        for peer in &self.added {
+            if peer.addr.is_ipv4() {
+                encode_compact(&peer.addr, &mut added);
+                added_flags.push(peer.flags);
+            } else {
+                encode_compact(&peer.addr, &mut added6);
+                added6_flags.push(peer.flags);
+            }
+        }
+        let (mut dropped, mut dropped6) = (Vec::new(), Vec::new());
+        for addr in &self.dropped {
+            let target = if addr.is_ipv4() {
+                &mut dropped
+            } else {
+                &mut dropped6
+            };
+            encode_compact(addr, target);
+        }
+        bencode::to_vec_u8(&Value::dict([
+            ("added", Value::Str(added)),
+            ("added.f", Value::Str(added_flags)),
+            ("added6", Value::Str(added6)),
+            ("added6.f", Value::Str(added6_flags)),
+            ("dropped", Value::Str(dropped)),
+            ("dropped6", Value::Str(dropped6)),
+        ]))
+    }
+}
+
+fn compact_peers(dict: &Value, key: &str, ipv6: bool) -> Result<Vec<SocketAddr>> {
+    match dict.get(key).and_then(Value::as_bytes) {
+        Some(bytes) => Ok(parse_compact_peers(bytes, ipv6)?
+            .into_iter()
+            .map(|peer| peer.addr)
+            .collect()),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn encode_compact(addr: &SocketAddr, out: &mut Vec<u8>) {
+    match addr {
+        SocketAddr::V4(addr) => out.extend_from_slice(&addr.ip().octets()),
+        SocketAddr::V6(addr) => out.extend_from_slice(&addr.ip().octets()),
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// What we have told one peer so far, so each message only carries the
+/// difference.
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashMap<SocketAddr, u8>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    /// Builds the next message from the peers we are currently connected to,
+    /// or `None` if the last one went out less than [`PEX_INTERVAL`] ago or
+    /// nothing changed. Entries over the BEP 11 limit wait for a later round.
+    pub fn next_message(
+        &mut self,
+        connected: &HashMap<SocketAddr, u8>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last| now.duration_since(last) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .map(|(&addr, &flags)| PexPeer { addr, flags })
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .keys()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for peer in &added {
+            self.sent.insert(peer.addr, peer.flags);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(now);
+        Some(PexMessage { added, dropped })
+    }
+}
+
+/// Fixed one-minute window counting accepted peers.
+#[derive(Debug)]
+struct RateLimit {
+    per_minute: usize,
+    window_start: Instant,
+    accepted: usize,
+}
+
+impl RateLimit {
+    /// How many of `wanted` peers may still be accepted at `now`.
+    fn take(&mut self, wanted: usize, now: Instant) -> usize {
+        if now.duration_since(self.window_start) >= Duration::from_secs(60) {
+            self.window_start = now;
+            self.accepted = 0;
+        }
+        let allowed = wanted.min(self.per_minute - self.accepted);
+        self.accepted += allowed;
+        allowed
+    }
+}
+
+/// Hands peers learned from PEX messages to the [`PexSource`], dropping
+/// whatever exceeds the shared rate limit. Cheap to clone for each
+/// connection.
+#[derive(Clone)]
+pub struct PexSender {
+    peers: mpsc::UnboundedSender<Vec<Peer>>,
+    limit: Arc<Mutex<RateLimit>>,
+}
+
+impl PexSender {
+    /// Offers the `added` entries of a PEX message to the candidate pool and
+    /// returns how many were accepted.
+    pub fn offer(&self, added: &[PexPeer]) -> usize {
+        let usable: Vec<Peer> = added
+            .iter()
+            .filter(|peer| !peer.addr.ip().is_unspecified() && peer.addr.port() != 0)
+            .map(|peer| Peer::new(peer.addr))
+            .collect();
+        let allowed = self
+            .limit
+            .lock()
+            .unwrap()
+            .take(usable.len(), Instant::now());
+        if allowed > 0 {
+            let _ = self.peers.send(usable[..allowed].to_vec());
+        }
+        allowed
+    }
+}
+
+/// Peers other peers told us about. The stream ends once every
+/// [`PexSender`] is gone.
+pub struct PexSource {
+    peers: mpsc::UnboundedReceiver<Vec<Peer>>,
+}
+
+impl PexSource {
+    pub fn new(per_minute: usize) -> (Self, PexSender) {
+        let (sender, peers) = mpsc::unbounded_channel();
+        let limit = RateLimit {
+            per_minute,
+            window_start: Instant::now(),
+            accepted: 0,
+        };
+        (
+            Self { peers },
+            PexSender {
+                peers: sender,
+                limit: Arc::new(Mutex::new(limit)),
+            },
+        )
+    }
+}
+
+impl PeerSource for PexSource {
+    fn name(&self) -> String {
+        "PEX".to_owned()
+    }
+
+    fn peers(self: Box<Self>) -> PeerStream {
+        let mut peers = self.peers;
+        stream::poll_fn(move |cx| peers.poll_recv(cx))
+            .flat_map(stream::iter)
+            .boxed()
+    }
+}
+
+/// `ut_pex` on one connection: passes on what the peer tells us and tells
+/// it which other peers we are connected to.
+pub struct PexExtension {
+    sender: PexSender,
+    connected: ConnectedPeers,
+    peer: SocketAddr,
+    state: PexState,
+}
+
+impl PexExtension {
+    pub fn new(sender: PexSender, connected: ConnectedPeers, peer: SocketAddr) -> Self {
+        Self {
+            sender,
+            connected,
+            peer,
+            state: PexState::default(),
+        }
+    }
+}
+
+impl ExtensionHandler for PexExtension {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<()> {
+        let message = PexMessage::parse(payload)?;
+        let accepted = self.sender.offer(&message.added);
+        log::debug!(
+            "PEX from {}: {} added, {} dropped, {} accepted",
+            self.peer,
+            message.added.len(),
+            message.dropped.len(),
+            accepted
+        );
+        Ok(())
+    }
+
+    fn poll_message(&mut self, now: Instant) -> Result<Option<Vec<u8>>> {
+        let mut connected = self.connected.lock().unwrap().clone();
+        connected.remove(&self.peer);
+        self.state
+            .next_message(&connected, now)
+            .map(|message| message.to_bytes())
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::extension::{ExtensionRegistry, HANDSHAKE_ID};
+    use crate::app::messages::BTMessage;
+
+    fn peer(addr: &str, flags: u8) -> PexPeer {
+        PexPeer {
+            addr: addr.parse().unwrap(),
+            flags,
+        }
+    }
+
+    #[test]
+    fn message_round_trip_with_ipv6_and_flags() {
+        let message = PexMessage {
+            added: vec![
+                peer("10.0.0.1:6881", flags::SEED | flags::REACHABLE),
+                peer("[2001:db8::1]:51413", flags::SUPPORTS_UTP),
+            ],
+            dropped: vec!["10.0.0.2:6881".parse().unwrap(), "[::1]:1".parse().unwrap()],
+        };
+        let encoded = message.to_bytes().unwrap();
+        assert_eq!(PexMessage::parse(&encoded).unwrap(), message);
+
+        // Missing flags default to zero and absent keys to empty lists.
+        let bare = PexMessage::parse(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(bare.added, vec![peer("10.0.0.1:6881", 0)]);
+        assert!(bare.dropped.is_empty());
+        assert!(PexMessage::parse(b"d5:added5:\x0a\x00\x00\x01\x1ae").is_err());
+    }
+
+    #[test]
+    fn state_sends_differences_at_most_once_per_minute() {
+        let start = Instant::now();
+        let mut state = PexState::default();
+        let a = peer("10.0.0.1:1", flags::REACHABLE);
+        let b = peer("10.0.0.2:2", 0);
+        let mut connected = HashMap::from([(a.addr, a.flags), (b.addr, b.flags)]);
+
+        let first = state.next_message(&connected, start).unwrap();
+        assert_eq!(first.added.len(), 2);
+        assert!(first.dropped.is_empty());
+
+        connected.remove(&a.addr);
+        assert_eq!(
+            state.next_message(&connected, start + Duration::from_secs(59)),
+            None
+        );
+        let second = state
+            .next_message(&connected, start + PEX_INTERVAL)
+            .unwrap();
+        assert!(second.added.is_empty());
+        assert_eq!(second.dropped, vec![a.addr]);
+        assert_eq!(
+            state.next_message(&connected, start + 3 * PEX_INTERVAL),
+            None
+        );
+    }
+
+    #[test]
+    fn state_splits_large_changes_over_several_messages() {
+        let start = Instant::now();
+        let mut state = PexState::default();
+        let connected: HashMap<SocketAddr, u8> = (0..70u16)
+            .map(|port| (SocketAddr::from(([10, 0, 0, 1], port + 1)), 0))
+            .collect();
+        let first = state.next_message(&connected, start).unwrap();
+        assert_eq!(first.added.len(), MAX_PEX_PEERS);
+        let second = state
+            .next_message(&connected, start + PEX_INTERVAL)
+            .unwrap();
+        assert_eq!(second.added.len(), 20);
+    }
+
+    #[test]
+    fn rate_limit_resets_each_minute() {
+        let start = Instant::now();
+        let mut limit = RateLimit {
+            per_minute: 50,
+            window_start: start,
+            accepted: 0,
+        };
+        assert_eq!(limit.take(30, start), 30);
+        assert_eq!(limit.take(30, start + Duration::from_secs(10)), 20);
+        assert_eq!(limit.take(5, start + Duration::from_secs(59)), 0);
+        assert_eq!(limit.take(5, start + Duration::from_secs(60)), 5);
+    }
+
+    #[tokio::test]
+    async fn sender_feeds_source_up_to_the_limit() {
+        let (source, sender) = PexSource::new(2);
+        let offered = [
+            peer("10.0.0.1:1", 0),
+            peer("0.0.0.0:2", 0),
+            peer("10.0.0.3:3", 0),
+            peer("10.0.0.4:4", 0),
+        ];
+        assert_eq!(sender.offer(&offered), 2);
+        drop(sender);
+        let addrs: Vec<SocketAddr> = Box::new(source).peers().map(|p| p.addr).collect().await;
+        assert_eq!(addrs, vec![offered[0].addr, offered[2].addr]);
+    }
+
+    #[tokio::test]
+    async fn extension_exchanges_peers_but_not_the_recipient() {
+        let (source, sender) = PexSource::new(10);
+        let recipient: SocketAddr = "10.0.0.9:9".parse().unwrap();
+        let other = peer("10.0.0.1:1", flags::REACHABLE);
+        let connected = ConnectedPeers::default();
+        connected
+            .lock()
+            .unwrap()
+            .extend([(recipient, 0), (other.addr, other.flags)]);
+        let mut extension = PexExtension::new(sender, connected, recipient);
+
+        let payload = extension.poll_message(Instant::now()).unwrap().unwrap();
+        assert_eq!(PexMessage::parse(&payload).unwrap().added, vec![other]);
+
+        let incoming = PexMessage {
+            added: vec![peer("10.0.0.5:5", 0)],
+            dropped: Vec::new(),
+        };
+        extension.on_message(&incoming.to_bytes().unwrap()).unwrap();
+        drop(extension);
+        let addrs: Vec<SocketAddr> = Box::new(source).peers().map(|p| p.addr).collect().await;
+        assert_eq!(addrs, vec![incoming.added[0].addr]);
+    }
+
+    #[test]
+    fn connections_sharing_the_map_send_added_and_dropped() {
+        let (_source, sender) = PexSource::new(10);
+        let connected = ConnectedPeers::default();
+        let a: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:2".parse().unwrap();
+        // Every connection reads the one map the swarm keeps up to date.
+        let connection = |addr| {
+            let mut registry = ExtensionRegistry::new();
+            registry.register(Box::new(PexExtension::new(
+                sender.clone(),
+                connected.clone(),
+                addr,
+            )));
+            registry
+        };
+        let mut to_a = connection(a);
+        let mut to_b = connection(b);
+        let BTMessage::Extended(HANDSHAKE_ID, handshake) =
+            connection(a).handshake(6881, None).unwrap()
+        else {
+            panic!("Not an extended handshake");
+        };
+        to_a.handle(HANDSHAKE_ID, &handshake).unwrap();
+        to_b.handle(HANDSHAKE_ID, &handshake).unwrap();
+        connected
+            .lock()
+            .unwrap()
+            .extend([(a, flags::REACHABLE), (b, 0)]);
+
+        let sent = |registry: &mut ExtensionRegistry, now| match registry
+            .poll_messages(now)
+            .unwrap()
+            .as_slice()
+        {
+            [BTMessage::Extended(1, payload)] => PexMessage::parse(payload).unwrap(),
+            other => panic!("Expected one ut_pex message, got {:?}", other),
+        };
+        let start = Instant::now();
+        assert_eq!(
+            sent(&mut to_a, start).added,
+            vec![PexPeer { addr: b, flags: 0 }]
+        );
+        assert_eq!(
+            sent(&mut to_b, start).added,
+            vec![PexPeer {
+                addr: a,
+                flags: flags::REACHABLE
+            }]
+        );
+
+        // The connection to b closes.
+        connected.lock().unwrap().remove(&b);
+        drop(to_b);
+        let update = sent(&mut to_a, start + PEX_INTERVAL);
+        assert!(update.added.is_empty());
+        assert_eq!(update.dropped, vec![b]);
+    }
+}
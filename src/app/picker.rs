@@ -0,0 +1,180 @@
+use crate::app::bitfield::Bitfield;
+use crate::app::random;
+use anyhow::anyhow;
+use std::str::FromStr;
+
+/// Pieces picked at random before switching to rarest first, so there is
+/// soon something to trade.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// How the next piece to download is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickMode {
+    /// Rarest first, after a few random pieces.
+    #[default]
+    RarestFirst,
+    /// Lowest index first, for streaming.
+    Sequential,
+}
+
+impl FromStr for PickMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rarest-first" => Ok(PickMode::RarestFirst),
+            "sequential" => Ok(PickMode::Sequential),
+            _ => Err(anyhow!(
+                "Unknown piece order {}, expected rarest-first or sequential",
+                s
+            )),
+        }
+    }
+}
+
+/// Chooses which piece to start next from how many connected peers have
+/// each one.
+#[derive(Debug)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    mode: PickMode,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: usize, mode: PickMode) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            mode,
+        }
+    }
+
+    /// Counts a peer's pieces from its bitfield or `HaveAll`.
+    pub fn add_peer(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter_set() {
+            self.add_piece(index);
+        }
+    }
+
+    /// Uncounts a peer's pieces when it leaves or replaces its bitfield.
+    pub fn remove_peer(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter_set() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Counts a piece a peer announced with `Have`.
+    pub fn add_piece(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Number of connected peers that have `index`.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    /// Picks one of `candidates`, given in index order, to start when we
+    /// already have `have` pieces. Ties between equally rare pieces are
+    /// broken at random so peers do not all chase the same one.
+    pub fn pick(&self, candidates: impl IntoIterator<Item = usize>, have: usize) -> Option<usize> {
+        let mut candidates = candidates.into_iter();
+        match self.mode {
+            PickMode::Sequential => candidates.next(),
+            PickMode::RarestFirst if have < RANDOM_FIRST_PIECES => pick_random(candidates),
+            PickMode::RarestFirst => {
+                let mut rarest = Vec::new();
+                let mut lowest = u32::MAX;
+                for index in candidates {
+                    let availability = self.availability(index);
+                    if availability < lowest {
+                        lowest = availability;
+                        rarest.clear();
+                    }
+                    if availability == lowest {
+                        rarest.push(index);
+                    }
+                }
+                pick_random(rarest)
+            }
+        }
+    }
+}
+
+fn pick_random(candidates: impl IntoIterator<Item = usize>) -> Option<usize> {
+    let mut chosen = None;
+    for (seen, index) in candidates.into_iter().enumerate() {
+        if random::next_u64().is_multiple_of(seen as u64 + 1) {
+            chosen = Some(index);
+        }
+    }
+    chosen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for &index in pieces {
+            bitfield.set(index, true);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn availability_follows_peers() {
+        let mut picker = PiecePicker::new(4, PickMode::RarestFirst);
+        let peer = bitfield(4, &[0, 1]);
+        picker.add_peer(&peer);
+        picker.add_peer(&Bitfield::full(4));
+        picker.add_piece(3);
+        picker.add_piece(9);
+        assert_eq!(
+            (0..4).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+            vec![2, 2, 1, 2]
+        );
+        picker.remove_peer(&peer);
+        picker.remove_peer(&peer);
+        assert_eq!(
+            (0..4).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+            vec![0, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn picks_rarest_after_random_first() {
+        let mut picker = PiecePicker::new(5, PickMode::RarestFirst);
+        picker.add_peer(&Bitfield::full(5));
+        picker.add_peer(&bitfield(5, &[0, 1, 2, 4]));
+        picker.add_peer(&bitfield(5, &[0, 1, 4]));
+        assert_eq!(picker.pick(0..5, RANDOM_FIRST_PIECES), Some(3));
+        assert_eq!(picker.pick([0, 1, 2, 4], RANDOM_FIRST_PIECES), Some(2));
+        let tie = picker.pick([0, 1], RANDOM_FIRST_PIECES).unwrap();
+        assert!(tie <= 1);
+
+        // Early on any candidate will do.
+        let mut seen = [false; 5];
+        for _ in 0..200 {
+            seen[picker.pick(0..5, 0).unwrap()] = true;
+        }
+        assert_eq!(seen, [true; 5]);
+        assert_eq!(picker.pick([], 0), None);
+    }
+
+    #[test]
+    fn sequential_takes_lowest_index() {
+        let mut picker = PiecePicker::new(5, PickMode::Sequential);
+        picker.add_peer(&bitfield(5, &[4]));
+        assert_eq!(picker.pick([2, 3, 4], 0), Some(2));
+        assert_eq!(picker.pick([2, 3, 4], 10), Some(2));
+        assert_eq!(
+            "sequential".parse::<PickMode>().unwrap(),
+            PickMode::Sequential
+        );
+        assert!("newest".parse::<PickMode>().is_err());
+    }
+}
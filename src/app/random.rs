@@ -0,0 +1,67 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+
+thread_local! {
+    static KEYS: RandomState = RandomState::new();
+    static COUNTER: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Returns 64 unpredictable bits by running a per-thread, randomly keyed
+/// SipHash over a counter. Used for peer ids, transaction ids and nonces.
+pub(crate) fn next_u64() -> u64 {
+    let counter = COUNTER.with(|counter| {
+        let value = counter.get().wrapping_add(1);
+        counter.set(value);
+        value
+    });
+    KEYS.with(|keys| {
+        let mut hasher = keys.build_hasher();
+        hasher.write_u64(counter);
+        hasher.finish()
+    })
+}
+
+pub(crate) fn next_u32() -> u32 {
+    next_u64() as u32
+}
+
+pub(crate) fn fill(bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&next_u64().to_be_bytes()[..chunk.len()]);
+    }
+}
+
+/// Fills `bytes` from the operating system's CSPRNG, for secrets such as
+/// signing keys that must not be predictable. Only Unix systems, through
+/// `/dev/urandom`, are supported.
+#[cfg(unix)]
+pub(crate) fn fill_secure(bytes: &mut [u8]) -> io::Result<()> {
+    use std::fs::File;
+    use std::io::Read;
+    File::open("/dev/urandom")?.read_exact(bytes)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn fill_secure(_bytes: &mut [u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "No secure random source on this platform: /dev/urandom is Unix only",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn secure_bytes_differ() {
+        let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+        fill_secure(&mut a).unwrap();
+        fill_secure(&mut b).unwrap();
+        assert_ne!(a, b);
+        assert_ne!(a, [0u8; 32]);
+    }
+}
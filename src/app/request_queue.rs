@@ -0,0 +1,222 @@
+use crate::app::fast::BlockRequest;
+use crate::app::swarm::BLOCK_SIZE;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Requests kept outstanding before anything has been measured.
+const INITIAL_DEPTH: usize = 4;
+const MIN_DEPTH: usize = 2;
+/// Cap on the depth for peers that do not send `reqq`, and for those that
+/// send a larger one.
+const MAX_DEPTH: usize = 250;
+/// How long requests should wait at the peer on top of the round trip, so
+/// it never runs dry while our next requests are on their way.
+const QUEUE_TIME: Duration = Duration::from_secs(1);
+/// Throughput is sampled over windows at least this long.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Timeout before any round trip has been measured.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(20);
+const MIN_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests outstanding with one peer. The target depth is the bandwidth
+/// delay product from the measured throughput and round trip, limited by
+/// the peer's `reqq`.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    sent: HashMap<BlockRequest, Instant>,
+    /// The peer's `reqq` from its extended handshake.
+    reqq: Option<u32>,
+    /// Fastest round trip seen, without the time spent queued at the peer.
+    min_rtt: Option<Duration>,
+    /// Smoothed round trip and its variation, as in RFC 6298.
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// Bytes per second, averaged over windows.
+    rate: Option<f64>,
+    window_start: Option<Instant>,
+    window_bytes: u64,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_reqq(&mut self, reqq: u32) {
+        self.reqq = Some(reqq);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sent.len()
+    }
+
+    pub fn contains(&self, request: &BlockRequest) -> bool {
+        self.sent.contains_key(request)
+    }
+
+    pub fn requests(&self) -> impl Iterator<Item = &BlockRequest> {
+        self.sent.keys()
+    }
+
+    /// Whether there is room for another request.
+    pub fn wants_more(&self) -> bool {
+        self.len() < self.target_depth()
+    }
+
+    /// How many requests to keep outstanding.
+    pub fn target_depth(&self) -> usize {
+        let limit = self
+            .reqq
+            .map_or(MAX_DEPTH, |reqq| reqq as usize)
+            .clamp(1, MAX_DEPTH);
+        let depth = match (self.rate, self.min_rtt) {
+            (Some(rate), Some(rtt)) => {
+                let bytes = rate * (rtt + QUEUE_TIME).as_secs_f64();
+                (bytes / BLOCK_SIZE as f64).ceil() as usize
+            }
+            _ => INITIAL_DEPTH,
+        };
+        depth.max(MIN_DEPTH).min(limit)
+    }
+
+    /// How long a request may stay unanswered.
+    pub fn timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + 4 * self.rttvar).clamp(MIN_TIMEOUT, MAX_TIMEOUT),
+            None => INITIAL_TIMEOUT,
+        }
+    }
+
+    pub fn push(&mut self, request: BlockRequest, now: Instant) {
+        // Time spent idle says nothing about the peer's speed.
+        if self.sent.is_empty() {
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+        self.sent.insert(request, now);
+    }
+
+    /// Drops a request that was rejected or cancelled. Returns whether it
+    /// was outstanding.
+    pub fn remove(&mut self, request: &BlockRequest) -> bool {
+        self.sent.remove(request).is_some()
+    }
+
+    /// Drops every request, e.g. when the peer goes away.
+    pub fn drain(&mut self) -> Vec<BlockRequest> {
+        self.sent.drain().map(|(request, _)| request).collect()
+    }
+
+    /// Records the block answering `request`, updating the round trip and
+    /// throughput. Returns whether it was outstanding.
+    pub fn complete(&mut self, request: &BlockRequest, now: Instant) -> bool {
+        let Some(sent) = self.sent.remove(request) else {
+            return false;
+        };
+        let rtt = now.saturating_duration_since(sent);
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        match self.srtt {
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (3 * self.rttvar + delta) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+        }
+
+        self.window_bytes += request.2 as u64;
+        let start = *self.window_start.get_or_insert(sent);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed >= RATE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = Some(self.rate.map_or(sample, |rate| 0.7 * rate + 0.3 * sample));
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+        true
+    }
+
+    /// Removes and returns requests older than the timeout. Like a TCP
+    /// timeout, this starts the depth over from the initial one.
+    pub fn expire(&mut self, now: Instant) -> Vec<BlockRequest> {
+        let timeout = self.timeout();
+        let expired: Vec<BlockRequest> = self
+            .sent
+            .iter()
+            .filter(|(_, &sent)| now.saturating_duration_since(sent) >= timeout)
+            .map(|(&request, _)| request)
+            .collect();
+        if !expired.is_empty() {
+            for request in &expired {
+                self.sent.remove(request);
+            }
+            self.rate = None;
+            self.window_start = None;
+            self.window_bytes = 0;
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(index: u32) -> BlockRequest {
+        (index, 0, BLOCK_SIZE)
+    }
+
+    #[test]
+    fn depth_follows_throughput_and_reqq() {
+        let mut queue = RequestQueue::new();
+        assert_eq!(queue.target_depth(), INITIAL_DEPTH);
+
+        // A block every 10ms, each answered 50ms after it was sent.
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        for index in 0..5 {
+            queue.push(block(index), at(10 * index as u64));
+        }
+        for index in 0..200 {
+            assert!(queue.complete(&block(index), at(10 * index as u64 + 50)));
+            queue.push(block(index + 5), at(10 * index as u64 + 50));
+        }
+        assert!(!queue.complete(&block(0), at(3000)));
+        // 1.6 MB/s over 50ms plus a second of queueing.
+        let depth = queue.target_depth();
+        assert!((95..=110).contains(&depth), "depth {}", depth);
+        assert!(queue.wants_more());
+
+        queue.set_reqq(4);
+        assert_eq!(queue.target_depth(), 4);
+        assert!(!queue.wants_more());
+        queue.set_reqq(0);
+        assert_eq!(queue.target_depth(), 1);
+    }
+
+    #[test]
+    fn expires_requests_after_the_timeout() {
+        let mut queue = RequestQueue::new();
+        let start = Instant::now();
+        queue.push(block(1), start);
+        assert_eq!(queue.timeout(), INITIAL_TIMEOUT);
+        assert!(queue.expire(start + Duration::from_secs(19)).is_empty());
+        assert_eq!(queue.expire(start + INITIAL_TIMEOUT), vec![block(1)]);
+        assert_eq!(queue.len(), 0);
+
+        // Quick answers bring the timeout down to its floor.
+        queue.push(block(2), start);
+        queue.complete(&block(2), start + Duration::from_millis(100));
+        assert_eq!(queue.timeout(), MIN_TIMEOUT);
+        queue.push(block(3), start);
+        assert!(queue.contains(&block(3)));
+        assert!(queue.remove(&block(3)));
+        assert!(!queue.remove(&block(3)));
+        queue.push(block(4), start);
+        assert_eq!(queue.drain(), vec![block(4)]);
+    }
+}
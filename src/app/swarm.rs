@@ -0,0 +1,1081 @@
+use crate::app::bitfield::Bitfield;
+use crate::app::config::ClientConfig;
+use crate::app::connection::{PeerConnection, Transport};
+use crate::app::extension::{ExtensionRegistry, HANDSHAKE_ID};
+use crate::app::fast::{allowed_fast_set, BlockRequest, FastState, ALLOWED_FAST_COUNT};
+use crate::app::messages::{BTMessage, Capabilities, KEEP_ALIVE_INTERVAL};
+use crate::app::mse::MseStream;
+use crate::app::network::Peer;
+use crate::app::peer::{self, Connector, PeerManager};
+use crate::app::pex::{flags, ConnectedPeers, PexExtension, PexSender};
+use crate::app::picker::{PickMode, PiecePicker};
+use crate::app::request_queue::RequestQueue;
+use crate::app::tracker::MetaData;
+use crate::app::tracker_client::TransferStats;
+use crate::app::verify_piece;
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time;
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+/// How often requests are checked for timeouts.
+pub(crate) const REQUEST_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a connection checks for due keep-alives and extension messages.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What connection tasks report to the swarm.
+enum Event {
+    /// The handshake is done; messages for the peer go through the sender.
+    Connected(SocketAddr, Capabilities, mpsc::UnboundedSender<BTMessage>),
+    Message(SocketAddr, BTMessage),
+    /// The `reqq` from the peer's extended handshake.
+    Reqq(SocketAddr, u32),
+    /// The connection ended, or never got going.
+    Closed(SocketAddr, Option<anyhow::Error>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    Missing,
+    Requested,
+    Received,
+}
+
+/// The pieces we have and the blocks of those still in progress.
+struct Progress {
+    torrent: MetaData,
+    picker: PiecePicker,
+    have: Bitfield,
+    partial: BTreeMap<u32, Vec<BlockState>>,
+}
+
+impl Progress {
+    fn new(torrent: MetaData, mode: PickMode) -> Self {
+        let piece_count = torrent.info.piece_count();
+        Self {
+            torrent,
+            picker: PiecePicker::new(piece_count, mode),
+            have: Bitfield::new(piece_count),
+            partial: BTreeMap::new(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.have.count() == self.have.len()
+    }
+
+    /// Whether `pieces` holds anything we still need.
+    fn wants_from(&self, pieces: &Bitfield) -> bool {
+        pieces.and(&self.have.not()).count() > 0
+    }
+
+    fn block_count(&self, index: u32) -> usize {
+        let size = self.torrent.info.piece_size(index as usize) as u32;
+        size.div_ceil(BLOCK_SIZE) as usize
+    }
+
+    /// The request for block `block` of piece `index`; only the last
+    /// block of the last piece may be short.
+    fn block(&self, index: u32, block: usize) -> BlockRequest {
+        let size = self.torrent.info.piece_size(index as usize) as u32;
+        let begin = block as u32 * BLOCK_SIZE;
+        (index, begin, BLOCK_SIZE.min(size - begin))
+    }
+
+    fn state_mut(&mut self, index: u32, begin: u32) -> Option<&mut BlockState> {
+        if !begin.is_multiple_of(BLOCK_SIZE) {
+            return None;
+        }
+        self.partial
+            .get_mut(&index)?
+            .get_mut((begin / BLOCK_SIZE) as usize)
+    }
+
+    /// Picks a block to request from a peer that has `pieces`, among
+    /// the pieces `allowed` accepts and the blocks `skip` does not: a
+    /// missing block of a started piece, else the first block of a piece
+    /// the picker chooses. In endgame it may be a block already requested
+    /// from another peer.
+    fn next_request(
+        &mut self,
+        pieces: &Bitfield,
+        skip: impl Fn(&BlockRequest) -> bool,
+        allowed: impl Fn(u32) -> bool,
+    ) -> Option<BlockRequest> {
+        let usable = |index: u32| pieces.get(index as usize) && allowed(index);
+        let started = self
+            .partial
+            .iter()
+            .filter(|(&index, _)| usable(index))
+            .find_map(|(&index, blocks)| {
+                let block = (0..blocks.len()).find(|&block| {
+                    blocks[block] == BlockState::Missing && !skip(&self.block(index, block))
+                })?;
+                Some((index, block))
+            });
+        if let Some((index, block)) = started {
+            self.partial.get_mut(&index)?[block] = BlockState::Requested;
+            return Some(self.block(index, block));
+        }
+        let candidates = pieces
+            .and(&self.have.not())
+            .iter_set()
+            .filter(|&index| !self.partial.contains_key(&(index as u32)) && allowed(index as u32))
+            .collect::<Vec<_>>();
+        if let Some(index) = self.picker.pick(candidates, self.have.count()) {
+            let index = index as u32;
+            let mut blocks = vec![BlockState::Missing; self.block_count(index)];
+            blocks[0] = BlockState::Requested;
+            self.partial.insert(index, blocks);
+            return Some(self.block(index, 0));
+        }
+        if !self.in_endgame() {
+            return None;
+        }
+        self.partial
+            .iter()
+            .filter(|(&index, _)| usable(index))
+            .flat_map(|(&index, blocks)| {
+                blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &s)| s == BlockState::Requested)
+                    .map(move |(block, _)| (index, block))
+            })
+            .map(|(index, block)| self.block(index, block))
+            .find(|request| !skip(request))
+    }
+
+    /// Whether every block we lack has been requested, so the remaining
+    /// ones may be requested from several peers at once.
+    fn in_endgame(&self) -> bool {
+        self.have.count() + self.partial.len() == self.have.len()
+            && self
+                .partial
+                .values()
+                .flatten()
+                .all(|&s| s != BlockState::Missing)
+    }
+
+    /// Makes a requested block that will not arrive available again.
+    fn release(&mut self, (index, begin, _): BlockRequest) {
+        if let Some(state) = self.state_mut(index, begin) {
+            if *state == BlockState::Requested {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Records an arriving block. Returns false for blocks we do not need,
+    /// including ones that already came from another peer.
+    fn receive(&mut self, (index, begin, length): BlockRequest) -> bool {
+        let Some(state) = self.state_mut(index, begin) else {
+            return false;
+        };
+        if *state == BlockState::Received {
+            return false;
+        }
+        if self.block(index, (begin / BLOCK_SIZE) as usize).2 != length {
+            return false;
+        }
+        if let Some(state) = self.state_mut(index, begin) {
+            *state = BlockState::Received;
+        }
+        true
+    }
+
+    /// Whether every block of a started piece has arrived.
+    fn is_piece_done(&self, index: u32) -> bool {
+        self.partial
+            .get(&index)
+            .is_some_and(|blocks| blocks.iter().all(|&s| s == BlockState::Received))
+    }
+
+    /// Marks a downloaded piece as ours, or, if its hash did not match, as
+    /// needing every block again.
+    fn finish_piece(&mut self, index: u32, valid: bool) {
+        if valid {
+            self.partial.remove(&index);
+            self.have.set(index as usize, true);
+        } else if let Some(blocks) = self.partial.get_mut(&index) {
+            blocks.fill(BlockState::Missing);
+        }
+    }
+}
+
+/// One connected peer as the swarm sees it.
+struct PeerState {
+    sender: mpsc::UnboundedSender<BTMessage>,
+    fast: bool,
+    peer_choking: bool,
+    /// Read by an upload choker, once we have one.
+    #[allow(dead_code)]
+    peer_interested: bool,
+    am_choking: bool,
+    am_interested: bool,
+    pieces: Bitfield,
+    requests: RequestQueue,
+    fast_state: FastState,
+    /// Pieces we let the peer request while choking it.
+    allowed_fast: HashSet<u32>,
+}
+
+impl PeerState {
+    fn new(sender: mpsc::UnboundedSender<BTMessage>, fast: bool, piece_count: usize) -> Self {
+        Self {
+            sender,
+            fast,
+            peer_choking: true,
+            peer_interested: false,
+            am_choking: true,
+            am_interested: false,
+            pieces: Bitfield::new(piece_count),
+            requests: RequestQueue::new(),
+            fast_state: FastState::default(),
+            allowed_fast: HashSet::new(),
+        }
+    }
+
+    /// Queues a message for the connection. A failed send means the
+    /// connection is closing and a [`Event::Closed`] is on its way.
+    fn send(&self, message: BTMessage) {
+        let _ = self.sender.send(message);
+    }
+}
+
+/// Downloads a torrent from up to `max_peers` peers at once, spreading
+/// block requests over those that have the pieces.
+pub struct Swarm {
+    config: ClientConfig,
+    file_name: String,
+    stats: Arc<TransferStats>,
+    progress: Progress,
+    peers: HashMap<SocketAddr, PeerState>,
+    /// Peers still connecting or handshaking.
+    connecting: HashSet<SocketAddr>,
+    connected: ConnectedPeers,
+}
+
+impl Swarm {
+    pub fn new(
+        torrent: MetaData,
+        file_name: &str,
+        config: ClientConfig,
+        stats: Arc<TransferStats>,
+    ) -> Self {
+        Self {
+            progress: Progress::new(torrent, config.piece_order),
+            config,
+            file_name: file_name.to_owned(),
+            stats,
+            peers: HashMap::new(),
+            connecting: HashSet::new(),
+            connected: ConnectedPeers::default(),
+        }
+    }
+
+    /// Runs until every piece is downloaded and verified. Fails if the
+    /// peer sources run dry with no connections left: PEX alone cannot
+    /// bring new peers then.
+    pub async fn download(mut self, peer_manager: &mut PeerManager) -> Result<()> {
+        let connector = peer_manager.connector().await?;
+        let pex = peer_manager.pex_sender();
+        let (events, mut receiver) = mpsc::unbounded_channel();
+        let mut ticks = time::interval(REQUEST_CHECK_INTERVAL);
+        while !self.progress.is_complete() {
+            if peer_manager.exhausted() && self.peers.is_empty() && self.connecting.is_empty() {
+                return Err(anyhow!("Ran out of peers to connect to"));
+            }
+            let wants_peers = self.peers.len() + self.connecting.len() < self.config.max_peers;
+            tokio::select! {
+                // We hold a sender, so the channel never closes.
+                Some(event) = receiver.recv() => self.handle(event).await?,
+                Some(peer) = peer_manager.next_candidate(), if wants_peers => {
+                    self.open(peer, &connector, &pex, &events);
+                }
+                _ = ticks.tick() => self.expire_requests(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects to `peer` in the background.
+    fn open(
+        &mut self,
+        peer: Peer,
+        connector: &Connector,
+        pex: &PexSender,
+        events: &mpsc::UnboundedSender<Event>,
+    ) {
+        if self.peers.contains_key(&peer.addr) || !self.connecting.insert(peer.addr) {
+            return;
+        }
+        let connector = connector.clone();
+        let pex = pex.clone();
+        let events = events.clone();
+        let connected = self.connected.clone();
+        let port = self.config.port;
+        tokio::spawn(async move {
+            let result = match connector.connect(&peer).await {
+                Ok(connection) => {
+                    let peer_flags = outgoing_flags(connection.framed.get_ref());
+                    connected.lock().unwrap().insert(peer.addr, peer_flags);
+                    let result =
+                        serve(connection, peer.addr, pex, connected.clone(), port, &events).await;
+                    connected.lock().unwrap().remove(&peer.addr);
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            let _ = events.send(Event::Closed(peer.addr, result.err()));
+        });
+    }
+
+    async fn handle(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Connected(addr, capabilities, sender) => {
+                self.connecting.remove(&addr);
+                let fast = capabilities.contains(Capabilities::FAST);
+                let piece_count = self.progress.have.len();
+                let mut peer = PeerState::new(sender, fast, piece_count);
+                if self.progress.have.count() > 0 {
+                    peer.send(BTMessage::Bitfield(self.progress.have.clone()));
+                } else if fast {
+                    peer.send(BTMessage::HaveNone);
+                }
+                if fast {
+                    let info_hash: [u8; 20] = self
+                        .progress
+                        .torrent
+                        .raw()
+                        .info_hash_u8()?
+                        .try_into()
+                        .map_err(|_| anyhow!("Info hash must be 20 bytes."))?;
+                    let count = ALLOWED_FAST_COUNT;
+                    for index in allowed_fast_set(addr.ip(), &info_hash, piece_count as u32, count)
+                    {
+                        peer.allowed_fast.insert(index);
+                        peer.send(BTMessage::AllowedFast(index));
+                    }
+                }
+                self.peers.insert(addr, peer);
+            }
+            Event::Message(addr, message) => {
+                self.on_message(addr, message).await?;
+                self.update_interest(addr);
+                self.request_blocks(addr);
+            }
+            Event::Reqq(addr, reqq) => {
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    peer.requests.set_reqq(reqq);
+                }
+            }
+            Event::Closed(addr, error) => {
+                self.connecting.remove(&addr);
+                if let Some(e) = error {
+                    log::warn!("Peer {} failed: {}", addr, e);
+                }
+                self.drop_peer(addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets a peer, closing its connection if still open, and hands
+    /// its outstanding requests to the others.
+    fn drop_peer(&mut self, addr: SocketAddr) {
+        let Some(mut peer) = self.peers.remove(&addr) else {
+            return;
+        };
+        self.progress.picker.remove_peer(&peer.pieces);
+        for request in peer.requests.drain() {
+            self.progress.release(request);
+        }
+        self.request_from_all();
+    }
+
+    async fn on_message(&mut self, addr: SocketAddr, message: BTMessage) -> Result<()> {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return Ok(());
+        };
+        match message {
+            BTMessage::KeepAlive => {}
+            BTMessage::Choke => {
+                peer.peer_choking = true;
+                // A choke drops our requests. With the Fast Extension the
+                // peer rejects them explicitly, except allowed fast ones.
+                let dropped: Vec<BlockRequest> = peer
+                    .requests
+                    .requests()
+                    .filter(|&&(index, _, _)| !(peer.fast && peer.fast_state.allows(index)))
+                    .copied()
+                    .collect();
+                for request in dropped {
+                    peer.requests.remove(&request);
+                    self.progress.release(request);
+                }
+            }
+            BTMessage::Unchoke => {
+                peer.peer_choking = false;
+                // A new unchoke may mean the peer can serve what it refused.
+                peer.fast_state.clear_rejected();
+            }
+            BTMessage::Interested => peer.peer_interested = true,
+            BTMessage::NotInterested => peer.peer_interested = false,
+            BTMessage::Have(index) => {
+                if !peer.pieces.get(index as usize) {
+                    peer.pieces.set(index as usize, true);
+                    self.progress.picker.add_piece(index as usize);
+                }
+            }
+            BTMessage::Bitfield(bits) => match bits.validate(self.progress.have.len()) {
+                Ok(bits) => {
+                    log::debug!("Peer {} has {}/{} pieces", addr, bits.count(), bits.len());
+                    self.progress.picker.remove_peer(&peer.pieces);
+                    self.progress.picker.add_peer(&bits);
+                    peer.pieces = bits;
+                }
+                Err(e) => {
+                    log::warn!("Peer {} sent a bad bitfield: {}", addr, e);
+                    self.drop_peer(addr);
+                }
+            },
+            BTMessage::HaveAll | BTMessage::HaveNone => {
+                let piece_count = self.progress.have.len();
+                let pieces = match message {
+                    BTMessage::HaveAll => Bitfield::full(piece_count),
+                    _ => Bitfield::new(piece_count),
+                };
+                self.progress.picker.remove_peer(&peer.pieces);
+                self.progress.picker.add_peer(&pieces);
+                peer.pieces = pieces;
+            }
+            BTMessage::Request(index, begin, length) => {
+                let info = &self.progress.torrent.info;
+                let servable = peer.allowed_fast.contains(&index)
+                    && self.progress.have.get(index as usize)
+                    && length <= BLOCK_SIZE
+                    && begin as u64 + length as u64 <= info.piece_size(index as usize) as u64;
+                // We only upload the allowed fast set, but Fast peers expect
+                // an answer either way.
+                if servable {
+                    let offset = index as u64 * info.piece_length as u64 + begin as u64;
+                    let data =
+                        peer::read_at_offset(&self.file_name, offset, length as usize).await?;
+                    peer.send(BTMessage::Piece(index, begin, data.into()));
+                    self.stats.add_uploaded(length as u64);
+                } else if peer.fast && peer.am_choking {
+                    peer.send(BTMessage::RejectRequest(index, begin, length));
+                }
+            }
+            BTMessage::Piece(index, begin, data) => {
+                let request = (index, begin, data.len() as u32);
+                peer.requests.complete(&request, Instant::now());
+                let wanted = self.progress.receive(request);
+                // In endgame other peers may still be sending the block.
+                for other in self.peers.values_mut() {
+                    if other.requests.remove(&request) {
+                        other.send(BTMessage::Cancel(index, begin, request.2));
+                    }
+                }
+                if wanted {
+                    self.write_block(index, begin, &data).await?;
+                }
+            }
+            BTMessage::Cancel(_, _, _) => {}
+            BTMessage::Port(port) => {
+                log::debug!("Peer {} runs a DHT node on port {}", addr, port);
+            }
+            BTMessage::SuggestPiece(index) => {
+                log::debug!("Peer {} suggests piece {}", addr, index);
+            }
+            BTMessage::RejectRequest(index, begin, length) => {
+                let request = (index, begin, length);
+                // Asking the same peer again could go back and forth
+                // forever, so another peer gets the block instead.
+                if peer.requests.remove(&request) {
+                    peer.fast_state.reject(request);
+                    self.progress.release(request);
+                }
+            }
+            BTMessage::AllowedFast(index) => peer.fast_state.allow(index),
+            // Connection tasks handle extended messages themselves.
+            BTMessage::Extended(_, _) => {}
+        }
+        Ok(())
+    }
+
+    /// Saves a block and, once its piece is complete, verifies the piece
+    /// and announces it to every peer.
+    async fn write_block(&mut self, index: u32, begin: u32, data: &[u8]) -> Result<()> {
+        let info = &self.progress.torrent.info;
+        let offset = index as u64 * info.piece_length as u64 + begin as u64;
+        peer::write_at_offset(&self.file_name, offset, data).await?;
+        self.stats.add_downloaded(data.len() as u64);
+        if !self.progress.is_piece_done(index) {
+            return Ok(());
+        }
+        let valid =
+            match verify_piece(index as usize, &self.progress.torrent, &self.file_name).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("{}, downloading it again", e);
+                    false
+                }
+            };
+        self.progress.finish_piece(index, valid);
+        if valid {
+            let size = self.progress.torrent.info.piece_size(index as usize);
+            self.stats.piece_verified(size as u64);
+            for peer in self.peers.values() {
+                peer.send(BTMessage::Have(index));
+            }
+            let addrs: Vec<SocketAddr> = self.peers.keys().copied().collect();
+            for addr in addrs {
+                self.update_interest(addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells a peer whether it has anything we need, when that changes.
+    fn update_interest(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        let interested = self.progress.wants_from(&peer.pieces);
+        if interested != peer.am_interested {
+            peer.am_interested = interested;
+            peer.send(if interested {
+                BTMessage::Interested
+            } else {
+                BTMessage::NotInterested
+            });
+        }
+    }
+
+    /// Tops a peer's outstanding requests up to its queue's target depth
+    /// with blocks it can serve: any piece it has while it unchokes us, only
+    /// allowed fast ones while it does not.
+    fn request_blocks(&mut self, addr: SocketAddr) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        if !peer.am_interested || (peer.peer_choking && !peer.fast) {
+            return;
+        }
+        while peer.requests.wants_more() {
+            let request = self.progress.next_request(
+                &peer.pieces,
+                |request| peer.requests.contains(request) || peer.fast_state.rejected(request),
+                |index| !peer.peer_choking || peer.fast_state.allows(index),
+            );
+            let Some(request) = request else {
+                break;
+            };
+            let (index, begin, length) = request;
+            peer.requests.push(request, Instant::now());
+            peer.send(BTMessage::Request(index, begin, length));
+        }
+    }
+
+    /// Gives up on requests peers have sat on for too long. Their blocks go
+    /// to the other peers first.
+    fn expire_requests(&mut self) {
+        let now = Instant::now();
+        let mut slow = Vec::new();
+        for (&addr, peer) in self.peers.iter_mut() {
+            let expired = peer.requests.expire(now);
+            if expired.is_empty() {
+                continue;
+            }
+            log::debug!("{} requests to {} timed out", expired.len(), addr);
+            for request in expired {
+                let (index, begin, length) = request;
+                peer.send(BTMessage::Cancel(index, begin, length));
+                self.progress.release(request);
+            }
+            slow.push(addr);
+        }
+        if slow.is_empty() {
+            return;
+        }
+        let others: Vec<SocketAddr> = self
+            .peers
+            .keys()
+            .filter(|addr| !slow.contains(addr))
+            .copied()
+            .collect();
+        for addr in others.into_iter().chain(slow) {
+            self.request_blocks(addr);
+        }
+    }
+
+    fn request_from_all(&mut self) {
+        let addrs: Vec<SocketAddr> = self.peers.keys().copied().collect();
+        for addr in addrs {
+            self.request_blocks(addr);
+        }
+    }
+}
+
+/// Runs one connection: extension messages and keep-alives are handled
+/// here, everything else is passed to the swarm, and the swarm's messages
+/// are sent on.
+/// PEX flags for a peer we connected to ourselves.
+fn outgoing_flags(stream: &MseStream<Transport>) -> u8 {
+    let mut peer_flags = flags::REACHABLE;
+    if stream.is_encrypted() {
+        peer_flags |= flags::PREFERS_ENCRYPTION;
+    }
+    if matches!(stream.get_ref(), Transport::Utp(_)) {
+        peer_flags |= flags::SUPPORTS_UTP;
+    }
+    peer_flags
+}
+
+async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+    connection: PeerConnection<T>,
+    addr: SocketAddr,
+    pex: PexSender,
+    connected: ConnectedPeers,
+    port: u16,
+    events: &mpsc::UnboundedSender<Event>,
+) -> Result<()> {
+    let PeerConnection {
+        mut framed,
+        capabilities,
+        ..
+    } = connection;
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    if events
+        .send(Event::Connected(addr, capabilities, sender))
+        .is_err()
+    {
+        return Ok(());
+    }
+    let mut extensions = ExtensionRegistry::new();
+    extensions.register(Box::new(PexExtension::new(pex, connected, addr)));
+    if capabilities.contains(Capabilities::EXTENSION_PROTOCOL) {
+        framed
+            .send(extensions.handshake(port, Some(addr.ip()))?)
+            .await?;
+    }
+    let mut ticks = time::interval(TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            message = framed.next() => match message {
+                Some(Ok(BTMessage::Extended(id, payload))) => {
+                    if let Err(e) = extensions.handle(id, &payload) {
+                        log::warn!("Bad extended message {}: {}", id, e);
+                    } else if let Some(reqq) = extensions.remote_reqq().filter(|_| id == HANDSHAKE_ID) {
+                        let _ = events.send(Event::Reqq(addr, reqq));
+                    }
+                }
+                Some(Ok(message)) => {
+                    if events.send(Event::Message(addr, message)).is_err() {
+                        return Ok(());
+                    }
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            message = outgoing.recv() => match message {
+                Some(message) => framed.send(message).await?,
+                // The swarm dropped the peer.
+                None => return Ok(()),
+            },
+            _ = ticks.tick() => {
+                for message in extensions.poll_messages(Instant::now())? {
+                    framed.send(message).await?;
+                }
+                if framed.codec().idle_for() >= KEEP_ALIVE_INTERVAL {
+                    framed.send(BTMessage::KeepAlive).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::bencode::Value;
+    use crate::app::mse::EncryptionPolicy;
+    use crate::app::random;
+    use bytes::Bytes;
+    use sha1::{Digest, Sha1};
+    use std::sync::Mutex;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    fn torrent(data: &[u8]) -> MetaData {
+        let pieces = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let info = Value::dict([
+            ("length", Value::Int(data.len() as i64)),
+            ("name", Value::Str(b"swarm".to_vec())),
+            ("piece length", Value::Int(PIECE_LENGTH as i64)),
+            ("pieces", Value::Str(pieces)),
+        ]);
+        let announce = Value::Str(b"http://127.0.0.1/announce".to_vec());
+        MetaData::new(Value::dict([("announce", announce), ("info", info)])).unwrap()
+    }
+
+    /// Serves `data` on every connection, claiming only `pieces`.
+    async fn seeder(torrent: &MetaData, data: Arc<Vec<u8>>, pieces: Vec<u32>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut bitfield = Bitfield::new(torrent.info.piece_count());
+        for &index in &pieces {
+            bitfield.set(index as usize, true);
+        }
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (data, pieces, bitfield) = (data.clone(), pieces.clone(), bitfield.clone());
+                tokio::spawn(async move {
+                    let config = ClientConfig {
+                        capabilities: Capabilities::empty(),
+                        ..Default::default()
+                    };
+                    let connection = PeerConnection::incoming(stream, &config, |_| true).await;
+                    let mut peer = connection.unwrap().framed;
+                    peer.send(BTMessage::Bitfield(bitfield)).await.unwrap();
+                    while let Some(Ok(message)) = peer.next().await {
+                        let reply = match message {
+                            BTMessage::Interested => BTMessage::Unchoke,
+                            BTMessage::Request(index, begin, length) if pieces.contains(&index) => {
+                                let start = index as usize * PIECE_LENGTH + begin as usize;
+                                let block = &data[start..start + length as usize];
+                                BTMessage::Piece(index, begin, Bytes::copy_from_slice(block))
+                            }
+                            _ => continue,
+                        };
+                        if peer.send(reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// A Fast peer that claims every piece, unchokes, and rejects every
+    /// request. Counts how often each block was asked for.
+    async fn rejecter(torrent: &MetaData) -> (SocketAddr, Arc<Mutex<HashMap<BlockRequest, u32>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitfield = Bitfield::full(torrent.info.piece_count());
+        let asked = Arc::new(Mutex::new(HashMap::new()));
+        let counts = asked.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = ClientConfig {
+                capabilities: Capabilities::FAST,
+                ..Default::default()
+            };
+            let connection = PeerConnection::incoming(stream, &config, |_| true).await;
+            let mut peer = connection.unwrap().framed;
+            peer.send(BTMessage::Bitfield(bitfield)).await.unwrap();
+            while let Some(Ok(message)) = peer.next().await {
+                let reply = match message {
+                    BTMessage::Interested => BTMessage::Unchoke,
+                    BTMessage::Request(index, begin, length) => {
+                        *counts
+                            .lock()
+                            .unwrap()
+                            .entry((index, begin, length))
+                            .or_insert(0) += 1;
+                        BTMessage::RejectRequest(index, begin, length)
+                    }
+                    _ => continue,
+                };
+                if peer.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+        (addr, asked)
+    }
+
+    /// A Fast peer with only `piece`. Once we announce another piece it
+    /// asks for a block of it while choked, and serves `piece` only after
+    /// getting that block. Returns the allowed fast set it was granted.
+    async fn fast_leecher(
+        torrent: &MetaData,
+        data: Arc<Vec<u8>>,
+        piece: u32,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Vec<u32>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut bitfield = Bitfield::new(torrent.info.piece_count());
+        bitfield.set(piece as usize, true);
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = ClientConfig {
+                capabilities: Capabilities::FAST,
+                ..Default::default()
+            };
+            let connection = PeerConnection::incoming(stream, &config, |_| true).await;
+            let mut peer = connection.unwrap().framed;
+            peer.send(BTMessage::Bitfield(bitfield)).await.unwrap();
+            let mut granted = Vec::new();
+            let mut held = Vec::new();
+            let mut served = false;
+            while let Some(Ok(message)) = peer.next().await {
+                match message {
+                    BTMessage::AllowedFast(index) => granted.push(index),
+                    BTMessage::Interested => peer.send(BTMessage::Unchoke).await.unwrap(),
+                    BTMessage::Have(index) if !served => {
+                        peer.send(BTMessage::Request(index, 0, BLOCK_SIZE))
+                            .await
+                            .unwrap();
+                    }
+                    BTMessage::Piece(index, begin, block) => {
+                        let start = index as usize * PIECE_LENGTH + begin as usize;
+                        assert_eq!(block, data[start..start + block.len()]);
+                        served = true;
+                        for request in held.drain(..) {
+                            peer.send(request).await.unwrap();
+                        }
+                    }
+                    BTMessage::Request(index, begin, length) => {
+                        let start = index as usize * PIECE_LENGTH + begin as usize;
+                        let block = Bytes::copy_from_slice(&data[start..start + length as usize]);
+                        held.push(BTMessage::Piece(index, begin, block));
+                        if served {
+                            for request in held.drain(..) {
+                                peer.send(request).await.unwrap();
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            granted
+        });
+        (addr, task)
+    }
+
+    #[test]
+    fn progress_hands_out_each_block_once() {
+        let mut progress = Progress::new(
+            torrent(&[0u8; 2 * PIECE_LENGTH + 100]),
+            PickMode::Sequential,
+        );
+        let mut last = Bitfield::new(3);
+        last.set(2, true);
+        assert_eq!(
+            progress.next_request(&last, |_| false, |_| true),
+            Some((2, 0, 100))
+        );
+        assert_eq!(progress.next_request(&last, |_| false, |_| true), None);
+
+        // Started pieces come first, whatever their index.
+        let all = Bitfield::full(3);
+        let first = (1, 0, BLOCK_SIZE);
+        assert_eq!(
+            progress.next_request(&all, |_| false, |index| index != 0),
+            Some(first)
+        );
+        assert_eq!(
+            progress.next_request(&all, |_| false, |_| true),
+            Some((1, BLOCK_SIZE, BLOCK_SIZE))
+        );
+        progress.release(first);
+        assert_eq!(
+            progress.next_request(&all, |_| false, |_| true),
+            Some(first)
+        );
+
+        assert!(!progress.receive((1, 0, 100)));
+        assert!(progress.receive(first));
+        assert!(!progress.receive(first));
+        assert!(!progress.is_piece_done(1));
+        assert!(progress.receive((1, BLOCK_SIZE, BLOCK_SIZE)));
+        assert!(progress.is_piece_done(1));
+
+        // A piece failing its hash check is downloaded again.
+        progress.finish_piece(1, false);
+        assert_eq!(
+            progress.next_request(&all, |_| false, |_| true),
+            Some(first)
+        );
+        progress.finish_piece(2, true);
+        assert!(progress.have.get(2));
+        assert!(!progress.wants_from(&last));
+    }
+
+    #[test]
+    fn endgame_duplicates_outstanding_blocks() {
+        let mut progress = Progress::new(torrent(&[0u8; PIECE_LENGTH + 100]), PickMode::Sequential);
+        let all = Bitfield::full(2);
+        let now = Instant::now();
+        let mut first = RequestQueue::new();
+        let request = progress
+            .next_request(&all, |r| first.contains(r), |_| true)
+            .unwrap();
+        first.push(request, now);
+        assert!(!progress.in_endgame());
+        while let Some(request) = progress.next_request(&all, |r| first.contains(r), |_| true) {
+            first.push(request, now);
+        }
+        assert_eq!(first.len(), 3);
+        assert!(progress.in_endgame());
+
+        // Another peer gets every outstanding block, but each only once.
+        let mut second = RequestQueue::new();
+        while let Some(request) = progress.next_request(&all, |r| second.contains(r), |_| true) {
+            second.push(request, now);
+        }
+        let sorted = |queue: &RequestQueue| {
+            let mut requests: Vec<BlockRequest> = queue.requests().copied().collect();
+            requests.sort();
+            requests
+        };
+        assert_eq!(sorted(&second), sorted(&first));
+        assert!(progress.receive((1, 0, 100)));
+        assert_eq!(
+            progress.next_request(&all, |_| false, |index| index == 1),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn outgoing_connections_are_reachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let stream = MseStream::plaintext(Transport::Tcp(stream));
+        assert_eq!(outgoing_flags(&stream), flags::REACHABLE);
+    }
+
+    #[tokio::test]
+    async fn downloads_from_several_peers() {
+        let data: Vec<u8> = (0..4 * PIECE_LENGTH + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let torrent = torrent(&data);
+        let data = Arc::new(data);
+        let even = seeder(&torrent, data.clone(), vec![0, 2, 4]).await;
+        let odd = seeder(&torrent, data.clone(), vec![1, 3]).await;
+
+        let path = std::env::temp_dir().join(format!("swarm-{:016x}", random::next_u64()));
+        let config = ClientConfig {
+            peers: vec![even, odd],
+            encryption: EncryptionPolicy::Disabled,
+            ..Default::default()
+        };
+        let stats = Arc::new(TransferStats::new(data.len() as u64));
+        let mut peer_manager = PeerManager::new(torrent.clone(), config.clone());
+        let swarm = Swarm::new(torrent, path.to_str().unwrap(), config, stats.clone());
+        time::timeout(Duration::from_secs(10), swarm.download(&mut peer_manager))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), *data);
+        assert_eq!(stats.left(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fast_peers_get_their_allowed_fast_set_served_while_choked() {
+        let data: Vec<u8> = (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+        let torrent = torrent(&data);
+        let data = Arc::new(data);
+        let first = seeder(&torrent, data.clone(), vec![0]).await;
+        let (second, granted) = fast_leecher(&torrent, data.clone(), 1).await;
+
+        let path = std::env::temp_dir().join(format!("swarm-{:016x}", random::next_u64()));
+        let config = ClientConfig {
+            peers: vec![first, second],
+            encryption: EncryptionPolicy::Disabled,
+            ..Default::default()
+        };
+        let stats = Arc::new(TransferStats::new(data.len() as u64));
+        let mut peer_manager = PeerManager::new(torrent.clone(), config.clone());
+        let swarm = Swarm::new(
+            torrent.clone(),
+            path.to_str().unwrap(),
+            config,
+            stats.clone(),
+        );
+        // Piece 1 only comes once piece 0 was served to the choked peer.
+        time::timeout(Duration::from_secs(10), swarm.download(&mut peer_manager))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.uploaded(), BLOCK_SIZE as u64);
+        std::fs::remove_file(path).unwrap();
+
+        let info_hash: [u8; 20] = torrent.raw().info_hash_u8().unwrap().try_into().unwrap();
+        let localhost = "127.0.0.1".parse().unwrap();
+        let mut expected = allowed_fast_set(localhost, &info_hash, 2, ALLOWED_FAST_COUNT);
+        let mut granted = granted.await.unwrap();
+        granted.sort();
+        expected.sort();
+        assert_eq!(granted, expected);
+    }
+
+    #[tokio::test]
+    async fn rejected_blocks_are_not_asked_for_again() {
+        let data = vec![7u8; 4 * PIECE_LENGTH];
+        let torrent = torrent(&data);
+        let (rejecting, asked) = rejecter(&torrent).await;
+
+        let path = std::env::temp_dir().join(format!("swarm-{:016x}", random::next_u64()));
+        let config = ClientConfig {
+            peers: vec![rejecting],
+            encryption: EncryptionPolicy::Disabled,
+            ..Default::default()
+        };
+        let stats = Arc::new(TransferStats::new(data.len() as u64));
+        let mut peer_manager = PeerManager::new(torrent.clone(), config.clone());
+        let swarm = Swarm::new(torrent, path.to_str().unwrap(), config, stats);
+        // Nothing can be downloaded; the swarm just must not keep asking.
+        let download = time::timeout(Duration::from_secs(1), swarm.download(&mut peer_manager));
+        assert!(download.await.is_err());
+        let _ = std::fs::remove_file(path);
+        let asked = asked.lock().unwrap();
+        assert!(!asked.is_empty());
+        assert!(asked.values().all(|&count| count == 1), "{:?}", asked);
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_peer_can_be_reached() {
+        let torrent = torrent(&[1u8; PIECE_LENGTH]);
+        // Nothing listens here once the listener is gone.
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("swarm-{:016x}", random::next_u64()));
+        let config = ClientConfig {
+            peers: vec![unreachable],
+            encryption: EncryptionPolicy::Disabled,
+            utp: false,
+            ..Default::default()
+        };
+        let stats = Arc::new(TransferStats::new(PIECE_LENGTH as u64));
+        let mut peer_manager = PeerManager::new(torrent.clone(), config.clone());
+        let swarm = Swarm::new(torrent, path.to_str().unwrap(), config, stats);
+        let result = time::timeout(Duration::from_secs(10), swarm.download(&mut peer_manager))
+            .await
+            .unwrap();
+        assert!(result.is_err(), "Downloaded without peers");
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -16,7 +16,7 @@ impl MetaData {
         match values {
             bencode::Dict(ref map) => {
                 let announce = map
-                    .get("announce")
+                    .get("announce".as_bytes())
                     .and_then(|announce| match announce {
                         Value::Str(url) => Some(std::str::from_utf8(url)),
                         _ => None,
@@ -26,7 +26,7 @@ impl MetaData {
                     .to_owned();
 
                 let info = map
-                    .get("info")
+                    .get("info".as_bytes())
                     .and_then(|info| match info {
                         Value::Dict(info_dict) => Some(Info::new(info_dict)),
                         _ => None,
@@ -57,9 +57,9 @@ pub struct Info {
 }
 
 impl Info {
-    pub fn new(values: &HashMap<String, bencode::Value>) -> Result<Self> {
+    pub fn new(values: &HashMap<Vec<u8>, bencode::Value>) -> Result<Self> {
         let name = values
-            .get("name")
+            .get("name".as_bytes())
             .and_then(|name| match name {
                 Value::Str(bytes) => Some(std::str::from_utf8(bytes)),
                 _ => None,
@@ -69,7 +69,7 @@ impl Info {
             .to_owned();
 
         let piece_length = values
-            .get("piece length")
+            .get("piece length".as_bytes())
             .and_then(|piece_length| match piece_length {
                 Value::Int(piece_length) => Some(*piece_length),
                 _ => None,
@@ -77,7 +77,7 @@ impl Info {
             .ok_or(anyhow!("Expected that 'piece length' is an integer."))?;
 
         let length = values
-            .get("length")
+            .get("length".as_bytes())
             .and_then(|length| match length {
                 Value::Int(length) => Some(*length),
                 _ => None,
@@ -85,7 +85,7 @@ impl Info {
             .ok_or(anyhow!("Expected that 'length' is an integer."))?;
 
         let pieces = values
-            .get("pieces")
+            .get("pieces".as_bytes())
             .and_then(|pieces| match pieces {
                 Value::Str(pieces) => Some(pieces.clone()),
                 _ => None,
@@ -99,6 +99,23 @@ impl Info {
             pieces,
         })
     }
+    pub(crate) fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    /// Size in bytes of the piece at `index`; only the last piece may be short.
+    pub(crate) fn piece_size(&self, index: usize) -> i64 {
+        if index + 1 == self.piece_count() {
+            self.length - self.piece_length * index as i64
+        } else {
+            self.piece_length
+        }
+    }
+
+    pub(crate) fn piece_hash(&self, index: usize) -> Option<&[u8]> {
+        self.pieces.get(index * 20..(index + 1) * 20)
+    }
+
     pub(crate) fn hashes(&self) -> Vec<String> {
         let piece_hashes: Vec<String> = self
             .pieces
@@ -0,0 +1,413 @@
+use crate::app::config::ClientConfig;
+use crate::app::network::{announce, AnnounceEvent, AnnounceRequest, AnnounceResponse, Peer};
+use crate::app::tracker::MetaData;
+use crate::app::udp_tracker::UdpTrackerClient;
+use anyhow::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Used until the tracker tells us otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Upper bound on how long to wait before retrying a failed announce.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Transfer counters reported to the tracker, shared with the download loop.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a verified piece and returns the number of bytes still missing.
+    pub fn piece_verified(&self, bytes: u64) -> u64 {
+        let previous = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            })
+            .unwrap_or(0);
+        previous.saturating_sub(bytes)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerCommand {
+    /// Ask for more peers as soon as `min interval` allows.
+    Reannounce,
+    Completed,
+    Stopped,
+}
+
+/// Keeps a torrent announced to its tracker for the lifetime of a session.
+pub struct TrackerClient {
+    announce_url: String,
+    request: AnnounceRequest,
+    stats: Arc<TransferStats>,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    last_announce: Option<Instant>,
+    next_announce: Instant,
+    /// Kept between announces to a UDP tracker to reuse its connection id.
+    udp: Option<UdpTrackerClient>,
+}
+
+impl TrackerClient {
+    pub(crate) fn new(
+        torrent: &MetaData,
+        config: &ClientConfig,
+        stats: Arc<TransferStats>,
+    ) -> Result<Self> {
+        Ok(Self::from_request(
+            &torrent.announce,
+            AnnounceRequest::new(torrent, config)?,
+            stats,
+        ))
+    }
+
+    /// A client for any tracker, e.g. one from a magnet link's `tr`.
+    pub(crate) fn from_request(
+        announce_url: &str,
+        request: AnnounceRequest,
+        stats: Arc<TransferStats>,
+    ) -> Self {
+        Self {
+            announce_url: announce_url.to_owned(),
+            request,
+            stats,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            last_announce: None,
+            next_announce: Instant::now(),
+            udp: None,
+        }
+    }
+
+    pub fn announce_url(&self) -> &str {
+        &self.announce_url
+    }
+
+    /// Sends one announce with the current transfer counters and remembers
+    /// the timing hints and tracker id from the reply.
+    pub async fn announce(&mut self, event: Option<AnnounceEvent>) -> Result<AnnounceResponse> {
+        self.request.uploaded = self.stats.uploaded();
+        self.request.downloaded = self.stats.downloaded();
+        self.request.left = self.stats.left();
+        self.request.event = event;
+
+        let result = if self.announce_url.starts_with("udp://") {
+            self.announce_udp().await
+        } else {
+            announce(&self.announce_url, &self.request).await
+        };
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.next_announce = Instant::now() + self.interval.min(RETRY_DELAY);
+                return Err(e);
+            }
+        };
+        if let Some(interval) = response.interval {
+            self.interval = Duration::from_secs(interval);
+        }
+        self.min_interval = response.min_interval.map(Duration::from_secs);
+        if let Some(tracker_id) = &response.tracker_id {
+            self.request.tracker_id = Some(tracker_id.clone());
+        }
+        if let Some(warning) = &response.warning {
+            log::warn!("Tracker warning: {}", warning);
+        }
+        let now = Instant::now();
+        self.last_announce = Some(now);
+        self.next_announce = now + self.interval;
+        Ok(response)
+    }
+
+    async fn announce_udp(&mut self) -> Result<AnnounceResponse> {
+        let udp = match &mut self.udp {
+            Some(udp) => udp,
+            None => self
+                .udp
+                .insert(UdpTrackerClient::connect(&self.announce_url).await?),
+        };
+        udp.announce(&self.request).await
+    }
+
+    /// Earliest moment an out-of-schedule announce is allowed.
+    fn earliest_reannounce(&self) -> Instant {
+        match (self.last_announce, self.min_interval) {
+            (Some(last), Some(min_interval)) => last + min_interval,
+            _ => Instant::now(),
+        }
+    }
+
+    /// Runs announces in the background until stopped, starting with
+    /// `started` unless that was already sent. Every reply's peers are
+    /// forwarded on the returned channel.
+    pub fn spawn(self) -> (TrackerHandle, mpsc::UnboundedReceiver<Vec<Peer>>) {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(self.run(commands_rx, peers_tx));
+        (
+            TrackerHandle {
+                commands: commands_tx,
+                task,
+            },
+            peers_rx,
+        )
+    }
+
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<TrackerCommand>,
+        peers: mpsc::UnboundedSender<Vec<Peer>>,
+    ) {
+        let mut pending = match self.last_announce {
+            None => Some(AnnounceEvent::Started),
+            Some(_) => None,
+        };
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(self.next_announce) => {}
+                command = commands.recv() => match command {
+                    Some(TrackerCommand::Reannounce) => {
+                        self.next_announce = self.next_announce.min(self.earliest_reannounce());
+                        continue;
+                    }
+                    Some(TrackerCommand::Completed) => {
+                        // A `started` that never got through is superseded.
+                        pending = Some(AnnounceEvent::Completed);
+                    }
+                    Some(TrackerCommand::Stopped) | None => {
+                        if let Err(e) = self.announce(Some(AnnounceEvent::Stopped)).await {
+                            log::warn!("Failed to send stopped announce: {}", e);
+                        }
+                        return;
+                    }
+                }
+            }
+            match self.announce(pending).await {
+                Ok(response) => {
+                    pending = None;
+                    let _ = peers.send(response.peers);
+                }
+                Err(e) => log::warn!("Announce to {} failed: {}", self.announce_url, e),
+            }
+        }
+    }
+}
+
+/// Control side of a spawned [`TrackerClient`].
+pub struct TrackerHandle {
+    commands: mpsc::UnboundedSender<TrackerCommand>,
+    task: JoinHandle<()>,
+}
+
+impl TrackerHandle {
+    pub fn reannounce(&self) {
+        let _ = self.commands.send(TrackerCommand::Reannounce);
+    }
+
+    pub fn completed(&self) {
+        let _ = self.commands.send(TrackerCommand::Completed);
+    }
+
+    /// Sends the `stopped` event and waits for it to go out.
+    pub async fn stop(self) {
+        let _ = self.commands.send(TrackerCommand::Stopped);
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::udp_tracker::{event_from_code, ACTION_ANNOUNCE, ACTION_CONNECT};
+    use bytes::{Buf, BufMut, BytesMut};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    type Requests = Arc<Mutex<Vec<(u32, Option<AnnounceEvent>)>>>;
+
+    /// A UDP tracker that answers every request, handing out connection ids
+    /// and announce replies with `interval`. Records each request's action
+    /// and, for announces, event.
+    async fn udp_tracker(interval: u32) -> (String, Requests) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let requests = Requests::default();
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let mut request = &buf[..len];
+                request.advance(8);
+                let action = request.get_u32();
+                let transaction_id = request.get_u32();
+                let mut reply = BytesMut::new();
+                reply.put_u32(action);
+                reply.put_u32(transaction_id);
+                if action == ACTION_CONNECT {
+                    seen.lock().unwrap().push((action, None));
+                    reply.put_u64(0xfeed);
+                } else {
+                    let event =
+                        event_from_code(u32::from_be_bytes(buf[80..84].try_into().unwrap()));
+                    seen.lock().unwrap().push((action, event));
+                    reply.put_u32(interval);
+                    reply.put_u32(0);
+                    reply.put_u32(0);
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn client(url: &str) -> TrackerClient {
+        let config = ClientConfig::default();
+        let request = AnnounceRequest::from_info_hash([5; 20], 100, &config);
+        TrackerClient::from_request(url, request, Arc::new(TransferStats::new(100)))
+    }
+
+    #[tokio::test]
+    async fn udp_announces_reuse_the_connection_id() {
+        let (url, requests) = udp_tracker(1800).await;
+        let mut client = client(&url);
+        client.announce(Some(AnnounceEvent::Started)).await.unwrap();
+        client.announce(None).await.unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                (ACTION_CONNECT, None),
+                (ACTION_ANNOUNCE, Some(AnnounceEvent::Started)),
+                (ACTION_ANNOUNCE, None),
+            ]
+        );
+        assert_eq!(client.interval, Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn piece_verified_counts_down_left() {
+        let stats = TransferStats::new(100);
+        assert_eq!(stats.piece_verified(40), 60);
+        assert_eq!(stats.piece_verified(60), 0);
+        assert_eq!(stats.piece_verified(10), 0);
+        assert_eq!(stats.left(), 0);
+    }
+
+    /// An HTTP tracker recording when each announce arrived and its event.
+    /// The first reply asks for the next announce after a second, later
+    /// ones after a minute, all with a one second `min interval`.
+    async fn http_tracker() -> (String, Arc<Mutex<Vec<(Instant, Option<String>)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let announces = Arc::new(Mutex::new(Vec::new()));
+        let seen = announces.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).into_owned();
+                let event = request
+                    .split(['?', '&', ' '])
+                    .find_map(|pair| pair.strip_prefix("event="))
+                    .map(str::to_owned);
+                let first = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push((Instant::now(), event));
+                    seen.len() == 1
+                };
+                let interval = if first { 1 } else { 60 };
+                let body = format!("d8:intervali{}e12:min intervali1e5:peers0:e", interval);
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+        (url, announces)
+    }
+
+    #[tokio::test]
+    async fn background_announces_follow_events_and_intervals() {
+        let (url, announces) = http_tracker().await;
+        let (handle, _peers) = client(&url).spawn();
+        let count = || announces.lock().unwrap().len();
+        let wait_for = |n: usize| async move {
+            while count() < n {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let deadline = Duration::from_secs(10);
+        // `started`, then a regular announce after the first `interval`.
+        tokio::time::timeout(deadline, wait_for(2)).await.unwrap();
+        // An early reannounce still waits out `min interval`.
+        handle.reannounce();
+        tokio::time::timeout(deadline, wait_for(3)).await.unwrap();
+        handle.completed();
+        tokio::time::timeout(deadline, wait_for(4)).await.unwrap();
+        handle.stop().await;
+
+        let announces = announces.lock().unwrap();
+        let events: Vec<Option<&str>> = announces.iter().map(|(_, e)| e.as_deref()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Some("started"),
+                None,
+                None,
+                Some("completed"),
+                Some("stopped")
+            ]
+        );
+        let gap = |i: usize| announces[i].0 - announces[i - 1].0;
+        assert!(
+            gap(1) >= Duration::from_millis(950),
+            "interval {:?}",
+            gap(1)
+        );
+        assert!(
+            gap(2) >= Duration::from_millis(950),
+            "min interval {:?}",
+            gap(2)
+        );
+        assert!(gap(2) < Duration::from_secs(30), "reannounce {:?}", gap(2));
+    }
+}
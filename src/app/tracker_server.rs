@@ -0,0 +1,581 @@
+use crate::app::bencode;
+use crate::app::bencode::Value;
+use crate::app::network::{AnnounceEvent, Peer, ScrapeStats, TrackerError};
+use anyhow::{anyhow, Result};
+use percent_encoding::percent_decode_str;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+/// Announce and scrape requests are small GETs; refuse anything bigger.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// How often silent peers and empty swarms are swept out.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// Regular announce interval handed to clients.
+    pub interval: Duration,
+    pub min_interval: Duration,
+    /// Peers that haven't announced for this long are dropped.
+    pub peer_timeout: Duration,
+    /// When set, only these info hashes are tracked.
+    pub allowlist: Option<HashSet<[u8; 20]>>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30 * 60),
+            min_interval: Duration::from_secs(60),
+            peer_timeout: Duration::from_secs(2 * 30 * 60 + 60),
+            allowlist: None,
+        }
+    }
+}
+
+/// Announce parameters after transport-specific decoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceParams {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    /// Address peers should connect to.
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub numwant: Option<usize>,
+}
+
+/// What the swarm looks like to one announcing peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwarmAnnounce {
+    pub peers: Vec<Peer>,
+    pub stats: ScrapeStats,
+}
+
+#[derive(Debug)]
+struct SwarmPeer {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.peers
+            .retain(|_, peer| now.duration_since(peer.last_seen) < timeout);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u64;
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u64 - complete,
+        }
+    }
+}
+
+/// Swarm state shared by every tracker front end running in the process.
+#[derive(Debug)]
+pub struct SwarmRegistry {
+    config: TrackerConfig,
+    torrents: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl SwarmRegistry {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            torrents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &TrackerConfig {
+        &self.config
+    }
+
+    fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.config
+            .allowlist
+            .as_ref()
+            .is_none_or(|allowlist| allowlist.contains(info_hash))
+    }
+
+    /// Records an announce and returns other peers from the same swarm.
+    pub fn announce(&self, params: &AnnounceParams) -> Result<SwarmAnnounce, TrackerError> {
+        if !self.is_allowed(&params.info_hash) {
+            return Err(TrackerError::Failure(
+                "Torrent is not registered with this tracker".to_owned(),
+            ));
+        }
+        let now = Instant::now();
+        let mut torrents = self.torrents.lock().unwrap();
+        let swarm = torrents.entry(params.info_hash).or_default();
+        swarm.expire(now, self.config.peer_timeout);
+
+        if params.event == Some(AnnounceEvent::Stopped) {
+            swarm.peers.remove(&params.peer_id);
+        } else {
+            let was_seeding = swarm
+                .peers
+                .get(&params.peer_id)
+                .is_some_and(|peer| peer.left == 0);
+            if params.event == Some(AnnounceEvent::Completed) && !was_seeding {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(
+                params.peer_id,
+                SwarmPeer {
+                    addr: params.addr,
+                    left: params.left,
+                    last_seen: now,
+                },
+            );
+        }
+
+        let numwant = params.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+        let peers: Vec<Peer> = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != params.peer_id)
+            // Seeders have nothing to gain from other seeders.
+            .filter(|(_, peer)| params.left > 0 || peer.left > 0)
+            .take(numwant)
+            .map(|(peer_id, peer)| Peer {
+                addr: peer.addr,
+                peer_id: Some(*peer_id),
+            })
+            .collect();
+        let stats = swarm.stats();
+        if swarm.peers.is_empty() {
+            torrents.remove(&params.info_hash);
+        }
+        Ok(SwarmAnnounce { peers, stats })
+    }
+
+    /// Current statistics for `info_hash`; unknown torrents report zeroes.
+    pub fn scrape(&self, info_hash: &[u8; 20]) -> ScrapeStats {
+        let now = Instant::now();
+        let mut torrents = self.torrents.lock().unwrap();
+        let Some(swarm) = torrents.get_mut(info_hash) else {
+            return ScrapeStats::default();
+        };
+        swarm.expire(now, self.config.peer_timeout);
+        let stats = swarm.stats();
+        if swarm.peers.is_empty() {
+            torrents.remove(info_hash);
+        }
+        stats
+    }
+
+    /// Statistics for every tracked torrent, for scrapes without `info_hash`.
+    pub fn scrape_all(&self) -> Vec<([u8; 20], ScrapeStats)> {
+        self.expire();
+        let torrents = self.torrents.lock().unwrap();
+        torrents
+            .iter()
+            .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+            .collect()
+    }
+
+    /// Drops silent peers from every swarm, and swarms left empty.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.torrents.lock().unwrap().retain(|_, swarm| {
+            swarm.expire(now, self.config.peer_timeout);
+            !swarm.peers.is_empty()
+        });
+    }
+}
+
+/// Sweeps the registry every [`EXPIRE_INTERVAL`], so swarms nobody
+/// announces to any more do not stay around.
+pub async fn expire_periodically(registry: Arc<SwarmRegistry>) {
+    let mut ticks = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        ticks.tick().await;
+        registry.expire();
+    }
+}
+
+/// Answers `/announce` and `/scrape` over HTTP until the listener fails.
+pub async fn serve_http(listener: TcpListener, registry: Arc<SwarmRegistry>) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, remote, &registry).await {
+                log::debug!("HTTP tracker request from {} failed: {}", remote, e);
+            }
+        });
+    }
+}
+
+async fn handle_http(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    registry: &SwarmRegistry,
+) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before end of request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(anyhow!("Request too large"));
+        }
+    }
+    let request_line = request
+        .split(|&b| b == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or(anyhow!("Invalid request line"))?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, target) {
+        (Some("GET"), Some(target)) => {
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let params = parse_query(query);
+            match path {
+                "/announce" => ("200 OK", http_announce(&params, remote, registry)?),
+                "/scrape" => ("200 OK", http_scrape(&params, registry)?),
+                _ => ("404 Not Found", b"Not Found".to_vec()),
+            }
+        }
+        _ => ("400 Bad Request", b"Bad Request".to_vec()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Splits a query string into percent-decoded pairs; keys may repeat.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode_str(key).decode_utf8_lossy().into_owned(),
+                percent_decode_str(value).collect(),
+            )
+        })
+        .collect()
+}
+
+fn query_param<'a>(params: &'a [(String, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_slice())
+}
+
+fn query_number<T: std::str::FromStr>(params: &[(String, Vec<u8>)], key: &str) -> Option<T> {
+    query_param(params, key)
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| value.parse().ok())
+}
+
+fn failure(reason: &str) -> Result<Vec<u8>> {
+    bencode::to_vec_u8(&Value::dict([(
+        "failure reason",
+        Value::Str(reason.as_bytes().to_vec()),
+    )]))
+}
+
+fn http_announce(
+    params: &[(String, Vec<u8>)],
+    remote: SocketAddr,
+    registry: &SwarmRegistry,
+) -> Result<Vec<u8>> {
+    let id = |key| query_param(params, key).and_then(|value| <[u8; 20]>::try_from(value).ok());
+    let Some(info_hash) = id("info_hash") else {
+        return failure("Missing or invalid info_hash");
+    };
+    let Some(peer_id) = id("peer_id") else {
+        return failure("Missing or invalid peer_id");
+    };
+    let Some(port) = query_number::<u16>(params, "port") else {
+        return failure("Missing or invalid port");
+    };
+    // Peers are registered under the address the request came from.
+    let ip = remote.ip().to_canonical();
+    let announce = AnnounceParams {
+        info_hash,
+        peer_id,
+        addr: SocketAddr::new(ip, port),
+        left: query_number(params, "left").unwrap_or(0),
+        event: query_param(params, "event")
+            .and_then(|event| std::str::from_utf8(event).ok())
+            .and_then(AnnounceEvent::parse),
+        numwant: query_number(params, "numwant"),
+    };
+    let swarm = match registry.announce(&announce) {
+        Ok(swarm) => swarm,
+        Err(TrackerError::Failure(reason)) => return failure(&reason),
+    };
+
+    let config = registry.config();
+    let mut response = vec![
+        ("interval", Value::Int(config.interval.as_secs() as i64)),
+        (
+            "min interval",
+            Value::Int(config.min_interval.as_secs() as i64),
+        ),
+        ("complete", Value::Int(swarm.stats.complete as i64)),
+        ("incomplete", Value::Int(swarm.stats.incomplete as i64)),
+    ];
+    let compact = query_param(params, "compact") != Some(b"0");
+    if compact {
+        let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+        for peer in &swarm.peers {
+            let target = match peer.addr {
+                SocketAddr::V4(addr) => {
+                    peers.extend_from_slice(&addr.ip().octets());
+                    &mut peers
+                }
+                SocketAddr::V6(addr) => {
+                    peers6.extend_from_slice(&addr.ip().octets());
+                    &mut peers6
+                }
+            };
+            target.extend_from_slice(&peer.addr.port().to_be_bytes());
+        }
+        response.push(("peers", Value::Str(peers)));
+        if !peers6.is_empty() {
+            response.push(("peers6", Value::Str(peers6)));
+        }
+    } else {
+        let no_peer_id = query_param(params, "no_peer_id") == Some(b"1");
+        let peers = swarm
+            .peers
+            .iter()
+            .map(|peer| {
+                let mut entry = vec![
+                    ("ip", Value::Str(peer.addr.ip().to_string().into_bytes())),
+                    ("port", Value::Int(peer.addr.port() as i64)),
+                ];
+                if let (false, Some(peer_id)) = (no_peer_id, peer.peer_id) {
+                    entry.push(("peer id", Value::Str(peer_id.to_vec())));
+                }
+                Value::dict(entry)
+            })
+            .collect();
+        response.push(("peers", Value::List(peers)));
+    }
+    bencode::to_vec_u8(&Value::dict(response))
+}
+
+fn http_scrape(params: &[(String, Vec<u8>)], registry: &SwarmRegistry) -> Result<Vec<u8>> {
+    let requested: Vec<[u8; 20]> = params
+        .iter()
+        .filter(|(key, _)| key == "info_hash")
+        .filter_map(|(_, value)| <[u8; 20]>::try_from(value.as_slice()).ok())
+        .collect();
+    let stats = if requested.is_empty() {
+        registry.scrape_all()
+    } else {
+        requested
+            .into_iter()
+            .map(|info_hash| (info_hash, registry.scrape(&info_hash)))
+            .collect()
+    };
+    let files = stats
+        .into_iter()
+        .map(|(info_hash, stats)| {
+            (
+                info_hash.to_vec(),
+                Value::dict([
+                    ("complete", Value::Int(stats.complete as i64)),
+                    ("downloaded", Value::Int(stats.downloaded as i64)),
+                    ("incomplete", Value::Int(stats.incomplete as i64)),
+                ]),
+            )
+        })
+        .collect();
+    bencode::to_vec_u8(&Value::dict([("files", Value::Dict(files))]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::network::{announce, scrape, AnnounceRequest};
+
+    fn params(peer: u8, left: u64, event: Option<AnnounceEvent>) -> AnnounceParams {
+        AnnounceParams {
+            info_hash: [7; 20],
+            peer_id: [peer; 20],
+            addr: SocketAddr::from(([10, 0, 0, peer], 6881)),
+            left,
+            event,
+            numwant: None,
+        }
+    }
+
+    #[test]
+    fn swarm_tracks_events_and_counts() {
+        let registry = SwarmRegistry::new(TrackerConfig::default());
+        registry
+            .announce(&params(1, 0, Some(AnnounceEvent::Started)))
+            .unwrap();
+        let swarm = registry
+            .announce(&params(2, 100, Some(AnnounceEvent::Started)))
+            .unwrap();
+        assert_eq!(swarm.peers.len(), 1);
+        assert_eq!(swarm.peers[0].peer_id, Some([1; 20]));
+
+        registry
+            .announce(&params(2, 0, Some(AnnounceEvent::Completed)))
+            .unwrap();
+        assert_eq!(
+            registry.scrape(&[7; 20]),
+            ScrapeStats {
+                complete: 2,
+                downloaded: 1,
+                incomplete: 0
+            }
+        );
+
+        registry
+            .announce(&params(1, 0, Some(AnnounceEvent::Stopped)))
+            .unwrap();
+        assert_eq!(registry.scrape(&[7; 20]).complete, 1);
+    }
+
+    #[test]
+    fn swarm_expires_silent_peers_and_honours_allowlist() {
+        let registry = SwarmRegistry::new(TrackerConfig {
+            peer_timeout: Duration::ZERO,
+            allowlist: Some(HashSet::from([[7; 20]])),
+            ..TrackerConfig::default()
+        });
+        registry.announce(&params(1, 10, None)).unwrap();
+        assert!(registry
+            .announce(&params(2, 10, None))
+            .unwrap()
+            .peers
+            .is_empty());
+
+        let mut other = params(1, 10, None);
+        other.info_hash = [8; 20];
+        assert!(registry.announce(&other).is_err());
+    }
+
+    #[test]
+    fn empty_swarms_are_dropped() {
+        let registry = SwarmRegistry::new(TrackerConfig::default());
+        let tracked = || registry.torrents.lock().unwrap().len();
+        registry
+            .announce(&params(1, 10, Some(AnnounceEvent::Stopped)))
+            .unwrap();
+        assert_eq!(tracked(), 0);
+        registry.announce(&params(1, 10, None)).unwrap();
+        registry.expire();
+        assert_eq!(tracked(), 1);
+        registry
+            .announce(&params(1, 10, Some(AnnounceEvent::Stopped)))
+            .unwrap();
+        assert_eq!(tracked(), 0);
+
+        let silent = SwarmRegistry::new(TrackerConfig {
+            peer_timeout: Duration::ZERO,
+            ..TrackerConfig::default()
+        });
+        silent.announce(&params(1, 10, None)).unwrap();
+        let mut other = params(2, 10, None);
+        other.info_hash = [8; 20];
+        silent.announce(&other).unwrap();
+        assert_eq!(silent.torrents.lock().unwrap().len(), 2);
+        silent.expire();
+        assert!(silent.torrents.lock().unwrap().is_empty());
+        assert!(silent.scrape_all().is_empty());
+    }
+
+    #[tokio::test]
+    async fn http_announce_and_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let registry = Arc::new(SwarmRegistry::new(TrackerConfig::default()));
+        tokio::spawn(serve_http(listener, registry));
+
+        let request = |peer: u8, left| AnnounceRequest {
+            info_hash: [9; 20],
+            peer_id: [peer; 20],
+            port: 7000 + peer as u16,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: Some(AnnounceEvent::Started),
+            tracker_id: None,
+            numwant: None,
+            key: None,
+            ip: None,
+            no_peer_id: false,
+        };
+        announce(&url, &request(1, 0)).await.unwrap();
+        let response = announce(&url, &request(2, 5)).await.unwrap();
+        assert_eq!(
+            response.peers,
+            vec![Peer::new("127.0.0.1:7001".parse().unwrap())]
+        );
+        assert_eq!(response.complete, Some(1));
+        assert_eq!(response.incomplete, Some(1));
+        assert_eq!(response.interval, Some(1800));
+
+        let body = reqwest::get(format!(
+            "{}?info_hash=%09%09%09%09%09%09%09%09%09%09%09%09%09%09%09%09%09%09%09%09\
+             &peer_id=33333333333333333333&port=7003&left=1&compact=0",
+            url
+        ))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+        let response = bencode::decode(&body).unwrap();
+        let Some(Value::List(peers)) = response.get("peers") else {
+            panic!("Expected a non-compact peer list");
+        };
+        assert_eq!(peers.len(), 2);
+        assert!(peers
+            .iter()
+            .all(|peer| peer.get("peer id").is_some() && peer.get("ip").is_some()));
+
+        let stats = scrape(&url, &[[9; 20], [1; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 0,
+                    incomplete: 2
+                },
+                ScrapeStats::default()
+            ]
+        );
+    }
+}
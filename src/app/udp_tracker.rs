@@ -0,0 +1,308 @@
+use crate::app::network::{
+    parse_compact_peers, AnnounceEvent, AnnounceRequest, AnnounceResponse, ScrapeStats,
+    TrackerError,
+};
+use crate::app::random;
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Instant};
+use url::Url;
+
+/// Magic constant identifying the UDP tracker protocol (BEP 15).
+pub(crate) const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub(crate) const ACTION_CONNECT: u32 = 0;
+pub(crate) const ACTION_ANNOUNCE: u32 = 1;
+pub(crate) const ACTION_SCRAPE: u32 = 2;
+pub(crate) const ACTION_ERROR: u32 = 3;
+
+/// A connection id may be reused for one minute after it was received.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// The spec retries forever with `15 * 2^n` second timeouts; a CLI can't
+/// wait that long, so give up after a few attempts.
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Scrape requests are limited to what fits in a single datagram.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+pub(crate) fn event_code(event: Option<AnnounceEvent>) -> u32 {
+    match event {
+        None => 0,
+        Some(AnnounceEvent::Completed) => 1,
+        Some(AnnounceEvent::Started) => 2,
+        Some(AnnounceEvent::Stopped) => 3,
+    }
+}
+
+pub(crate) fn event_from_code(code: u32) -> Option<AnnounceEvent> {
+    match code {
+        1 => Some(AnnounceEvent::Completed),
+        2 => Some(AnnounceEvent::Started),
+        3 => Some(AnnounceEvent::Stopped),
+        _ => None,
+    }
+}
+
+pub struct UdpTrackerClient {
+    socket: UdpSocket,
+    connection_id: Option<(u64, Instant)>,
+    base_timeout: Duration,
+}
+
+impl UdpTrackerClient {
+    /// Resolves a `udp://host:port/...` tracker URL and binds a socket to it.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if url.scheme() != "udp" {
+            return Err(anyhow!("Not a UDP tracker URL: {}", url));
+        }
+        let host = url
+            .host_str()
+            .ok_or(anyhow!("UDP tracker URL has no host: {}", url))?;
+        let port = url
+            .port()
+            .ok_or(anyhow!("UDP tracker URL has no port: {}", url))?;
+        let address = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await?
+            .next()
+            .ok_or(anyhow!("Failed to resolve tracker {}", host))?;
+        let bind = if address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(address).await?;
+        Ok(Self {
+            socket,
+            connection_id: None,
+            base_timeout: BASE_TIMEOUT,
+        })
+    }
+
+    #[cfg(test)]
+    fn with_base_timeout(mut self, base_timeout: Duration) -> Self {
+        self.base_timeout = base_timeout;
+        self
+    }
+
+    /// Sends `request` and waits for a reply carrying the same transaction
+    /// id, retransmitting with exponential back-off.
+    async fn transact(&self, request: &[u8], transaction_id: u32) -> Result<BytesMut> {
+        let mut buf = vec![0u8; 64 * 1024];
+        for attempt in 0..MAX_ATTEMPTS {
+            self.socket.send(request).await?;
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(attempt);
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let len = match timeout(remaining, self.socket.recv(&mut buf)).await {
+                    Ok(len) => len?,
+                    Err(_) => break,
+                };
+                if len < 8 {
+                    continue;
+                }
+                let mut response = BytesMut::from(&buf[..len]);
+                let action = response.get_u32();
+                if response.get_u32() != transaction_id {
+                    continue;
+                }
+                if action == ACTION_ERROR {
+                    let message = String::from_utf8_lossy(&response).into_owned();
+                    return Err(TrackerError::Failure(message).into());
+                }
+                let expected = u32::from_be_bytes(request[8..12].try_into()?);
+                if action != expected {
+                    return Err(anyhow!(
+                        "Tracker replied with action {} to action {}",
+                        action,
+                        expected
+                    ));
+                }
+                return Ok(response);
+            }
+        }
+        Err(anyhow!("UDP tracker did not respond"))
+    }
+
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some((id, received)) = self.connection_id {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(id);
+            }
+        }
+        let transaction_id = random::next_u32();
+        let mut request = BytesMut::with_capacity(16);
+        request.put_u64(PROTOCOL_ID);
+        request.put_u32(ACTION_CONNECT);
+        request.put_u32(transaction_id);
+        let mut response = self.transact(&request, transaction_id).await?;
+        if response.len() < 8 {
+            return Err(anyhow!("Truncated UDP tracker connect response"));
+        }
+        let id = response.get_u64();
+        self.connection_id = Some((id, Instant::now()));
+        Ok(id)
+    }
+
+    /// Announces over UDP. Trackers answer with peers of the address family
+    /// the request arrived on.
+    pub async fn announce(&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id = random::next_u32();
+        let mut packet = BytesMut::with_capacity(98);
+        packet.put_u64(connection_id);
+        packet.put_u32(ACTION_ANNOUNCE);
+        packet.put_u32(transaction_id);
+        packet.put_slice(&request.info_hash);
+        packet.put_slice(&request.peer_id);
+        packet.put_u64(request.downloaded);
+        packet.put_u64(request.left);
+        packet.put_u64(request.uploaded);
+        packet.put_u32(event_code(request.event));
+        packet.put_slice(&match request.ip {
+            Some(IpAddr::V4(ip)) => ip.octets(),
+            _ => [0; 4],
+        });
+        packet.put_u32(
+            request
+                .key
+                .as_deref()
+                .and_then(|key| u32::from_str_radix(key, 16).ok())
+                .unwrap_or(0),
+        );
+        packet.put_i32(request.numwant.map_or(-1, |numwant| numwant as i32));
+        packet.put_u16(request.port);
+
+        let mut response = self.transact(&packet, transaction_id).await?;
+        if response.len() < 12 {
+            return Err(anyhow!("Truncated UDP tracker announce response"));
+        }
+        let interval = response.get_u32() as u64;
+        let leechers = response.get_u32() as u64;
+        let seeders = response.get_u32() as u64;
+        let ipv6 = self.socket.peer_addr()?.is_ipv6();
+        Ok(AnnounceResponse {
+            peers: parse_compact_peers(&response, ipv6)?,
+            interval: Some(interval),
+            complete: Some(seeders),
+            incomplete: Some(leechers),
+            ..AnnounceResponse::default()
+        })
+    }
+
+    /// Scrapes any number of torrents, batching them into as few requests
+    /// as the datagram size allows. Results are in the order of `info_hashes`.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let connection_id = self.connection_id().await?;
+            let transaction_id = random::next_u32();
+            let mut request = BytesMut::with_capacity(16 + 20 * batch.len());
+            request.put_u64(connection_id);
+            request.put_u32(ACTION_SCRAPE);
+            request.put_u32(transaction_id);
+            for info_hash in batch {
+                request.put_slice(info_hash);
+            }
+            let mut response = self.transact(&request, transaction_id).await?;
+            if response.len() < 12 * batch.len() {
+                return Err(anyhow!("Truncated UDP tracker scrape response"));
+            }
+            for _ in batch {
+                let complete = response.get_u32() as u64;
+                let downloaded = response.get_u32() as u64;
+                let incomplete = response.get_u32() as u64;
+                stats.push(ScrapeStats {
+                    complete,
+                    downloaded,
+                    incomplete,
+                });
+            }
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scrape_over_loopback() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", tracker.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            // Drop the first connect to exercise retransmission.
+            tracker.recv_from(&mut buf).await.unwrap();
+
+            let (len, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 16);
+            let mut reply = BytesMut::new();
+            reply.put_u32(ACTION_CONNECT);
+            reply.put_slice(&buf[12..16]);
+            reply.put_u64(0xfeed);
+            tracker.send_to(&reply, from).await.unwrap();
+
+            let (len, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 16 + 40);
+            assert_eq!(u64::from_be_bytes(buf[0..8].try_into().unwrap()), 0xfeed);
+            let mut reply = BytesMut::new();
+            reply.put_u32(ACTION_SCRAPE);
+            reply.put_slice(&buf[12..16]);
+            for n in [(5, 10, 1), (0, 2, 3)] {
+                reply.put_u32(n.0);
+                reply.put_u32(n.1);
+                reply.put_u32(n.2);
+            }
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+
+        let mut client = UdpTrackerClient::connect(&url)
+            .await
+            .unwrap()
+            .with_base_timeout(Duration::from_millis(50));
+        let stats = client.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 5,
+                    downloaded: 10,
+                    incomplete: 1
+                },
+                ScrapeStats {
+                    complete: 0,
+                    downloaded: 2,
+                    incomplete: 3
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn error_action_maps_to_tracker_failure() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", tracker.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut reply = BytesMut::new();
+            reply.put_u32(ACTION_ERROR);
+            reply.put_slice(&buf[12..16]);
+            reply.put_slice(b"go away");
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+        let mut client = UdpTrackerClient::connect(&url).await.unwrap();
+        let err = client.scrape(&[[1; 20]]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure("go away".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use crate::app::network::TrackerError;
+use crate::app::random;
+use crate::app::tracker_server::{AnnounceParams, SwarmRegistry};
+use crate::app::udp_tracker::{
+    event_from_code, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, PROTOCOL_ID,
+};
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Clients may use a connection id for a minute; allow one more for slack.
+const CONNECTION_ID_MAX_AGE: u64 = 2 * 60;
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Issues connection ids that can be verified without per-client state: the
+/// high half is the issue time, the low half a keyed hash of time and address.
+struct ConnectionIds {
+    secret: [u8; 16],
+}
+
+impl ConnectionIds {
+    fn new() -> Self {
+        let mut secret = [0u8; 16];
+        secret[..8].copy_from_slice(&random::next_u64().to_be_bytes());
+        secret[8..].copy_from_slice(&random::next_u64().to_be_bytes());
+        Self { secret }
+    }
+
+    fn signature(&self, issued: u32, addr: &SocketAddr) -> u32 {
+        let mut hasher = Sha1::new();
+        hasher.update(self.secret);
+        hasher.update(issued.to_be_bytes());
+        match addr.ip().to_canonical() {
+            std::net::IpAddr::V4(ip) => hasher.update(ip.octets()),
+            std::net::IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(addr.port().to_be_bytes());
+        let digest = hasher.finalize();
+        u32::from_be_bytes(digest[..4].try_into().unwrap())
+    }
+
+    fn issue(&self, addr: &SocketAddr, now: u64) -> u64 {
+        let issued = now as u32;
+        ((issued as u64) << 32) | self.signature(issued, addr) as u64
+    }
+
+    fn verify(&self, id: u64, addr: &SocketAddr, now: u64) -> bool {
+        let issued = (id >> 32) as u32;
+        let age = (now as u32).wrapping_sub(issued) as u64;
+        age <= CONNECTION_ID_MAX_AGE && self.signature(issued, addr) == id as u32
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Answers BEP 15 connect, announce and scrape requests on `socket`, using
+/// the same swarm state as any other tracker front end sharing `registry`.
+pub async fn serve_udp(socket: UdpSocket, registry: Arc<SwarmRegistry>) -> Result<()> {
+    let ids = ConnectionIds::new();
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if let Some(reply) = handle_packet(&buf[..len], from, &ids, &registry) {
+            if let Err(e) = socket.send_to(&reply, from).await {
+                log::debug!("Failed to answer UDP tracker request from {}: {}", from, e);
+            }
+        }
+    }
+}
+
+fn error_reply(transaction_id: u32, message: &str) -> BytesMut {
+    let mut reply = BytesMut::with_capacity(8 + message.len());
+    reply.put_u32(ACTION_ERROR);
+    reply.put_u32(transaction_id);
+    reply.put_slice(message.as_bytes());
+    reply
+}
+
+/// Returns the reply datagram, or `None` for packets that aren't worth one.
+fn handle_packet(
+    packet: &[u8],
+    from: SocketAddr,
+    ids: &ConnectionIds,
+    registry: &SwarmRegistry,
+) -> Option<BytesMut> {
+    if packet.len() < 16 {
+        return None;
+    }
+    let mut packet = packet;
+    let connection_id = packet.get_u64();
+    let action = packet.get_u32();
+    let transaction_id = packet.get_u32();
+    let now = unix_time();
+
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return None;
+        }
+        let mut reply = BytesMut::with_capacity(16);
+        reply.put_u32(ACTION_CONNECT);
+        reply.put_u32(transaction_id);
+        reply.put_u64(ids.issue(&from, now));
+        return Some(reply);
+    }
+    if !ids.verify(connection_id, &from, now) {
+        return Some(error_reply(transaction_id, "Invalid connection id"));
+    }
+    match action {
+        ACTION_ANNOUNCE => Some(announce(packet, from, transaction_id, registry)),
+        ACTION_SCRAPE => {
+            let count = (packet.len() / 20).min(MAX_SCRAPE_HASHES);
+            let mut reply = BytesMut::with_capacity(8 + 12 * count);
+            reply.put_u32(ACTION_SCRAPE);
+            reply.put_u32(transaction_id);
+            for info_hash in packet.chunks_exact(20).take(count) {
+                let stats = registry.scrape(info_hash.try_into().unwrap());
+                reply.put_u32(stats.complete as u32);
+                reply.put_u32(stats.downloaded as u32);
+                reply.put_u32(stats.incomplete as u32);
+            }
+            Some(reply)
+        }
+        _ => Some(error_reply(transaction_id, "Unknown action")),
+    }
+}
+
+fn announce(
+    mut packet: &[u8],
+    from: SocketAddr,
+    transaction_id: u32,
+    registry: &SwarmRegistry,
+) -> BytesMut {
+    // 98 byte request minus the 16 byte header already consumed.
+    if packet.len() < 82 {
+        return error_reply(transaction_id, "Malformed announce");
+    }
+    let mut info_hash = [0u8; 20];
+    packet.copy_to_slice(&mut info_hash);
+    let mut peer_id = [0u8; 20];
+    packet.copy_to_slice(&mut peer_id);
+    let _downloaded = packet.get_u64();
+    let left = packet.get_u64();
+    let _uploaded = packet.get_u64();
+    let event = event_from_code(packet.get_u32());
+    // The IP field is ignored; peers are registered under their source address.
+    let _ip = packet.get_u32();
+    let _key = packet.get_u32();
+    let numwant = packet.get_i32();
+    let port = packet.get_u16();
+
+    let ip = from.ip().to_canonical();
+    let params = AnnounceParams {
+        info_hash,
+        peer_id,
+        addr: SocketAddr::new(ip, port),
+        left,
+        event,
+        numwant: usize::try_from(numwant).ok(),
+    };
+    let swarm = match registry.announce(&params) {
+        Ok(swarm) => swarm,
+        Err(TrackerError::Failure(reason)) => return error_reply(transaction_id, &reason),
+    };
+
+    let mut reply = BytesMut::with_capacity(20 + 18 * swarm.peers.len());
+    reply.put_u32(ACTION_ANNOUNCE);
+    reply.put_u32(transaction_id);
+    reply.put_u32(registry.config().interval.as_secs() as u32);
+    reply.put_u32(swarm.stats.incomplete as u32);
+    reply.put_u32(swarm.stats.complete as u32);
+    // Only peers of the requester's address family fit the reply format.
+    for peer in &swarm.peers {
+        match (peer.addr, ip.is_ipv4()) {
+            (SocketAddr::V4(addr), true) => reply.put_slice(&addr.ip().octets()),
+            (SocketAddr::V6(addr), false) => reply.put_slice(&addr.ip().octets()),
+            _ => continue,
+        }
+        reply.put_u16(peer.addr.port());
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::network::{announce, scrape, AnnounceEvent, AnnounceRequest, Peer};
+    use crate::app::tracker_server::TrackerConfig;
+    use crate::app::udp_tracker::UdpTrackerClient;
+
+    #[test]
+    fn connection_ids_are_bound_to_address_and_time() {
+        let ids = ConnectionIds::new();
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let id = ids.issue(&addr, 1000);
+        assert!(ids.verify(id, &addr, 1000));
+        assert!(ids.verify(id, &addr, 1000 + CONNECTION_ID_MAX_AGE));
+        assert!(!ids.verify(id, &addr, 1001 + CONNECTION_ID_MAX_AGE));
+        assert!(!ids.verify(id, &"127.0.0.1:5001".parse().unwrap(), 1000));
+        assert!(!ids.verify(id ^ 1, &addr, 1000));
+    }
+
+    #[tokio::test]
+    async fn announce_and_scrape_over_loopback() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let registry = Arc::new(SwarmRegistry::new(TrackerConfig::default()));
+        tokio::spawn(serve_udp(socket, registry.clone()));
+
+        let request = |peer: u8, left| AnnounceRequest {
+            info_hash: [4; 20],
+            peer_id: [peer; 20],
+            port: 8000 + peer as u16,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: Some(AnnounceEvent::Started),
+            tracker_id: None,
+            numwant: None,
+            key: Some("deadbeef".to_owned()),
+            ip: None,
+            no_peer_id: false,
+        };
+        announce(&url, &request(1, 0)).await.unwrap();
+        let response = announce(&url, &request(2, 10)).await.unwrap();
+        assert_eq!(
+            response.peers,
+            vec![Peer::new("127.0.0.1:8001".parse().unwrap())]
+        );
+        assert_eq!(response.complete, Some(1));
+        assert_eq!(response.incomplete, Some(1));
+
+        let stats = scrape(&url, &[[4; 20], [5; 20]]).await.unwrap();
+        assert_eq!((stats[0].complete, stats[0].incomplete), (1, 1));
+        assert_eq!(stats[1], Default::default());
+        assert_eq!(registry.scrape(&[4; 20]), stats[0]);
+    }
+
+    #[tokio::test]
+    async fn rejects_forged_connection_ids_and_unlisted_torrents() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let registry = Arc::new(SwarmRegistry::new(TrackerConfig {
+            allowlist: Some([[1; 20]].into()),
+            ..TrackerConfig::default()
+        }));
+        tokio::spawn(serve_udp(socket, registry));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(&url["udp://".len()..]).await.unwrap();
+        let mut forged = BytesMut::new();
+        forged.put_u64(12345);
+        forged.put_u32(ACTION_SCRAPE);
+        forged.put_u32(77);
+        forged.put_slice(&[1; 20]);
+        client.send(&forged).await.unwrap();
+        let mut buf = [0u8; 256];
+        let len = client.recv(&mut buf).await.unwrap();
+        let mut reply = &buf[..len];
+        assert_eq!(reply.get_u32(), ACTION_ERROR);
+        assert_eq!(reply.get_u32(), 77);
+
+        let mut tracker = UdpTrackerClient::connect(&url).await.unwrap();
+        let mut request = AnnounceRequest {
+            info_hash: [2; 20],
+            peer_id: [3; 20],
+            port: 1,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+            tracker_id: None,
+            numwant: None,
+            key: None,
+            ip: None,
+            no_peer_id: false,
+        };
+        let err = tracker.announce(&request).await.unwrap_err();
+        assert!(err.downcast_ref::<TrackerError>().is_some());
+        request.info_hash = [1; 20];
+        assert!(tracker.announce(&request).await.is_ok());
+    }
+}
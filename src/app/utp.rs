@@ -0,0 +1,1117 @@
+use crate::app::random;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+const EXTENSION_SACK: u8 = 1;
+/// Payload per packet, so a packet with headers fits a 1500 byte MTU.
+const MAX_PAYLOAD: usize = 1400;
+/// LEDBAT aims for this much queuing delay, in microseconds.
+const TARGET_DELAY: f64 = 100_000.0;
+/// LEDBAT grows the window by at most this many bytes per round trip.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = 2.0 * MAX_PAYLOAD as f64;
+/// Base delay is the lowest delay seen over this long.
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(60);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+/// Packets sent this many times without an ack give up the connection.
+const MAX_TRANSMISSIONS: u32 = 5;
+/// Packets received after a gap that make the gap count as lost.
+const LOSS_THRESHOLD: usize = 3;
+const SEND_BUFFER: usize = 256 * 1024;
+const RECV_BUFFER: usize = 1024 * 1024;
+/// Out of order packets further ahead than this are dropped.
+const MAX_REORDER: u16 = 1024;
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Incoming connections waiting for `accept`; SYNs past this are reset.
+const ACCEPT_BACKLOG: usize = 16;
+/// Incoming connections not accepted within this long are reset.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        [Kind::Data, Kind::Fin, Kind::State, Kind::Reset, Kind::Syn]
+            .into_iter()
+            .find(|known| *known as u8 == kind)
+    }
+}
+
+/// A BEP 29 packet.
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    kind: Kind,
+    conn_id: u16,
+    /// Send time in microseconds.
+    timestamp: u32,
+    /// The sender's latest one-way delay measurement of our packets.
+    timestamp_diff: u32,
+    /// Bytes the sender can still receive.
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    /// Selective ack: bit `i` (least significant first) stands for
+    /// `ack_nr + 2 + i`.
+    sack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(kind: Kind, conn_id: u16, seq_nr: u16, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr: 0,
+            sack: None,
+            payload,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len() + 10);
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(if self.sack.is_some() {
+            EXTENSION_SACK
+        } else {
+            0
+        });
+        bytes.extend(self.conn_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.wnd_size.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            bytes.push(0);
+            bytes.push(sack.len() as u8);
+            bytes.extend(sack);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            return Err(anyhow!("uTP packet of {} bytes is too short", bytes.len()));
+        }
+        if bytes[0] & 0x0f != VERSION {
+            return Err(anyhow!("Unsupported uTP version {}", bytes[0] & 0x0f));
+        }
+        let kind =
+            Kind::from_u8(bytes[0] >> 4).ok_or(anyhow!("Unknown uTP type {}", bytes[0] >> 4))?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        let mut sack = None;
+        let mut extension = bytes[1];
+        let mut at = HEADER_LEN;
+        while extension != 0 {
+            let header = bytes
+                .get(at..at + 2)
+                .ok_or(anyhow!("Truncated uTP extension"))?;
+            let data = bytes
+                .get(at + 2..at + 2 + header[1] as usize)
+                .ok_or(anyhow!("Truncated uTP extension"))?;
+            if extension == EXTENSION_SACK {
+                sack = Some(data.to_vec());
+            }
+            extension = header[0];
+            at += 2 + data.len();
+        }
+        Ok(Self {
+            kind,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: bytes[at..].to_vec(),
+        })
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap around.
+fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+/// Microseconds on a clock shared by every connection, wrapping at 2^32.
+fn timestamp() -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+/// LEDBAT congestion control: grows the window while the queuing delay
+/// our packets see stays under the target and shrinks it above, so bulk
+/// transfers yield to interactive traffic on the same uplink.
+#[derive(Debug)]
+struct Ledbat {
+    /// Bytes allowed in flight.
+    window: f64,
+    /// Lowest delay of the current and previous window; the path's
+    /// delay without queuing.
+    base_delay: [u32; 2],
+    base_started: Instant,
+}
+
+impl Ledbat {
+    fn new(now: Instant) -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            base_delay: [u32::MAX; 2],
+            base_started: now,
+        }
+    }
+
+    fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// `delay` is the one-way delay the peer measured, in microseconds.
+    fn on_ack(&mut self, acked_bytes: usize, delay: u32, now: Instant) {
+        if now.duration_since(self.base_started) >= BASE_DELAY_WINDOW {
+            self.base_delay = [u32::MAX, self.base_delay[0]];
+            self.base_started = now;
+        }
+        self.base_delay[0] = self.base_delay[0].min(delay);
+        let base = self.base_delay[0].min(self.base_delay[1]);
+        let queuing = delay.saturating_sub(base) as f64;
+        let off_target = (TARGET_DELAY - queuing) / TARGET_DELAY;
+        let window_factor = acked_bytes as f64 / self.window;
+        self.window =
+            (self.window + MAX_WINDOW_INCREASE * off_target * window_factor).max(MIN_WINDOW);
+    }
+
+    fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+}
+
+/// The UDP socket shared by all connections, with optional simulated loss.
+struct Link {
+    socket: UdpSocket,
+    /// Drop every n-th outgoing packet; 0 drops none.
+    drop_every: AtomicU32,
+    sent: AtomicU32,
+}
+
+impl Link {
+    fn send(&self, packet: &Packet, to: SocketAddr) {
+        let sent = self.sent.fetch_add(1, Ordering::Relaxed) + 1;
+        let drop_every = self.drop_every.load(Ordering::Relaxed);
+        if drop_every != 0 && sent.is_multiple_of(drop_every) {
+            return;
+        }
+        // Lost sends are recovered by retransmission like any other loss.
+        if let Err(e) = self.socket.try_send_to(&packet.encode(), to) {
+            log::debug!("uTP send to {} failed: {}", to, e);
+        }
+    }
+}
+
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+/// One connection's state, driven by the stream and by the socket task.
+struct Connection {
+    link: Arc<Link>,
+    peer: SocketAddr,
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Next sequence number to send.
+    seq_nr: u16,
+    /// Last sequence number received in order.
+    ack_nr: u16,
+    error: Option<io::ErrorKind>,
+
+    send_buffer: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    in_flight_bytes: usize,
+    peer_window: usize,
+    ledbat: Ledbat,
+    /// Sequence number after which another loss may cut the window again.
+    loss_recovery: Option<u16>,
+    duplicate_acks: usize,
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    /// Delay of the peer's last packet, echoed back as `timestamp_diff`.
+    reply_micro: u32,
+    closing: bool,
+    fin_sent: bool,
+    dropped: bool,
+    /// Whether a stream or a pending `connect` owns the connection.
+    claimed: bool,
+    created: Instant,
+
+    recv_buffer: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    eof: bool,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(link: Arc<Link>, peer: SocketAddr, recv_id: u16, send_id: u16, now: Instant) -> Self {
+        Self {
+            link,
+            peer,
+            state: State::SynSent,
+            recv_id,
+            send_id,
+            seq_nr: 1,
+            ack_nr: 0,
+            error: None,
+            send_buffer: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            peer_window: RECV_BUFFER,
+            ledbat: Ledbat::new(now),
+            loss_recovery: None,
+            duplicate_acks: 0,
+            rtt: None,
+            rto: INITIAL_RTO,
+            reply_micro: 0,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            claimed: false,
+            created: now,
+            recv_buffer: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            eof: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Starts a connection by sending SYN.
+    fn connect(link: Arc<Link>, peer: SocketAddr, recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(link, peer, recv_id, recv_id.wrapping_add(1), now);
+        connection.claimed = true;
+        connection.send_packet(Kind::Syn, Vec::new(), now);
+        connection
+    }
+
+    /// Answers a peer's SYN.
+    fn accept(link: Arc<Link>, peer: SocketAddr, syn: &Packet, now: Instant) -> Self {
+        let mut connection = Self::new(link, peer, syn.conn_id.wrapping_add(1), syn.conn_id, now);
+        connection.state = State::Connected;
+        connection.seq_nr = random::next_u32() as u16;
+        connection.ack_nr = syn.seq_nr;
+        connection.reply_micro = timestamp().wrapping_sub(syn.timestamp);
+        connection.send_state();
+        connection
+    }
+
+    fn sack(&self) -> Option<Vec<u8>> {
+        let furthest = self
+            .out_of_order
+            .keys()
+            .map(|seq| seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .max()?;
+        let mut sack = vec![0u8; (furthest / 32 + 1).min(8) * 4];
+        for seq in self.out_of_order.keys() {
+            let bit = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if bit < sack.len() * 8 {
+                sack[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(sack)
+    }
+
+    /// Fills in the fields that describe our side right now and sends.
+    fn transmit(&self, packet: &mut Packet) {
+        packet.timestamp = timestamp();
+        packet.timestamp_diff = self.reply_micro;
+        packet.wnd_size = RECV_BUFFER.saturating_sub(self.recv_buffer.len()) as u32;
+        packet.ack_nr = self.ack_nr;
+        packet.sack = self.sack();
+        self.link.send(packet, self.peer);
+    }
+
+    fn send_state(&self) {
+        let mut packet = Packet::new(Kind::State, self.send_id, self.seq_nr, Vec::new());
+        self.transmit(&mut packet);
+    }
+
+    /// Sends a packet that takes a sequence number and must be acked.
+    fn send_packet(&mut self, kind: Kind, payload: Vec<u8>, now: Instant) {
+        // SYN is the one packet addressed with our own id.
+        let conn_id = if kind == Kind::Syn {
+            self.recv_id
+        } else {
+            self.send_id
+        };
+        let mut packet = Packet::new(kind, conn_id, self.seq_nr, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&mut packet);
+        self.in_flight_bytes += packet.payload.len();
+        self.in_flight.push_back(Sent {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+        });
+    }
+
+    fn resend(&mut self, index: usize, now: Instant) {
+        let mut packet = self.in_flight[index].packet.clone();
+        self.transmit(&mut packet);
+        let sent = &mut self.in_flight[index];
+        sent.sent_at = now;
+        sent.transmissions += 1;
+    }
+
+    /// Sends buffered data as far as the windows allow, then FIN once
+    /// everything is out and the stream is shut down.
+    fn flush(&mut self, now: Instant) {
+        if self.state != State::Connected || self.error.is_some() {
+            return;
+        }
+        let window = self.ledbat.window().min(self.peer_window);
+        while !self.send_buffer.is_empty() {
+            let len = self.send_buffer.len().min(MAX_PAYLOAD);
+            if !self.in_flight.is_empty() && self.in_flight_bytes + len > window {
+                break;
+            }
+            let payload = self.send_buffer.drain(..len).collect();
+            self.send_packet(Kind::Data, payload, now);
+        }
+        if self.closing && self.send_buffer.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.send_packet(Kind::Fin, Vec::new(), now);
+        }
+        self.wake_writer();
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn fail(&mut self, error: io::ErrorKind) {
+        self.error.get_or_insert(error);
+        self.wake_reader();
+        self.wake_writer();
+    }
+
+    /// Resets the connection for a side that will never use it.
+    fn abort(&mut self) {
+        if self.error.is_none() {
+            let mut packet = Packet::new(Kind::Reset, self.send_id, self.seq_nr, Vec::new());
+            self.transmit(&mut packet);
+        }
+        self.dropped = true;
+        self.fail(io::ErrorKind::ConnectionAborted);
+    }
+
+    fn on_packet(&mut self, packet: Packet, now: Instant) {
+        if packet.kind == Kind::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+        if packet.kind == Kind::Syn {
+            // Our reply to the SYN was lost.
+            self.send_state();
+            return;
+        }
+        self.reply_micro = timestamp().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.wnd_size as usize;
+        if self.state == State::SynSent {
+            if packet.kind != Kind::State {
+                return;
+            }
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+        }
+        self.on_ack(&packet, now);
+        if matches!(packet.kind, Kind::Data | Kind::Fin) {
+            self.receive(packet);
+        }
+        self.flush(now);
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        // Ignore acks for packets we never sent.
+        if !seq_before(packet.ack_nr, self.seq_nr) {
+            return;
+        }
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        let mut acked = |sent: &Sent| {
+            acked_bytes += sent.packet.payload.len();
+            // Karn: only packets sent once give a clean round trip time.
+            if sent.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(sent.sent_at));
+            }
+        };
+        while let Some(sent) = self.in_flight.front() {
+            if seq_before(packet.ack_nr, sent.packet.seq_nr) {
+                break;
+            }
+            acked(sent);
+            self.in_flight.pop_front();
+        }
+
+        let mut sacked = HashSet::new();
+        if let Some(sack) = &packet.sack {
+            for bit in 0..sack.len() * 8 {
+                if sack[bit / 8] & (1 << (bit % 8)) != 0 {
+                    sacked.insert(packet.ack_nr.wrapping_add(2 + bit as u16));
+                }
+            }
+            self.in_flight.retain(|sent| {
+                let keep = !sacked.contains(&sent.packet.seq_nr);
+                if !keep {
+                    acked(sent);
+                }
+                keep
+            });
+        }
+        self.in_flight_bytes -= acked_bytes;
+
+        if let Some(sample) = rtt_sample {
+            let (rtt, variance) = match self.rtt {
+                None => (sample, sample / 2),
+                Some((rtt, variance)) => {
+                    let delta = rtt.abs_diff(sample);
+                    ((rtt * 7 + sample) / 8, (variance * 3 + delta) / 4)
+                }
+            };
+            self.rtt = Some((rtt, variance));
+        }
+        let progress = acked_bytes > 0 || rtt_sample.is_some() || !sacked.is_empty();
+        if progress {
+            // New acks end any timeout backoff.
+            if let Some((rtt, variance)) = self.rtt {
+                self.rto = (rtt + variance * 4).clamp(MIN_RTO, MAX_RTO);
+            }
+            self.duplicate_acks = 0;
+            if packet.timestamp_diff != 0 && acked_bytes > 0 {
+                self.ledbat.on_ack(acked_bytes, packet.timestamp_diff, now);
+            }
+        } else if packet.kind == Kind::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+        }
+
+        // A packet is lost once enough later ones arrived, either as
+        // selective acks or as duplicate acks.
+        let lost = self.in_flight.iter().position(|sent| {
+            sent.transmissions == 1
+                && sacked
+                    .iter()
+                    .filter(|seq| seq_before(sent.packet.seq_nr, **seq))
+                    .count()
+                    >= LOSS_THRESHOLD
+        });
+        let lost = lost
+            .or((self.duplicate_acks >= LOSS_THRESHOLD && !self.in_flight.is_empty()).then_some(0));
+        if let Some(index) = lost {
+            self.duplicate_acks = 0;
+            let seq_nr = self.in_flight[index].packet.seq_nr;
+            if self
+                .loss_recovery
+                .is_none_or(|recovery| seq_before(recovery, seq_nr))
+            {
+                self.ledbat.on_loss();
+                self.loss_recovery = Some(self.seq_nr.wrapping_sub(1));
+            }
+            self.resend(index, now);
+        }
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        let offset = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if offset == 0 || offset > MAX_REORDER || self.eof {
+            // A duplicate whose ack was lost, or far out of range.
+            self.send_state();
+            return;
+        }
+        if offset > 1 {
+            self.out_of_order.insert(packet.seq_nr, packet);
+            self.send_state();
+            return;
+        }
+        let mut next = Some(packet);
+        while let Some(packet) = next {
+            self.ack_nr = packet.seq_nr;
+            if packet.kind == Kind::Fin {
+                self.eof = true;
+                self.out_of_order.clear();
+                break;
+            }
+            self.recv_buffer.extend(packet.payload);
+            next = self.out_of_order.remove(&self.ack_nr.wrapping_add(1));
+        }
+        self.send_state();
+        self.wake_reader();
+    }
+
+    fn on_tick(&mut self, now: Instant) {
+        if !self.claimed && now.saturating_duration_since(self.created) >= ACCEPT_TIMEOUT {
+            self.abort();
+            return;
+        }
+        let Some(oldest) = self.in_flight.front() else {
+            return;
+        };
+        if now.duration_since(oldest.sent_at) < self.rto {
+            return;
+        }
+        if oldest.transmissions >= MAX_TRANSMISSIONS {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+        self.ledbat.on_timeout();
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.resend(0, now);
+        self.flush(now);
+    }
+
+    /// Whether the socket can forget this connection: closed by its stream,
+    /// or failed before a stream existed.
+    fn finished(&self) -> bool {
+        match self.error {
+            Some(_) => self.dropped || !self.claimed,
+            None => self.dropped && self.fin_sent && self.in_flight.is_empty(),
+        }
+    }
+}
+
+type Connections = HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>;
+
+struct Shared {
+    link: Arc<Link>,
+    /// Keyed by peer address and the connection id its packets carry.
+    connections: Mutex<Connections>,
+    /// Set once the socket listens; until then SYNs are reset.
+    incoming: OnceLock<mpsc::Sender<Arc<Mutex<Connection>>>>,
+}
+
+impl Shared {
+    fn dispatch(&self, data: &[u8], from: SocketAddr) {
+        let packet = match Packet::decode(data) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::debug!("Ignoring datagram from {}: {}", from, e);
+                return;
+            }
+        };
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get(&(from, packet.conn_id)) {
+            connection.lock().unwrap().on_packet(packet, now);
+            return;
+        }
+        if packet.kind != Kind::Syn {
+            return;
+        }
+        let key = (from, packet.conn_id.wrapping_add(1));
+        if let Some(connection) = connections.get(&key) {
+            connection.lock().unwrap().on_packet(packet, now);
+            return;
+        }
+        let Some(permit) = self
+            .incoming
+            .get()
+            .and_then(|sender| sender.try_reserve().ok())
+        else {
+            log::debug!("Resetting uTP connection from {}", from);
+            let mut reset = Packet::new(Kind::Reset, packet.conn_id, 0, Vec::new());
+            reset.ack_nr = packet.seq_nr;
+            self.link.send(&reset, from);
+            return;
+        };
+        let connection = Arc::new(Mutex::new(Connection::accept(
+            self.link.clone(),
+            from,
+            &packet,
+            now,
+        )));
+        connections.insert(key, connection.clone());
+        permit.send(connection);
+    }
+
+    fn tick(&self, now: Instant) {
+        self.connections.lock().unwrap().retain(|_, connection| {
+            let mut connection = connection.lock().unwrap();
+            connection.on_tick(now);
+            !connection.finished()
+        });
+    }
+}
+
+/// Receives for every connection on the socket and runs their timers.
+/// Ends once the socket and all its streams are gone.
+async fn drive(shared: Weak<Shared>) {
+    let mut buffer = vec![0u8; 65536];
+    let mut ticks = time::interval(TICK_INTERVAL);
+    while let Some(shared) = shared.upgrade() {
+        tokio::select! {
+            received = shared.link.socket.recv_from(&mut buffer) => match received {
+                Ok((len, from)) => shared.dispatch(&buffer[..len], from),
+                Err(e) => log::debug!("uTP receive failed: {}", e),
+            },
+            _ = ticks.tick() => shared.tick(Instant::now()),
+        }
+    }
+}
+
+/// A UDP socket carrying uTP connections, both ways.
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    incoming: Option<mpsc::Receiver<Arc<Mutex<Connection>>>>,
+}
+
+/// Resets a connection whose `connect` was given up before it finished.
+struct PendingConnect(Option<Arc<Mutex<Connection>>>);
+
+impl Drop for PendingConnect {
+    fn drop(&mut self) {
+        if let Some(connection) = self.0.take() {
+            connection.lock().unwrap().abort();
+        }
+    }
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        // Sends use try_send_to, which needs the reactor to have seen the
+        // socket writable once.
+        socket.writable().await?;
+        let shared = Arc::new(Shared {
+            link: Arc::new(Link {
+                socket,
+                drop_every: AtomicU32::new(0),
+                sent: AtomicU32::new(0),
+            }),
+            connections: Mutex::new(HashMap::new()),
+            incoming: OnceLock::new(),
+        });
+        tokio::spawn(drive(Arc::downgrade(&shared)));
+        Ok(Self {
+            shared,
+            incoming: None,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.link.socket.local_addr()?)
+    }
+
+    pub async fn connect(&self, peer: SocketAddr) -> Result<UtpStream> {
+        let connection = {
+            let mut connections = self.shared.connections.lock().unwrap();
+            let recv_id = loop {
+                let id = random::next_u32() as u16;
+                if !connections.contains_key(&(peer, id)) {
+                    break id;
+                }
+            };
+            let connection = Arc::new(Mutex::new(Connection::connect(
+                self.shared.link.clone(),
+                peer,
+                recv_id,
+                Instant::now(),
+            )));
+            connections.insert((peer, recv_id), connection.clone());
+            connection
+        };
+        let mut pending = PendingConnect(Some(connection.clone()));
+        poll_fn(|cx| {
+            let mut connection = connection.lock().unwrap();
+            if let Some(error) = connection.error {
+                return Poll::Ready(Err(anyhow!("uTP connect to {} failed: {}", peer, error)));
+            }
+            if connection.state == State::Connected {
+                return Poll::Ready(Ok(()));
+            }
+            connection.write_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await?;
+        pending.0 = None;
+        Ok(self.stream(connection))
+    }
+
+    /// Starts taking incoming connections. Until then, and whenever the
+    /// backlog is full, SYNs are answered with a reset.
+    #[allow(dead_code)]
+    pub fn listen(&mut self) {
+        if self.incoming.is_none() {
+            let (sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+            let _ = self.shared.incoming.set(sender);
+            self.incoming = Some(incoming);
+        }
+    }
+
+    /// Waits for a peer to connect, listening first if needed.
+    #[allow(dead_code)]
+    pub async fn accept(&mut self) -> Result<UtpStream> {
+        self.listen();
+        let incoming = self.incoming.as_mut().expect("listening");
+        loop {
+            let connection = incoming.recv().await.ok_or(anyhow!("uTP socket closed"))?;
+            // Skip connections reset while they waited.
+            if connection.lock().unwrap().error.is_none() {
+                return Ok(self.stream(connection));
+            }
+        }
+    }
+
+    /// Drops every `n`-th packet sent, to exercise loss recovery.
+    #[cfg(test)]
+    fn drop_every(&self, n: u32) {
+        self.shared.link.drop_every.store(n, Ordering::Relaxed);
+    }
+
+    fn stream(&self, connection: Arc<Mutex<Connection>>) -> UtpStream {
+        let peer = {
+            let mut connection = connection.lock().unwrap();
+            connection.claimed = true;
+            connection.peer
+        };
+        UtpStream {
+            connection,
+            peer,
+            _shared: self.shared.clone(),
+        }
+    }
+}
+
+/// A reliable, ordered byte stream over uTP.
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    peer: SocketAddr,
+    /// Keeps the socket task running while the stream is in use.
+    _shared: Arc<Shared>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        if !connection.recv_buffer.is_empty() {
+            let len = connection.recv_buffer.len().min(buf.remaining());
+            let (front, back) = connection.recv_buffer.as_slices();
+            let from_front = len.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..len - from_front]);
+            connection.recv_buffer.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        if connection.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(error) = connection.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        connection.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(error) = connection.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if connection.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = SEND_BUFFER.saturating_sub(connection.send_buffer.len());
+        if space == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.len().min(space);
+        connection.send_buffer.extend(&buf[..len]);
+        connection.flush(Instant::now());
+        Poll::Ready(Ok(len))
+    }
+
+    /// Data is handed to the socket as the window allows; like TCP,
+    /// flushing does not wait for acks.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.connection.lock().unwrap().error {
+            Some(error) => Poll::Ready(Err(error.into())),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Sends FIN after the buffered data and waits for it to be acked.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(error) = connection.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        connection.closing = true;
+        connection.flush(Instant::now());
+        if connection.fin_sent && connection.in_flight.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        connection.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for UtpStream {
+    /// Lets the socket task finish sending and close the connection.
+    fn drop(&mut self) {
+        let mut connection = self.connection.lock().unwrap();
+        connection.dropped = true;
+        connection.closing = true;
+        connection.flush(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::messages::{BTMessage, BTMessageFramer};
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Framed;
+
+    #[test]
+    fn packet_round_trip() {
+        let mut packet = Packet::new(Kind::Data, 0x1234, 65535, b"payload".to_vec());
+        packet.timestamp = 1;
+        packet.timestamp_diff = 2;
+        packet.wnd_size = 3;
+        packet.ack_nr = 4;
+        packet.sack = Some(vec![0b101, 0, 0, 0]);
+        let bytes = packet.encode();
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes[1], EXTENSION_SACK);
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+
+        let state = Packet::new(Kind::State, 1, 2, Vec::new());
+        assert_eq!(state.encode().len(), HEADER_LEN);
+        assert_eq!(Packet::decode(&state.encode()).unwrap(), state);
+        assert!(Packet::decode(&bytes[..19]).is_err());
+        assert!(Packet::decode(&[0x02; 20]).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_before(1, 2));
+        assert!(seq_before(65535, 0));
+        assert!(!seq_before(2, 2));
+        assert!(!seq_before(0, 65535));
+    }
+
+    #[test]
+    fn ledbat_tracks_queuing_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(now);
+        ledbat.on_ack(1000, 20_000, now);
+        let start = ledbat.window;
+        // Delay at the base: no queuing, so the window grows.
+        ledbat.on_ack(3000, 20_000, now);
+        assert!(ledbat.window > start);
+        // Queuing well past the target shrinks it again.
+        let grown = ledbat.window;
+        ledbat.on_ack(3000, 20_000 + 300_000, now);
+        assert!(ledbat.window < grown);
+        ledbat.on_loss();
+        assert!(ledbat.window >= MIN_WINDOW);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window, MIN_WINDOW);
+        // The base delay follows the path once old minima expire.
+        ledbat.on_ack(1000, 50_000, now + BASE_DELAY_WINDOW);
+        ledbat.on_ack(1000, 50_000, now + BASE_DELAY_WINDOW * 2);
+        assert_eq!(ledbat.base_delay, [50_000, 50_000]);
+    }
+
+    async fn pair(drop_every: u32) -> (UtpStream, UtpStream) {
+        let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut server = UtpSocket::bind(loopback).await.unwrap();
+        let client = UtpSocket::bind(loopback).await.unwrap();
+        server.drop_every(drop_every);
+        client.drop_every(drop_every);
+        server.listen();
+        let server_addr = server.local_addr().unwrap();
+        let (outgoing, incoming) = tokio::join!(client.connect(server_addr), server.accept());
+        (outgoing.unwrap(), incoming.unwrap())
+    }
+
+    async fn transfer(drop_every: u32) {
+        let (mut client, mut server) = pair(drop_every).await;
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+            client
+        });
+        let mut received = Vec::new();
+        time::timeout(Duration::from_secs(30), server.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfers_over_loopback() {
+        transfer(0).await;
+    }
+
+    #[tokio::test]
+    async fn recovers_from_packet_loss() {
+        transfer(7).await;
+    }
+
+    #[tokio::test]
+    async fn carries_peer_messages() {
+        let (client, server) = pair(5).await;
+        let mut client = Framed::new(client, BTMessageFramer::new());
+        let mut server = Framed::new(server, BTMessageFramer::new());
+        let messages = || {
+            vec![
+                BTMessage::Interested,
+                BTMessage::Have(42),
+                BTMessage::Piece(1, 0, vec![0xab; 16384].into()),
+                BTMessage::KeepAlive,
+            ]
+        };
+        for message in messages() {
+            client.send(message).await.unwrap();
+        }
+        for expected in messages() {
+            let received = time::timeout(Duration::from_secs(10), server.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(received, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_times_out_without_a_peer() {
+        let socket = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let attempt = time::timeout(
+            Duration::from_millis(300),
+            socket.connect(silent.local_addr().unwrap()),
+        )
+        .await;
+        assert!(attempt.is_err(), "Connected without a peer");
+        // The abandoned attempt is reset and forgotten.
+        time::sleep(TICK_INTERVAL * 3).await;
+        assert!(socket.shared.connections.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resets_connections_without_a_listener() {
+        let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = UtpSocket::bind(loopback).await.unwrap();
+        let client = UtpSocket::bind(loopback).await.unwrap();
+        let attempt = time::timeout(
+            Duration::from_secs(5),
+            client.connect(server.local_addr().unwrap()),
+        )
+        .await
+        .unwrap();
+        assert!(attempt.is_err(), "Connected without a listener");
+        assert!(server.shared.connections.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bounds_and_reaps_unaccepted_connections() {
+        let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut server = UtpSocket::bind(loopback).await.unwrap();
+        server.listen();
+        let server_addr = server.local_addr().unwrap();
+        let client = UtpSocket::bind(loopback).await.unwrap();
+        let mut streams = Vec::new();
+        for _ in 0..ACCEPT_BACKLOG {
+            streams.push(client.connect(server_addr).await.unwrap());
+        }
+        assert!(client.connect(server_addr).await.is_err());
+        assert_eq!(
+            server.shared.connections.lock().unwrap().len(),
+            ACCEPT_BACKLOG
+        );
+
+        server.shared.tick(Instant::now() + ACCEPT_TIMEOUT);
+        assert!(server.shared.connections.lock().unwrap().is_empty());
+        let mut buffer = [0u8; 1];
+        let read = time::timeout(Duration::from_secs(5), streams[0].read(&mut buffer))
+            .await
+            .unwrap();
+        assert!(read.is_err(), "Stream survived the reset");
+    }
+}